@@ -72,7 +72,7 @@ impl Version {
 pub(crate) static BREAKINGCHANGES: &str = include_str!("../BREAKINGCHANGES.md");
 
 /// Return platform's data directory.
-fn data_dir() -> PathBuf {
+pub(crate) fn data_dir() -> PathBuf {
     #[cfg(unix)]
     return XDG_DIRS.data_dir();
 
@@ -8,6 +8,7 @@ use color_eyre::eyre;
 use color_eyre::eyre::eyre;
 use color_eyre::eyre::Context;
 
+use crate::cassette;
 use crate::error::TopgradeError;
 
 use tracing::debug;
@@ -163,11 +164,8 @@ impl CommandExt for Command {
         let command = log(self);
 
         // This is where we implement `output_checked`, which is what we prefer to use instead of
-        // `output`, so we allow `Command::output` here.
-        #[allow(clippy::disallowed_methods)]
-        let output = self
-            .output()
-            .with_context(|| format!("Failed to execute `{command}`"))?;
+        // `output`, through the cassette which records or replays it if there is one.
+        let output = cassette::output(self).with_context(|| format!("Failed to execute `{command}`"))?;
 
         if succeeded(&output).is_ok() {
             Ok(output)
@@ -199,9 +197,8 @@ impl CommandExt for Command {
         let message = format!("Failed to execute `{command}`");
 
         // This is where we implement `status_checked`, which is what we prefer to use instead of
-        // `status`, so we allow `Command::status` here.
-        #[allow(clippy::disallowed_methods)]
-        let status = self.status().with_context(|| message.clone())?;
+        // `status`, through the cassette which records or replays it if there is one.
+        let status = cassette::status(self).with_context(|| message.clone())?;
 
         if succeeded(status).is_ok() {
             Ok(())
@@ -217,6 +214,9 @@ impl CommandExt for Command {
     fn spawn_checked(&mut self) -> eyre::Result<Self::Child> {
         let command = log(self);
         let message = format!("Failed to execute `{command}`");
+        if cassette::replaying() {
+            return Err(eyre!("Cannot replay `{command}`, which runs in the background"));
+        }
 
         // This is where we implement `spawn_checked`, which is what we prefer to use instead of
         // `spawn`, so we allow `Command::spawn` here.
@@ -227,14 +227,14 @@ impl CommandExt for Command {
     }
 }
 
-fn get_program_and_args(cmd: &Command) -> (String, String) {
+pub(crate) fn get_program_and_args(cmd: &Command) -> (String, String) {
     // We're not doing anything weird with commands that are invalid UTF-8 so this is fine.
     let program = cmd.get_program().to_string_lossy().into_owned();
     let args = shell_words::join(cmd.get_args().map(|arg| arg.to_string_lossy()));
     (program, args)
 }
 
-fn format_program_and_args(cmd: &Command) -> String {
+pub(crate) fn format_program_and_args(cmd: &Command) -> String {
     let (program, args) = get_program_and_args(cmd);
     if args.is_empty() {
         program
@@ -1,13 +1,15 @@
 #![allow(dead_code)]
 
+use std::ffi::OsStr;
 use std::fs::{write, File};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::{env, fmt, fs};
 
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 use clap_complete::Shell;
+use color_eyre::eyre::eyre;
 use color_eyre::eyre::Context;
 use color_eyre::eyre::Result;
 use etcetera::base_strategy::BaseStrategy;
@@ -15,15 +17,24 @@ use indexmap::IndexMap;
 use merge::Merge;
 use regex::Regex;
 use regex_split::RegexSplit;
-use serde::Deserialize;
+use schemars::JsonSchema;
+use serde::{de, Deserialize, Deserializer, Serialize};
 use strum::IntoEnumIterator;
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 use which_crate::which;
+use wildmatch::WildMatch;
 
 use super::utils::editor;
 use crate::command::CommandExt;
+use crate::config_env::{self, Override};
+use crate::config_provenance::{self, Origin, Source};
+use crate::config_validation::Diagnostic;
+use crate::error::ConfigError;
+use crate::events::EventsTarget;
 use crate::execution_context::RunType;
+use crate::notifications::{Backend, Notifier};
 use crate::step::Step;
+use crate::steps::binaries;
 use crate::sudo::SudoKind;
 use crate::utils::string_prepend_str;
 
@@ -47,14 +58,17 @@ macro_rules! str_value {
 
 pub type Commands = IndexMap<String, String>;
 
-#[derive(Deserialize, Default, Debug, Merge)]
+/// Configurations by the condition under which they apply, e.g. a hostname pattern.
+pub type Overlays = IndexMap<String, ConfigFile>;
+
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 pub struct Include {
     #[merge(strategy = crate::utils::merge_strategies::vec_prepend_opt)]
     paths: Option<Vec<String>>,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 pub struct Containers {
     #[merge(strategy = crate::utils::merge_strategies::vec_prepend_opt)]
@@ -63,13 +77,13 @@ pub struct Containers {
     system_prune: Option<bool>,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 pub struct Mandb {
     enable: Option<bool>,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 pub struct Git {
     max_concurrency: Option<usize>,
@@ -83,7 +97,7 @@ pub struct Git {
     pull_predefined: Option<bool>,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 pub struct Vagrant {
     #[merge(strategy = crate::utils::merge_strategies::vec_prepend_opt)]
@@ -93,7 +107,7 @@ pub struct Vagrant {
     always_suspend: Option<bool>,
 }
 
-#[derive(Deserialize, Default, Debug, Copy, Clone)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Copy, Clone)]
 #[serde(rename_all = "snake_case")]
 pub enum UpdatesAutoReboot {
     Yes,
@@ -102,7 +116,7 @@ pub enum UpdatesAutoReboot {
     Ask,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 pub struct Windows {
     accept_all_updates: Option<bool>,
@@ -115,7 +129,7 @@ pub struct Windows {
     winget_use_sudo: Option<bool>,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 pub struct Python {
     enable_pip_review: Option<bool>,
@@ -125,7 +139,7 @@ pub struct Python {
     poetry_force_self_update: Option<bool>,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 pub struct Conda {
     #[merge(strategy = crate::utils::merge_strategies::vec_prepend_opt)]
@@ -135,7 +149,7 @@ pub struct Conda {
     env_paths: Option<Vec<String>>,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 #[allow(clippy::upper_case_acronyms)]
 pub struct Distrobox {
@@ -145,35 +159,35 @@ pub struct Distrobox {
     containers: Option<Vec<String>>,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 #[allow(clippy::upper_case_acronyms)]
 pub struct Yarn {
     use_sudo: Option<bool>,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 #[allow(clippy::upper_case_acronyms)]
 pub struct NPM {
     use_sudo: Option<bool>,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 #[allow(clippy::upper_case_acronyms)]
 pub struct Deno {
     version: Option<String>,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 #[allow(clippy::upper_case_acronyms)]
 pub struct Chezmoi {
     exclude_encrypted: Option<bool>,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 #[allow(clippy::upper_case_acronyms)]
 pub struct Mise {
@@ -182,28 +196,28 @@ pub struct Mise {
     jobs: Option<u32>,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 #[allow(clippy::upper_case_acronyms)]
 pub struct Firmware {
     upgrade: Option<bool>,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 #[allow(clippy::upper_case_acronyms)]
 pub struct Flatpak {
     use_sudo: Option<bool>,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 #[allow(clippy::upper_case_acronyms)]
 pub struct Pixi {
     include_release_notes: Option<bool>,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 pub struct Brew {
     greedy_cask: Option<bool>,
@@ -213,7 +227,7 @@ pub struct Brew {
     fetch_head: Option<bool>,
 }
 
-#[derive(Debug, Deserialize, Clone, Copy, Default)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, Default)]
 #[serde(rename_all = "snake_case")]
 pub enum ArchPackageManager {
     #[default]
@@ -228,7 +242,7 @@ pub enum ArchPackageManager {
     Yay,
 }
 
-#[derive(Clone, Copy, Debug, Deserialize, Default)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, JsonSchema, Default)]
 #[serde(rename_all = "snake_case")]
 pub enum ContainerRuntime {
     #[default] // defaults to a popular choice
@@ -239,13 +253,13 @@ pub enum ContainerRuntime {
 impl fmt::Display for ContainerRuntime {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            ContainerRuntime::Docker => write!(f, "docker"),
-            ContainerRuntime::Podman => write!(f, "podman"),
+            ContainerRuntime::Docker => write!(f, "{}", binaries::DOCKER),
+            ContainerRuntime::Podman => write!(f, "{}", binaries::PODMAN),
         }
     }
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 pub struct Linux {
     #[merge(strategy = crate::utils::merge_strategies::string_append_opt)]
@@ -299,19 +313,19 @@ pub struct Linux {
     home_manager_arguments: Option<Vec<String>>,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 pub struct Composer {
     self_update: Option<bool>,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 pub struct Vim {
     force_plug_update: Option<bool>,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 pub struct Misc {
     allow_root: Option<bool>,
@@ -331,6 +345,9 @@ pub struct Misc {
 
     remote_topgrade_path: Option<String>,
 
+    #[merge(strategy = crate::utils::merge_strategies::vec_prepend_opt)]
+    users: Option<Vec<String>>,
+
     #[merge(strategy = crate::utils::merge_strategies::string_append_opt)]
     ssh_arguments: Option<String>,
 
@@ -367,9 +384,32 @@ pub struct Misc {
     log_filters: Option<Vec<String>>,
 
     show_distribution_summary: Option<bool>,
+
+    output: Option<OutputMode>,
+
+    metrics_file: Option<String>,
+
+    audit_log: Option<String>,
+
+    env_policy: Option<EnvPolicy>,
+
+    #[merge(strategy = crate::utils::merge_strategies::vec_prepend_opt)]
+    env_allowlist: Option<Vec<String>>,
+
+    #[serde(default, deserialize_with = "deserialize_nice")]
+    #[schemars(range(min = -20, max = 19))]
+    nice: Option<i32>,
+
+    ionice_class: Option<IoniceClass>,
+
+    cpu_quota: Option<String>,
+
+    memory_max: Option<String>,
+
+    strict_config: Option<bool>,
 }
 
-#[derive(Clone, Copy, Debug, Deserialize, ValueEnum, Default)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, JsonSchema, ValueEnum, Default)]
 #[clap(rename_all = "snake_case")]
 #[serde(rename_all = "snake_case")]
 pub enum TmuxSessionMode {
@@ -378,24 +418,212 @@ pub enum TmuxSessionMode {
     AttachAlways,
 }
 
+/// How the output of the steps is displayed
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, JsonSchema, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputMode {
+    /// Show everything the commands print
+    #[default]
+    Full,
+    /// Show a single status line per step, and the output of a step only if it fails or asks for input
+    Compact,
+}
+
+/// Which environment variables the commands inherit from Topgrade
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, JsonSchema, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EnvPolicy {
+    /// All of them
+    #[default]
+    Inherit,
+    /// Only the essential ones, such as `PATH` and `HOME`, and those in `env_allowlist`
+    Clean,
+}
+
+/// The environment variables kept by `env_policy = "clean"`, besides those in `env_allowlist`.
+const ENV_ALLOWLIST: &[&str] = &[
+    "PATH",
+    "HOME",
+    "USER",
+    "LOGNAME",
+    "SHELL",
+    "TERM",
+    "COLORTERM",
+    "LANG",
+    "LANGUAGE",
+    "LC_*",
+    "TZ",
+    "TMPDIR",
+    "DISPLAY",
+    "WAYLAND_DISPLAY",
+    "XDG_*",
+    "DBUS_SESSION_BUS_ADDRESS",
+    "SSH_AUTH_SOCK",
+    "SUDO_ASKPASS",
+    "http_proxy",
+    "https_proxy",
+    "no_proxy",
+    "all_proxy",
+    "HTTP_PROXY",
+    "HTTPS_PROXY",
+    "NO_PROXY",
+    "ALL_PROXY",
+    "SSL_CERT_FILE",
+    "SSL_CERT_DIR",
+    // Windows
+    "SYSTEMROOT",
+    "SYSTEMDRIVE",
+    "WINDIR",
+    "COMSPEC",
+    "PATHEXT",
+    "TEMP",
+    "TMP",
+    "USERNAME",
+    "USERPROFILE",
+    "APPDATA",
+    "LOCALAPPDATA",
+    "PROGRAMDATA",
+    "PROGRAMFILES*",
+    "PROCESSOR_ARCHITECTURE",
+];
+
+/// Whether the environment variable `name` matches one of `patterns`.
+fn env_matches<'a>(name: &OsStr, patterns: impl IntoIterator<Item = &'a str>) -> bool {
+    let name = name.to_string_lossy();
+    patterns.into_iter().any(|pattern| {
+        // The names of environment variables are case-insensitive on Windows
+        if cfg!(windows) {
+            WildMatch::new_case_insensitive(pattern).matches(&name)
+        } else {
+            WildMatch::new(pattern).matches(&name)
+        }
+    })
+}
+
+/// The I/O scheduling class of the commands, set with `ionice`
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IoniceClass {
+    Realtime,
+    BestEffort,
+    Idle,
+}
+
+impl IoniceClass {
+    /// The number of the class, as passed to `ionice -c`.
+    pub fn number(self) -> u8 {
+        match self {
+            IoniceClass::Realtime => 1,
+            IoniceClass::BestEffort => 2,
+            IoniceClass::Idle => 3,
+        }
+    }
+}
+
+/// Deserialize a niceness, which `nice` only accepts from -20 to 19.
+fn deserialize_nice<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<i32>, D::Error> {
+    let nice = i32::deserialize(deserializer)?;
+    if (-20..=19).contains(&nice) {
+        Ok(Some(nice))
+    } else {
+        Err(de::Error::custom(format!(
+            "nice must be between -20 and 19, not {nice}"
+        )))
+    }
+}
+
+/// The priority and resource limits of the commands of a step
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Resources {
+    pub nice: Option<i32>,
+    pub ionice_class: Option<IoniceClass>,
+    /// The `CPUQuota` of the `systemd-run` scope of the commands, e.g. `50%`.
+    pub cpu_quota: Option<String>,
+    /// The `MemoryMax` of the `systemd-run` scope of the commands, e.g. `4G`.
+    pub memory_max: Option<String>,
+}
+
 pub struct TmuxConfig {
     pub args: Vec<String>,
     pub session_mode: TmuxSessionMode,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+/// When a notification is sent at the end of a run
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, JsonSchema, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyOn {
+    #[default]
+    Always,
+    Failure,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookNotification {
+    url: Option<String>,
+    notify_on: Option<NotifyOn>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
+#[serde(deny_unknown_fields)]
+pub struct NtfyNotification {
+    url: Option<String>,
+    token: Option<String>,
+    notify_on: Option<NotifyOn>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
+#[serde(deny_unknown_fields)]
+pub struct GotifyNotification {
+    url: Option<String>,
+    token: Option<String>,
+    notify_on: Option<NotifyOn>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
+#[serde(deny_unknown_fields)]
+pub struct SendmailNotification {
+    to: Option<String>,
+    command: Option<String>,
+    notify_on: Option<NotifyOn>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
+#[serde(deny_unknown_fields)]
+pub struct Security {
+    #[merge(strategy = crate::utils::merge_strategies::vec_prepend_opt)]
+    privileged_commands: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
+#[serde(deny_unknown_fields)]
+pub struct Notifications {
+    #[merge(strategy = crate::utils::merge_strategies::inner_merge_opt)]
+    webhook: Option<WebhookNotification>,
+
+    #[merge(strategy = crate::utils::merge_strategies::inner_merge_opt)]
+    ntfy: Option<NtfyNotification>,
+
+    #[merge(strategy = crate::utils::merge_strategies::inner_merge_opt)]
+    gotify: Option<GotifyNotification>,
+
+    #[merge(strategy = crate::utils::merge_strategies::inner_merge_opt)]
+    sendmail: Option<SendmailNotification>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 pub struct Lensfun {
     use_sudo: Option<bool>,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 pub struct JuliaConfig {
     startup_file: Option<bool>,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 pub struct Zigup {
     target_versions: Option<Vec<String>>,
@@ -404,31 +632,71 @@ pub struct Zigup {
     cleanup: Option<bool>,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 pub struct VscodeConfig {
     profile: Option<String>,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 pub struct DoomConfig {
     aot: Option<bool>,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 pub struct Rustup {
     channels: Option<Vec<String>>,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 pub struct Pkgfile {
     enable: Option<bool>,
 }
 
-#[derive(Deserialize, Default, Debug, Merge)]
+/// Options that every step accepts, in `[step.<name>]`
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
+#[serde(deny_unknown_fields)]
+pub struct StepOptions {
+    #[merge(strategy = crate::utils::merge_strategies::vec_prepend_opt)]
+    extra_args: Option<Vec<String>>,
+
+    #[serde(alias = "env_set")]
+    #[merge(strategy = crate::utils::merge_strategies::commands_merge_opt)]
+    env: Option<IndexMap<String, String>>,
+
+    #[merge(strategy = crate::utils::merge_strategies::vec_prepend_opt)]
+    env_remove: Option<Vec<String>>,
+
+    use_sudo: Option<bool>,
+    assume_yes: Option<bool>,
+    cleanup: Option<bool>,
+    enabled: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_nice")]
+    #[schemars(range(min = -20, max = 19))]
+    nice: Option<i32>,
+    ionice_class: Option<IoniceClass>,
+    cpu_quota: Option<String>,
+    memory_max: Option<String>,
+}
+
+/// Sections of the configuration that only apply to some machines
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
+#[serde(deny_unknown_fields)]
+pub struct When {
+    #[merge(strategy = crate::utils::merge_strategies::map_merge_opt)]
+    hostname: Option<Overlays>,
+
+    #[merge(strategy = crate::utils::merge_strategies::map_merge_opt)]
+    distribution: Option<Overlays>,
+
+    #[merge(strategy = crate::utils::merge_strategies::map_merge_opt)]
+    os: Option<Overlays>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Default, Debug, Merge)]
 #[serde(deny_unknown_fields)]
 /// Configuration file
 pub struct ConfigFile {
@@ -527,6 +795,18 @@ pub struct ConfigFile {
 
     #[merge(strategy = crate::utils::merge_strategies::inner_merge_opt)]
     pkgfile: Option<Pkgfile>,
+
+    #[merge(strategy = crate::utils::merge_strategies::inner_merge_opt)]
+    notifications: Option<Notifications>,
+
+    #[merge(strategy = crate::utils::merge_strategies::inner_merge_opt)]
+    security: Option<Security>,
+
+    #[merge(strategy = crate::utils::merge_strategies::map_merge_opt)]
+    step: Option<IndexMap<Step, StepOptions>>,
+
+    #[merge(strategy = crate::utils::merge_strategies::inner_merge_opt)]
+    when: Option<When>,
 }
 
 fn config_directory() -> PathBuf {
@@ -537,6 +817,37 @@ fn config_directory() -> PathBuf {
     return crate::WINDOWS_DIRS.config_dir();
 }
 
+/// The format of a configuration file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Format {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl Format {
+    /// The extensions of the main configuration file, in the order they are looked for.
+    const EXTENSIONS: [&'static str; 4] = ["toml", "json", "yaml", "yml"];
+
+    /// The format of the file at `path`, by its extension, TOML being the default.
+    pub(crate) fn of(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Format::Json,
+            Some("yaml" | "yml") => Format::Yaml,
+            _ => Format::Toml,
+        }
+    }
+
+    /// Parse `contents` into a table, which has the same structure in every format.
+    fn parse(self, contents: &str) -> Result<toml::Table> {
+        Ok(match self {
+            Format::Toml => toml::from_str(contents)?,
+            Format::Json => serde_json::from_str(contents)?,
+            Format::Yaml => serde_yaml_ng::from_str(contents)?,
+        })
+    }
+}
+
 /// The only purpose of this struct is to deserialize only the `include` field of the config file.
 #[derive(Deserialize, Default, Debug)]
 struct ConfigFileIncludeOnly {
@@ -551,11 +862,7 @@ impl ConfigFile {
         let mut res = (PathBuf::new(), Vec::new());
 
         let config_directory = config_directory();
-
-        let possible_config_paths = [
-            config_directory.join("topgrade.toml"),
-            config_directory.join("topgrade/topgrade.toml"),
-        ];
+        let possible_config_paths = Self::possible_paths(&config_directory);
 
         // Search for the main config file
         for path in &possible_config_paths {
@@ -585,6 +892,18 @@ impl ConfigFile {
         Ok(res)
     }
 
+    /// The paths where the main config file is looked for, in order.
+    fn possible_paths(config_directory: &Path) -> Vec<PathBuf> {
+        [config_directory.to_path_buf(), config_directory.join("topgrade")]
+            .iter()
+            .flat_map(|directory| {
+                Format::EXTENSIONS
+                    .iter()
+                    .map(move |extension| directory.join(format!("topgrade.{extension}")))
+            })
+            .collect()
+    }
+
     /// Searches topgrade.d for additional config files
     fn ensure_topgrade_d(config_directory: &Path) -> Result<Vec<PathBuf>> {
         let mut res = Vec::new();
@@ -615,7 +934,9 @@ impl ConfigFile {
     /// Read the configuration file.
     ///
     /// If the configuration file does not exist, the function returns the default ConfigFile.
-    fn read(config_path: Option<PathBuf>) -> Result<ConfigFile> {
+    /// The parts that are merged are added to `sources`, in the order they are merged, and `strict`
+    /// is set if `misc.strict_config` is set in any of them, even in a part that is ignored.
+    fn read(config_path: Option<PathBuf>, sources: &mut Vec<Source>, strict: &mut bool) -> Result<ConfigFile> {
         let mut result = Self::default();
 
         let config_path = if let Some(path) = config_path {
@@ -631,11 +952,16 @@ impl ConfigFile {
                 let include_contents = fs::read_to_string(&include).inspect_err(|_| {
                     error!("Unable to read {}", include.display());
                 })?;
-                let include_contents_parsed = toml::from_str(include_contents.as_str()).inspect_err(|_| {
-                    error!("Failed to deserialize {}", include.display());
-                })?;
-
-                result.merge(include_contents_parsed);
+                match Self::parse_lenient(&include_contents, &include, strict) {
+                    Ok((parsed, table)) => {
+                        result.merge(parsed);
+                        sources.push(Source {
+                            origin: Origin::File(include),
+                            table,
+                        });
+                    }
+                    Err(e) => error!("Failed to deserialize {}: {e}", include.display()),
+                }
             }
 
             path
@@ -651,19 +977,24 @@ impl ConfigFile {
             error!("Unable to read {}", config_path.display());
         })?;
 
-        Self::ensure_misc_is_present(&mut contents_non_split, &config_path);
-
-        // To parse [include] sections in the order as they are written,
-        // we split the file and parse each part as a separate file
-        let regex_match_include = Regex::new(r"^\s*\[include]").expect("Failed to compile regex");
-        let contents_split = regex_match_include.split_inclusive_left(contents_non_split.as_str());
+        // Only TOML files can be split, the others are a single part
+        let parts = if Format::of(&config_path) == Format::Toml {
+            Self::ensure_misc_is_present(&mut contents_non_split, &config_path);
+            split_at_includes(&contents_non_split)
+        } else {
+            vec![contents_non_split.as_str()]
+        };
 
-        for contents in contents_split {
-            let config_file_include_only: ConfigFileIncludeOnly = toml::from_str(contents).inspect_err(|_| {
-                error!("Failed to deserialize an include section of {}", config_path.display());
-            })?;
+        for contents in parts {
+            let (parsed, table) = match Self::parse_lenient(contents, &config_path, strict) {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    error!("Failed to deserialize {}: {e}", config_path.display());
+                    continue;
+                }
+            };
 
-            if let Some(includes) = &config_file_include_only.include {
+            if let Some(includes) = &parsed.include {
                 // Parses the [include] section present in the slice
                 if let Some(ref paths) = includes.paths {
                     for include in paths.iter().rev() {
@@ -676,8 +1007,15 @@ impl ConfigFile {
                                 continue;
                             }
                         };
-                        match toml::from_str::<Self>(&include_contents) {
-                            Ok(include_parsed) => result.merge(include_parsed),
+                        match Self::parse_lenient(&include_contents, &include_path, strict) {
+                            Ok((include_parsed, table)) => {
+                                result.merge(include_parsed);
+                                let origin = Origin::Include {
+                                    path: include_path,
+                                    from: config_path.clone(),
+                                };
+                                sources.push(Source { origin, table });
+                            }
                             Err(e) => {
                                 error!("Failed to deserialize {}: {e}", include_path.display(),);
                                 continue;
@@ -687,16 +1025,228 @@ impl ConfigFile {
                 }
             }
 
-            match toml::from_str::<Self>(contents) {
-                Ok(contents) => result.merge(contents),
-                Err(e) => error!("Failed to deserialize {}: {e}", config_path.display(),),
-            }
+            result.merge(parsed);
+            sources.push(Source {
+                origin: Origin::File(config_path.clone()),
+                table,
+            });
         }
 
         debug!("Loaded configuration: {:?}", result);
         Ok(result)
     }
 
+    /// Parse the configuration in `contents`, read from `path`, with the sections that are valid.
+    ///
+    /// A section that cannot be parsed, e.g. because of an option of another version of Topgrade,
+    /// is ignored with a warning instead of losing the whole file. The sections that are kept are
+    /// returned as written, and an error is only returned if `contents` is not valid TOML, or JSON
+    /// or YAML depending on the extension of `path`. `strict` is set if `misc.strict_config` is set,
+    /// even if that section is ignored.
+    fn parse_lenient(contents: &str, path: &Path, strict: &mut bool) -> Result<(Self, toml::Table)> {
+        let table = Format::of(path).parse(contents)?;
+        *strict |= strict_config(&table);
+        if let Ok(parsed) = toml::Value::Table(table.clone()).try_into() {
+            return Ok((parsed, table));
+        }
+
+        let mut result = Self::default();
+        let mut valid = toml::Table::new();
+        for (section, value) in table {
+            let mut single = toml::Table::new();
+            single.insert(section.clone(), value);
+            match toml::Value::Table(single.clone()).try_into::<Self>() {
+                Ok(parsed) => {
+                    result.merge(parsed);
+                    valid.extend(single);
+                }
+                Err(e) => {
+                    // Name the keys that cannot be parsed on their own, if the section is a table
+                    let mut errors: Vec<(String, String)> = Vec::new();
+                    if let Some(toml::Value::Table(keys)) = single.get(&section) {
+                        for (key, value) in keys {
+                            let mut table = toml::Table::new();
+                            table.insert(key.clone(), value.clone());
+                            let mut single = toml::Table::new();
+                            single.insert(section.clone(), toml::Value::Table(table));
+                            if let Err(e) = toml::Value::Table(single).try_into::<Self>() {
+                                errors.push((format!("{section}.{key}"), e.message().trim().to_string()));
+                            }
+                        }
+                    }
+                    if errors.is_empty() {
+                        errors.push((section.clone(), e.message().trim().to_string()));
+                    }
+
+                    for (key, message) in errors {
+                        warn!(
+                            "Ignoring the [{section}] section of {} because of `{key}`: {message}",
+                            path.display()
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok((result, valid))
+    }
+
+    /// Check the configuration files read by `read` for errors.
+    ///
+    /// Unlike `read`, which skips the parts of the files it cannot parse, this reports all errors.
+    fn validate(config_path: Option<PathBuf>) -> Result<Validation> {
+        let mut validation = Validation::default();
+
+        let config_path = if let Some(path) = config_path {
+            path
+        } else {
+            let (path, dir_include) = Self::ensure()?;
+            for include in dir_include {
+                validation.check_file(&include, false);
+            }
+            path
+        };
+
+        if config_path != PathBuf::default() {
+            validation.check_file(&config_path, true);
+        }
+
+        Ok(validation)
+    }
+
+    /// Merge the `[when]` sections that apply to this machine, which take precedence over the
+    /// rest of the configuration.
+    ///
+    /// The sections of the hostname take precedence over the ones of the distribution, which take
+    /// precedence over the ones of the OS. The sections that are merged are added to the front of
+    /// `sources`.
+    fn apply_overlays(mut self, sources: &mut Vec<Source>) -> Self {
+        let Some(when) = self.when.take() else {
+            return self;
+        };
+
+        let mut result = Self::default();
+        let mut overlay_sources = Vec::new();
+        for (kind, overlays) in [
+            ("hostname", when.hostname),
+            ("distribution", when.distribution),
+            ("os", when.os),
+        ] {
+            for (condition, overlay) in overlays.into_iter().flatten() {
+                if !overlay_matches(kind, &condition) {
+                    continue;
+                }
+
+                let section = format!("when.{kind}.{}", config_provenance::key(&condition));
+                debug!("Merging [{section}]");
+                for source in sources.iter() {
+                    if let Some(toml::Value::Table(table)) =
+                        config_provenance::lookup(&source.table, &["when", kind, &condition])
+                    {
+                        overlay_sources.push(Source {
+                            origin: Origin::Overlay {
+                                section: section.clone(),
+                                origin: Box::new(source.origin.clone()),
+                            },
+                            table: table.clone(),
+                        });
+                    }
+                }
+                result.merge(overlay.apply_overlays(&mut Vec::new()));
+            }
+        }
+
+        result.merge(self);
+        sources.splice(0..0, overlay_sources);
+        result
+    }
+
+    /// The configuration set by the `TOPGRADE__<SECTION>__<KEY>` environment variables, with the
+    /// errors in them.
+    ///
+    /// The variables that are read are added to `sources`.
+    fn from_environment(sources: &mut Vec<Source>) -> (Vec<Override>, Vec<Diagnostic>) {
+        Self::from_overrides(config_env::overrides(env::vars()), Origin::Environment, sources)
+    }
+
+    /// The configuration set by the `--set KEY=VALUE` arguments, with the errors in them.
+    ///
+    /// The keys that are set are added to `sources`.
+    fn from_settings(args: &[String], sources: &mut Vec<Source>) -> (Vec<Override>, Vec<Diagnostic>) {
+        Self::from_overrides(config_env::settings(args), Origin::Set, sources)
+    }
+
+    /// The valid `overrides`, in order, with the errors in the others.
+    fn from_overrides(
+        overrides: Vec<Result<Override, Diagnostic>>,
+        origin: fn(String) -> Origin,
+        sources: &mut Vec<Source>,
+    ) -> (Vec<Override>, Vec<Diagnostic>) {
+        let mut valid = Vec::new();
+        let mut diagnostics = Vec::new();
+        for r#override in overrides {
+            let r#override = match r#override {
+                Ok(r#override) => r#override,
+                Err(diagnostic) => {
+                    diagnostics.push(diagnostic);
+                    continue;
+                }
+            };
+
+            match toml::Value::Table(r#override.table.clone()).try_into::<Self>() {
+                Ok(_) => {
+                    sources.insert(
+                        0,
+                        Source {
+                            origin: origin(r#override.name.clone()),
+                            table: r#override.table.clone(),
+                        },
+                    );
+                    valid.push(r#override);
+                }
+                Err(e) => diagnostics.push(Diagnostic::new(
+                    Path::new(&origin(r#override.name).to_string()),
+                    e.message().trim(),
+                )),
+            }
+        }
+
+        (valid, diagnostics)
+    }
+
+    /// This configuration with the keys set by `overrides` replaced, the later ones taking
+    /// precedence.
+    ///
+    /// Unlike the files, which are merged, an override replaces the value of its key, so that
+    /// e.g. `--set misc.disable=["emacs"]` does not add to the configured list.
+    fn overridden(self, overrides: &[Override]) -> Result<Self> {
+        if overrides.is_empty() {
+            return Ok(self);
+        }
+
+        let mut table = toml::Table::try_from(&self).context("Failed to serialize the configuration")?;
+        for r#override in overrides {
+            r#override.apply(&mut table);
+        }
+        let config: Self = toml::Value::Table(table)
+            .try_into()
+            .context("Failed to apply the environment variables and --set")?;
+        Ok(config.apply_overlays(&mut Vec::new()))
+    }
+
+    /// The files read by `read`, except the included ones.
+    fn files(config_path: Option<PathBuf>) -> Result<Vec<PathBuf>> {
+        if let Some(path) = config_path {
+            return Ok(vec![path]);
+        }
+
+        let (path, mut files) = Self::ensure()?;
+        if path != PathBuf::default() {
+            files.push(path);
+        }
+        Ok(files)
+    }
+
     fn edit() -> Result<()> {
         let config_path = Self::ensure()?.0;
         let editor = editor();
@@ -725,6 +1275,166 @@ impl ConfigFile {
     }
 }
 
+/// Whether the `[when.<kind>.<condition>]` sections apply to this machine.
+fn overlay_matches(kind: &str, condition: &str) -> bool {
+    match kind {
+        "hostname" => crate::utils::hostname().is_ok_and(|hostname| WildMatch::new(condition).matches(&hostname)),
+        #[cfg(target_os = "linux")]
+        "distribution" => crate::steps::os::linux::Distribution::detect()
+            .is_ok_and(|distribution| distribution.to_string() == condition),
+        "os" => condition == env::consts::OS || condition == env::consts::FAMILY,
+        _ => false,
+    }
+}
+
+/// To parse [include] sections in the order as they are written,
+/// we split the file and parse each part as a separate file
+pub(crate) fn split_at_includes(contents: &str) -> Vec<&str> {
+    let regex_match_include = Regex::new(r"^\s*\[include]").expect("Failed to compile regex");
+    regex_match_include.split_inclusive_left(contents).collect()
+}
+
+/// The files included by the `[include]` sections of `contents`, a TOML configuration file.
+pub(crate) fn include_paths(contents: &str) -> Vec<PathBuf> {
+    split_at_includes(contents)
+        .into_iter()
+        .filter_map(|part| toml::from_str::<ConfigFileIncludeOnly>(part).ok()?.include?.paths)
+        .flatten()
+        .map(|include| PathBuf::from(shellexpand::tilde(&include).into_owned()))
+        .collect()
+}
+
+/// Whether `misc.strict_config` is set in `table`, a configuration file or a part of it.
+fn strict_config(table: &toml::Table) -> bool {
+    table
+        .get("misc")
+        .and_then(|misc| misc.get("strict_config"))
+        .and_then(toml::Value::as_bool)
+        .unwrap_or(false)
+}
+
+/// The errors found in the configuration files.
+#[derive(Debug, Default)]
+pub struct Validation {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Validation {
+    /// Check the configuration file at `path`, and the files it includes if it is the main one.
+    fn check_file(&mut self, path: &Path, main: bool) {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                self.diagnostics
+                    .push(Diagnostic::new(path, format!("Unable to read the file: {e}")));
+                return;
+            }
+        };
+
+        let format = Format::of(path);
+        if format != Format::Toml {
+            self.check_table(path, &contents, format, main);
+            return;
+        }
+
+        let parts = if main {
+            split_at_includes(&contents)
+        } else {
+            vec![contents.as_str()]
+        };
+        for part in parts {
+            let offset = part.as_ptr() as usize - contents.as_ptr() as usize;
+
+            if main {
+                if let Ok(ConfigFileIncludeOnly {
+                    include: Some(Include { paths: Some(paths) }),
+                }) = toml::from_str(part)
+                {
+                    for include in paths {
+                        let include_path = PathBuf::from(shellexpand::tilde(&include).into_owned());
+                        self.check_file(&include_path, false);
+                    }
+                }
+            }
+
+            if let Err(e) = toml::from_str::<ConfigFile>(part) {
+                self.diagnostics
+                    .push(Diagnostic::from_toml(path, &contents, offset, &e));
+            }
+        }
+    }
+
+    /// Check a configuration file in `format` other than TOML, which is not split in parts.
+    fn check_table(&mut self, path: &Path, contents: &str, format: Format, main: bool) {
+        let table = match format.parse(contents) {
+            Ok(table) => table,
+            Err(e) => {
+                self.diagnostics.push(Diagnostic::new(path, e));
+                return;
+            }
+        };
+
+        if main {
+            if let Ok(ConfigFileIncludeOnly {
+                include: Some(Include { paths: Some(paths) }),
+            }) = toml::Value::Table(table.clone()).try_into()
+            {
+                for include in paths {
+                    let include_path = PathBuf::from(shellexpand::tilde(&include).into_owned());
+                    self.check_file(&include_path, false);
+                }
+            }
+        }
+
+        if let Err(e) = toml::Value::Table(table).try_into::<ConfigFile>() {
+            self.diagnostics.push(Diagnostic::new(path, e.message().trim()));
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.diagnostics.is_empty()
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SubCommand {
+    /// Manage the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Check the configuration files for errors, and exit with 6 if there are any
+    Validate,
+    /// Show the configuration files, in the order they are merged
+    Show {
+        /// Show the merged configuration in effect instead, with where each value comes from
+        #[arg(long)]
+        effective: bool,
+    },
+    /// Print the JSON Schema of the configuration file, for editors
+    Schema,
+    /// Write a configuration for this machine, with the sections of the tools that are installed
+    Init {
+        /// Only print the configuration
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+        /// Replace the configuration file if there is one, keeping a backup
+        #[arg(short = 'f', long)]
+        force: bool,
+    },
+    /// Rewrite the configuration files for the breaking changes of the new releases
+    Migrate {
+        /// Only show the changes
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+        /// Apply the changes without asking
+        #[arg(short = 'y', long)]
+        yes: bool,
+    },
+}
+
 // Command line arguments
 // TODO: i18n of clap currently not easily possible. Waiting for https://github.com/clap-rs/clap/issues/380
 // Tracking issue for i18n: https://github.com/topgrade-rs/topgrade/issues/859
@@ -757,10 +1467,28 @@ pub struct CommandLineArgs {
     #[arg(short = 'n', long = "dry-run")]
     dry_run: bool,
 
+    /// Write the commands of the dry run to a POSIX shell script
+    #[arg(long = "emit-script", value_name = "FILE", requires = "dry_run")]
+    emit_script: Option<PathBuf>,
+
+    /// Print the commands that would be run with elevated privileges, and whether the [security]
+    /// section allows them, without running anything
+    #[arg(long = "print-privileged-plan")]
+    print_privileged_plan: bool,
+
     /// Pick between just running commands, running and logging commands, and just logging commands
     #[arg(short = 'r', long = "run-type", value_enum, default_value_t)]
     run_type: RunType,
 
+    /// The cassette to record the commands to with `--run-type record`, or to replay them from
+    /// with `--run-type replay`
+    #[arg(
+        long = "cassette",
+        value_name = "PATH",
+        required_if_eq_any = [("run_type", "record"), ("run_type", "replay")]
+    )]
+    cassette: Option<PathBuf>,
+
     /// Do not ask to retry failed steps
     #[arg(long = "no-retry")]
     no_retry: bool,
@@ -781,6 +1509,11 @@ pub struct CommandLineArgs {
     #[arg(long = "env", value_name = "NAME=VALUE", num_args = 1..)]
     env: Vec<String>,
 
+    /// Set a key of the configuration, taking precedence over the configuration files, e.g.
+    /// `--set git.max_concurrency=4`. The value is TOML, or a string.
+    #[arg(long = "set", value_name = "KEY=VALUE", global = true)]
+    set: Vec<String>,
+
     /// Output debug logs. Alias for `--log-filter debug`.
     #[arg(short = 'v', long = "verbose")]
     pub verbose: bool,
@@ -808,9 +1541,13 @@ pub struct CommandLineArgs {
     disable_predefined_git_repos: bool,
 
     /// Alternative configuration file
-    #[arg(long = "config", value_name = "PATH")]
+    #[arg(long = "config", value_name = "PATH", global = true)]
     config: Option<PathBuf>,
 
+    /// Refuse to run if the configuration files have errors, instead of ignoring the invalid parts
+    #[arg(long = "strict-config")]
+    strict_config: bool,
+
     /// A regular expression for restricting remote host execution
     #[arg(long = "remote-host-limit", value_name = "REGEX")]
     remote_host_limit: Option<Regex>,
@@ -840,6 +1577,31 @@ pub struct CommandLineArgs {
     /// Don't update Topgrade
     #[arg(long = "no-self-update")]
     pub no_self_update: bool,
+
+    /// Show a single status line per step, and the output of a step only if it fails or asks for input
+    #[arg(short = 'q', long = "quiet")]
+    quiet: bool,
+
+    /// Write newline-delimited JSON events describing the progress of the run to a file, or to an
+    /// inherited file descriptor with `fd:N`
+    #[arg(long = "events", value_name = "FILE|fd:N")]
+    events: Option<EventsTarget>,
+
+    /// Write metrics of the run for the Prometheus textfile collector to a `.prom` file
+    #[arg(long = "metrics-file", value_name = "PATH")]
+    metrics_file: Option<PathBuf>,
+
+    /// Append every command run, with its step, exit code and whether it was elevated, to this
+    /// audit log
+    #[arg(long = "audit-log", value_name = "PATH")]
+    audit_log: Option<PathBuf>,
+
+    /// Run the user-level steps again as each of these users, with their own configuration
+    #[arg(long = "for-users", value_name = "USER", num_args = 1.., value_delimiter = ',')]
+    for_users: Vec<String>,
+
+    #[command(subcommand)]
+    command: Option<SubCommand>,
 }
 
 impl CommandLineArgs {
@@ -847,10 +1609,18 @@ impl CommandLineArgs {
         self.edit_config
     }
 
+    pub fn subcommand(&self) -> Option<&SubCommand> {
+        self.command.as_ref()
+    }
+
     pub fn show_config_reference(&self) -> bool {
         self.show_config_reference
     }
 
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
     pub fn env_variables(&self) -> &Vec<String> {
         &self.env
     }
@@ -887,19 +1657,44 @@ pub struct Config {
     opt: CommandLineArgs,
     config_file: ConfigFile,
     allowed_steps: Vec<Step>,
+    /// The parts of the configuration files that were merged into `config_file`.
+    sources: Vec<Source>,
 }
 
 impl Config {
+    /// The configuration of `toml`, with the default command line arguments.
+    #[cfg(test)]
+    pub(crate) fn from_toml(toml: &str) -> Self {
+        Self::from_toml_args(toml, &[])
+    }
+
+    /// The configuration of `toml`, with the command line arguments `args`.
+    #[cfg(test)]
+    pub(crate) fn from_toml_args(toml: &str, args: &[String]) -> Self {
+        let opt = CommandLineArgs::parse_from(std::iter::once("topgrade").chain(args.iter().map(String::as_str)));
+        let config_file: ConfigFile = toml::from_str(toml).unwrap();
+        let allowed_steps = Self::allowed_steps(&opt, &config_file);
+        Self {
+            opt,
+            config_file,
+            allowed_steps,
+            sources: Vec::new(),
+        }
+    }
+
     /// Load the configuration.
     ///
     /// The function parses the command line arguments and reads the configuration file.
     pub fn load(opt: CommandLineArgs) -> Result<Self> {
         let config_directory = config_directory();
+        let mut sources = Vec::new();
+        let mut strict = opt.strict_config;
         let config_file = if config_directory.is_dir() {
-            ConfigFile::read(opt.config.clone()).unwrap_or_else(|e| {
+            ConfigFile::read(opt.config.clone(), &mut sources, &mut strict).unwrap_or_else(|e| {
                 // Inform the user about errors when loading the configuration,
                 // but fallback to the default config to at least attempt to do something
                 error!("failed to load configuration: {e}");
+                sources.clear();
                 ConfigFile::default()
             })
         } else {
@@ -907,15 +1702,182 @@ impl Config {
             ConfigFile::default()
         };
 
+        let config_file = config_file.apply_overlays(&mut sources);
+
+        // The environment variables take precedence over the files
+        let mut environment_sources = Vec::new();
+        let (environment, diagnostics) = ConfigFile::from_environment(&mut environment_sources);
+        let mut environment_table = toml::Table::new();
+        for r#override in &environment {
+            r#override.apply(&mut environment_table);
+        }
+        strict |= strict_config(&environment_table);
+        // Like the parts of the files that cannot be parsed, a stray variable is only fatal in
+        // strict mode
+        if !diagnostics.is_empty() {
+            if strict {
+                let diagnostics = diagnostics.iter().map(ToString::to_string);
+                return Err(eyre!(diagnostics.collect::<Vec<_>>().join("\n"))).wrap_err(ConfigError);
+            }
+            for diagnostic in &diagnostics {
+                warn!("Ignoring {diagnostic}");
+            }
+        }
+        let config_file = config_file.overridden(&environment).wrap_err(ConfigError)?;
+        sources.splice(0..0, environment_sources);
+
+        // And `--set` takes precedence over both
+        let mut setting_sources = Vec::new();
+        let (settings, diagnostics) = ConfigFile::from_settings(&opt.set, &mut setting_sources);
+        if !diagnostics.is_empty() {
+            let diagnostics = diagnostics.iter().map(ToString::to_string);
+            return Err(eyre!(diagnostics.collect::<Vec<_>>().join("\n"))).wrap_err(ConfigError);
+        }
+        let config_file = config_file.overridden(&settings).wrap_err(ConfigError)?;
+        sources.splice(0..0, setting_sources);
+
+        // The files are only read again to report all their errors in strict mode
+        if strict && (config_directory.is_dir() || opt.config.is_some()) {
+            let validation = ConfigFile::validate(opt.config.clone()).wrap_err(ConfigError)?;
+            if !validation.is_valid() {
+                let diagnostics = validation.diagnostics.iter().map(ToString::to_string);
+                return Err(eyre!(diagnostics.collect::<Vec<_>>().join("\n"))).wrap_err(ConfigError);
+            }
+        }
+
         let allowed_steps = Self::allowed_steps(&opt, &config_file);
 
         Ok(Self {
             opt,
             config_file,
             allowed_steps,
+            sources,
         })
     }
 
+    pub fn subcommand(&self) -> Option<&SubCommand> {
+        self.opt.subcommand()
+    }
+
+    /// The configuration files, in the order they were merged.
+    pub fn sources(&self) -> Vec<&Origin> {
+        let mut origins: Vec<&Origin> = Vec::new();
+        for source in &self.sources {
+            if !origins.contains(&&source.origin) {
+                origins.push(&source.origin);
+            }
+        }
+        origins
+    }
+
+    /// The configuration in effect, including the command line flags that override it, as TOML
+    /// annotated with where each value comes from.
+    pub fn show_effective(&self) -> Result<String> {
+        let mut effective = toml::Table::try_from(&self.config_file)?;
+        let mut sources = self.sources.clone();
+
+        for (flag, key, value, append) in self.flag_overrides()? {
+            let misc = effective
+                .entry("misc")
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            if let Some(misc) = misc.as_table_mut() {
+                match (misc.get_mut(key), &value) {
+                    (Some(toml::Value::Array(values)), toml::Value::Array(flag_values)) if append => {
+                        values.extend(flag_values.iter().cloned());
+                    }
+                    _ => {
+                        misc.insert(key.to_string(), value.clone());
+                    }
+                }
+            }
+
+            let mut table = toml::Table::new();
+            table.insert(key.to_string(), value);
+            let mut misc = toml::Table::new();
+            misc.insert("misc".to_string(), toml::Value::Table(table));
+            sources.push(Source {
+                origin: Origin::Flag(flag),
+                table: misc,
+            });
+        }
+
+        Ok(config_provenance::render(&effective, &sources))
+    }
+
+    /// The command line flags which override a `misc` option: the flag, the option, its value, and
+    /// whether the value is added to the list of the configuration instead of replacing it.
+    fn flag_overrides(&self) -> Result<Vec<(&'static str, &'static str, toml::Value, bool)>> {
+        let opt = &self.opt;
+        let mut overrides = Vec::new();
+
+        if !opt.only.is_empty() {
+            overrides.push(("--only", "only", toml::Value::try_from(&opt.only)?, true));
+        }
+        if !opt.disable.is_empty() {
+            overrides.push(("--disable", "disable", toml::Value::try_from(&opt.disable)?, true));
+        }
+        for (set, flag, key) in [
+            (opt.no_retry, "--no-retry", "no_retry"),
+            (opt.cleanup, "--cleanup", "cleanup"),
+            (opt.show_skipped, "--show-skipped", "show_skipped"),
+            (opt.no_self_update, "--no-self-update", "no_self_update"),
+            (opt.run_in_tmux, "--tmux", "run_in_tmux"),
+            (opt.strict_config, "--strict-config", "strict_config"),
+        ] {
+            if set {
+                overrides.push((flag, key, toml::Value::Boolean(true), false));
+            }
+        }
+        if opt.no_tmux {
+            overrides.push(("--no-tmux", "run_in_tmux", toml::Value::Boolean(false), false));
+        }
+        if opt.quiet {
+            overrides.push(("--quiet", "output", toml::Value::try_from(OutputMode::Compact)?, false));
+        }
+        if let Some(path) = &opt.metrics_file {
+            overrides.push(("--metrics-file", "metrics_file", toml::Value::try_from(path)?, false));
+        }
+        if let Some(path) = &opt.audit_log {
+            overrides.push(("--audit-log", "audit_log", toml::Value::try_from(path)?, false));
+        }
+        if !opt.for_users.is_empty() {
+            overrides.push(("--for-users", "users", toml::Value::try_from(&opt.for_users)?, false));
+        }
+
+        Ok(overrides)
+    }
+
+    /// Check the configuration files for errors.
+    pub fn validate(opt: &CommandLineArgs) -> Result<Validation> {
+        let mut validation = ConfigFile::validate(opt.config.clone())?;
+        validation
+            .diagnostics
+            .extend(ConfigFile::from_environment(&mut Vec::new()).1);
+        validation
+            .diagnostics
+            .extend(ConfigFile::from_settings(&opt.set, &mut Vec::new()).1);
+        Ok(validation)
+    }
+
+    /// The configuration files that are read, except the included ones.
+    pub fn files(opt: &CommandLineArgs) -> Result<Vec<PathBuf>> {
+        ConfigFile::files(opt.config.clone())
+    }
+
+    /// The main configuration file, which may not exist yet.
+    pub fn main_file(opt: &CommandLineArgs) -> PathBuf {
+        if let Some(path) = &opt.config {
+            return path.clone();
+        }
+
+        let possible_paths = ConfigFile::possible_paths(&config_directory());
+        possible_paths
+            .iter()
+            .find(|path| path.exists())
+            .unwrap_or(&possible_paths[0])
+            .clone()
+    }
+
     /// Launch an editor to edit the configuration
     pub fn edit() -> Result<()> {
         ConfigFile::edit()
@@ -1011,13 +1973,22 @@ impl Config {
         }
 
         let mut disabled_steps: Vec<Step> = Vec::new();
-        disabled_steps.extend(&opt.disable);
         if let Some(misc) = config_file.misc.as_ref() {
             if let Some(disabled) = misc.disable.as_ref() {
                 disabled_steps.extend(disabled);
             }
         }
 
+        // `enabled` in `[step.<name>]` overrides `misc.disable`
+        for (step, options) in config_file.step.iter().flatten() {
+            match options.enabled {
+                Some(false) => disabled_steps.push(*step),
+                Some(true) => disabled_steps.retain(|s| s != step),
+                None => {}
+            }
+        }
+        disabled_steps.extend(&opt.disable);
+
         // All steps that are disabled are not enabled, except ones that are passed to `--only`
         enabled_steps.retain(|e| !disabled_steps.contains(e) || opt.only.contains(e));
         enabled_steps
@@ -1055,6 +2026,43 @@ impl Config {
             .unwrap_or_default()
     }
 
+    /// The options of `step` in `[step.<name>]`, if there are any.
+    fn step_options(&self, step: Step) -> Option<&StepOptions> {
+        self.config_file.step.as_ref().and_then(|steps| steps.get(&step))
+    }
+
+    /// Extra arguments to pass to the commands of `step`.
+    pub fn step_extra_args(&self, step: Step) -> &[String] {
+        self.step_options(step)
+            .and_then(|options| options.extra_args.as_deref())
+            .unwrap_or_default()
+    }
+
+    /// Environment variables to set for the commands of `step`.
+    pub fn step_env(&self, step: Step) -> Option<&IndexMap<String, String>> {
+        self.step_options(step).and_then(|options| options.env.as_ref())
+    }
+
+    /// Tell whether the environment variable `name` is removed from the commands of `step`.
+    pub fn step_env_removed(&self, step: Step, name: &OsStr) -> bool {
+        self.step_options(step)
+            .and_then(|options| options.env_remove.as_ref())
+            .is_some_and(|patterns| env_matches(name, patterns.iter().map(String::as_str)))
+    }
+
+    /// Whether the commands of `step` should be run with sudo, if this is set.
+    pub fn step_use_sudo(&self, step: Step) -> Option<bool> {
+        self.step_options(step).and_then(|options| options.use_sudo)
+    }
+
+    /// Tell whether `step` should perform its cleanup, `[step.<name>]` taking precedence over
+    /// `--cleanup` and `misc.cleanup`.
+    pub fn step_cleanup(&self, step: Step) -> bool {
+        self.step_options(step)
+            .and_then(|options| options.cleanup)
+            .unwrap_or_else(|| self.cleanup())
+    }
+
     /// Tell whether we should perform cleanup steps.
     pub fn cleanup(&self) -> bool {
         self.opt.cleanup
@@ -1068,13 +2076,60 @@ impl Config {
 
     /// Get the [RunType] for the current execution
     pub fn run_type(&self) -> RunType {
-        if self.opt.dry_run {
+        if self.opt.dry_run || self.opt.print_privileged_plan {
             RunType::Dry
         } else {
             self.opt.run_type
         }
     }
 
+    /// The shell script to write the commands of the dry run to
+    pub fn emit_script(&self) -> Option<&Path> {
+        self.opt.emit_script.as_deref()
+    }
+
+    /// Tell whether the commands that would be elevated should be printed.
+    pub fn print_privileged_plan(&self) -> bool {
+        self.opt.print_privileged_plan
+    }
+
+    /// Tell whether `command` may be run with elevated privileges.
+    ///
+    /// Without `privileged_commands` in `[security]` any command may be. Otherwise, its patterns
+    /// match either the name of the binary, or its path if they contain a path separator.
+    pub fn privileged_allowed(&self, command: &Path) -> bool {
+        let Some(patterns) = self
+            .config_file
+            .security
+            .as_ref()
+            .and_then(|security| security.privileged_commands.as_ref())
+        else {
+            return true;
+        };
+
+        // A binary given by name is run from the `PATH`
+        let path = if command.components().count() == 1 {
+            which(command).unwrap_or_else(|_| command.to_path_buf())
+        } else {
+            command.to_path_buf()
+        };
+        let name = command.file_name().unwrap_or_default().to_string_lossy();
+        // On Windows, `winget` also matches `winget.exe`
+        let name = match name.len().checked_sub(4) {
+            Some(end) if cfg!(windows) && name[end..].eq_ignore_ascii_case(".exe") => &name[..end],
+            _ => &name,
+        };
+
+        patterns.iter().any(|pattern| {
+            let matcher = WildMatch::new(pattern);
+            if pattern.contains(['/', '\\']) {
+                matcher.matches(&path.to_string_lossy())
+            } else {
+                matcher.matches(name)
+            }
+        })
+    }
+
     /// Tell whether we should not attempt to retry anything.
     pub fn no_retry(&self) -> bool {
         self.opt.no_retry
@@ -1094,6 +2149,19 @@ impl Config {
             .and_then(|misc| misc.remote_topgrades.as_ref())
     }
 
+    /// The users to run the user-level steps for, after the steps of the current user
+    pub fn users(&self) -> &[String] {
+        if !self.opt.for_users.is_empty() {
+            return &self.opt.for_users;
+        }
+
+        self.config_file
+            .misc
+            .as_ref()
+            .and_then(|misc| misc.users.as_deref())
+            .unwrap_or_default()
+    }
+
     /// Path to Topgrade executable used for all remote hosts
     pub fn remote_topgrade_path(&self) -> &str {
         self.config_file
@@ -1148,6 +2216,58 @@ impl Config {
         self.opt.keep_at_end || env::var("TOPGRADE_KEEP_END").is_ok()
     }
 
+    /// The notifiers sending the summary of the run to remote services
+    pub fn notifiers(&self) -> Result<Vec<Notifier>> {
+        fn required(value: Option<&String>, section: &str, key: &str) -> Result<String> {
+            value
+                .cloned()
+                .ok_or_else(|| eyre!("`{key}` is required in [notifications.{section}]"))
+        }
+
+        let mut notifiers = Vec::new();
+        let Some(notifications) = self.config_file.notifications.as_ref() else {
+            return Ok(notifiers);
+        };
+
+        if let Some(webhook) = &notifications.webhook {
+            notifiers.push(Notifier {
+                backend: Backend::Webhook {
+                    url: required(webhook.url.as_ref(), "webhook", "url")?,
+                },
+                notify_on: webhook.notify_on.unwrap_or_default(),
+            });
+        }
+        if let Some(ntfy) = &notifications.ntfy {
+            notifiers.push(Notifier {
+                backend: Backend::Ntfy {
+                    url: required(ntfy.url.as_ref(), "ntfy", "url")?,
+                    token: ntfy.token.clone(),
+                },
+                notify_on: ntfy.notify_on.unwrap_or_default(),
+            });
+        }
+        if let Some(gotify) = &notifications.gotify {
+            notifiers.push(Notifier {
+                backend: Backend::Gotify {
+                    url: required(gotify.url.as_ref(), "gotify", "url")?,
+                    token: required(gotify.token.as_ref(), "gotify", "token")?,
+                },
+                notify_on: gotify.notify_on.unwrap_or_default(),
+            });
+        }
+        if let Some(sendmail) = &notifications.sendmail {
+            notifiers.push(Notifier {
+                backend: Backend::Sendmail {
+                    to: required(sendmail.to.as_ref(), "sendmail", "to")?,
+                    command: sendmail.command.clone(),
+                },
+                notify_on: sendmail.notify_on.unwrap_or_default(),
+            });
+        }
+
+        Ok(notifiers)
+    }
+
     /// Skip sending a notification at the end of a run
     pub fn skip_notify(&self) -> bool {
         if let Some(yes) = self.config_file.misc.as_ref().and_then(|misc| misc.skip_notify) {
@@ -1168,6 +2288,10 @@ impl Config {
 
     /// Whether to say yes to package managers
     pub fn yes(&self, step: Step) -> bool {
+        if let Some(yes) = self.step_options(step).and_then(|options| options.assume_yes) {
+            return yes;
+        }
+
         if let Some(yes) = self.config_file.misc.as_ref().and_then(|misc| misc.assume_yes) {
             return yes;
         }
@@ -1649,6 +2773,94 @@ impl Config {
             .unwrap_or(true)
     }
 
+    /// Where the events of the run should be written to
+    pub fn events(&self) -> Option<&EventsTarget> {
+        self.opt.events.as_ref()
+    }
+
+    /// The cassette to record the commands to or to replay them from
+    pub fn cassette(&self) -> Option<&Path> {
+        self.opt.cassette.as_deref()
+    }
+
+    /// Where the metrics of the run should be written to
+    pub fn metrics_file(&self) -> Option<PathBuf> {
+        self.opt.metrics_file.clone().or_else(|| {
+            self.config_file
+                .misc
+                .as_ref()
+                .and_then(|misc| misc.metrics_file.as_deref())
+                .map(|path| PathBuf::from(shellexpand::tilde(path).into_owned()))
+        })
+    }
+
+    /// Where the commands run should be logged to
+    pub fn audit_log(&self) -> Option<PathBuf> {
+        self.opt.audit_log.clone().or_else(|| {
+            self.config_file
+                .misc
+                .as_ref()
+                .and_then(|misc| misc.audit_log.as_deref())
+                .map(|path| PathBuf::from(shellexpand::tilde(path).into_owned()))
+        })
+    }
+
+    /// The priority and resource limits of the commands of `step`, or of the commands run outside
+    /// of a step, `[step.<name>]` taking precedence over `[misc]`.
+    pub fn resources(&self, step: Option<Step>) -> Resources {
+        let options = step.and_then(|step| self.step_options(step));
+        let misc = self.config_file.misc.as_ref();
+        Resources {
+            nice: options
+                .and_then(|options| options.nice)
+                .or_else(|| misc.and_then(|misc| misc.nice)),
+            ionice_class: options
+                .and_then(|options| options.ionice_class)
+                .or_else(|| misc.and_then(|misc| misc.ionice_class)),
+            cpu_quota: options
+                .and_then(|options| options.cpu_quota.clone())
+                .or_else(|| misc.and_then(|misc| misc.cpu_quota.clone())),
+            memory_max: options
+                .and_then(|options| options.memory_max.clone())
+                .or_else(|| misc.and_then(|misc| misc.memory_max.clone())),
+        }
+    }
+
+    /// Which environment variables the commands inherit
+    pub fn env_policy(&self) -> EnvPolicy {
+        self.config_file
+            .misc
+            .as_ref()
+            .and_then(|misc| misc.env_policy)
+            .unwrap_or_default()
+    }
+
+    /// Tell whether the environment variable `name` is kept by `env_policy = "clean"`.
+    pub fn env_allowed(&self, name: &OsStr) -> bool {
+        let allowlist = self
+            .config_file
+            .misc
+            .as_ref()
+            .and_then(|misc| misc.env_allowlist.as_ref())
+            .into_iter()
+            .flatten()
+            .map(String::as_str);
+        env_matches(name, ENV_ALLOWLIST.iter().copied().chain(allowlist))
+    }
+
+    /// How the output of the steps is displayed
+    pub fn output_mode(&self) -> OutputMode {
+        if self.opt.quiet {
+            return OutputMode::Compact;
+        }
+
+        self.config_file
+            .misc
+            .as_ref()
+            .and_then(|misc| misc.output)
+            .unwrap_or_default()
+    }
+
     #[cfg(target_os = "linux")]
     pub fn npm_use_sudo(&self) -> bool {
         self.config_file
@@ -1878,11 +3090,31 @@ mod test {
         assert!(toml::from_str::<ConfigFile>(str).is_ok());
     }
 
+    /// Test that a configuration is shown with the values given on the command line.
+    #[test]
+    fn test_show_effective() {
+        let config = Config {
+            opt: CommandLineArgs::parse_from(["topgrade", "--only", "cargo", "--no-retry"]),
+            config_file: toml::from_str("[misc]\nonly = [\"pipx\"]\n\n[git]\nrepos = [\"~/src\"]\n").unwrap(),
+            allowed_steps: Vec::new(),
+            sources: Vec::new(),
+        };
+
+        let effective: toml::Table = toml::from_str(&config.show_effective().unwrap()).unwrap();
+        assert_eq!(
+            effective["misc"]["only"],
+            toml::Value::try_from(["pipx", "cargo"]).unwrap()
+        );
+        assert_eq!(effective["misc"]["no_retry"], toml::Value::Boolean(true));
+        assert_eq!(effective["git"]["repos"], toml::Value::try_from(["~/src"]).unwrap());
+    }
+
     fn config() -> Config {
         Config {
             opt: CommandLineArgs::parse_from::<_, String>([]),
             config_file: ConfigFile::default(),
             allowed_steps: Vec::new(),
+            sources: Vec::new(),
         }
     }
 
@@ -1925,6 +3157,314 @@ mod test {
         assert!(!config.should_execute_remote(Ok("hostname".to_string()), "user@remote_hostname"));
     }
 
+    /// Test that the `[when]` sections matching the machine take precedence, and the others are ignored.
+    #[test]
+    fn test_apply_overlays() {
+        let contents = format!(
+            r#"
+[misc]
+assume_yes = false
+disable = ["vim"]
+
+[when.hostname."*".misc]
+assume_yes = true
+
+[when.os.{}.misc]
+disable = ["emacs"]
+no_retry = true
+
+[when.os.plan9.misc]
+cleanup = true
+
+[when.hostname."no such host, really".git]
+repos = ["~/src"]
+"#,
+            env::consts::FAMILY
+        );
+        let mut sources = Vec::new();
+        sources.push(Source {
+            origin: Origin::File(PathBuf::from("topgrade.toml")),
+            table: toml::from_str(&contents).unwrap(),
+        });
+        let config_file = toml::from_str::<ConfigFile>(&contents)
+            .unwrap()
+            .apply_overlays(&mut sources);
+
+        let misc = config_file.misc.as_ref().unwrap();
+        assert_eq!(misc.assume_yes, Some(true));
+        assert_eq!(misc.no_retry, Some(true));
+        assert_eq!(misc.cleanup, None);
+        assert_eq!(misc.disable.as_ref().map(Vec::len), Some(2));
+        assert!(config_file.git.is_none());
+        assert!(config_file.when.is_none());
+        assert_eq!(sources[0].origin.to_string(), "topgrade.toml [when.hostname.\"*\"]");
+    }
+
+    /// Test that the options in `[step.<name>]` apply to their step only.
+    #[test]
+    fn test_step_options() {
+        let mut config = config();
+        config.opt = CommandLineArgs::parse_from(["topgrade", "--cleanup", "--yes", "cargo"]);
+        config.config_file = toml::from_str(
+            r#"
+[misc]
+disable = ["emacs", "vim"]
+
+[step.cargo]
+extra_args = ["--offline"]
+env = { CARGO_NET_RETRY = "5" }
+cleanup = false
+assume_yes = false
+
+[step.emacs]
+enabled = true
+
+[step.pipx]
+enabled = false
+use_sudo = true
+"#,
+        )
+        .unwrap();
+        config.allowed_steps = Config::allowed_steps(&config.opt, &config.config_file);
+
+        assert_eq!(config.step_extra_args(Step::Cargo), ["--offline"]);
+        assert_eq!(config.step_env(Step::Cargo).unwrap()["CARGO_NET_RETRY"], "5");
+        assert!(!config.step_cleanup(Step::Cargo));
+        assert!(config.step_cleanup(Step::Pipx));
+        assert!(!config.yes(Step::Cargo));
+        assert_eq!(config.step_use_sudo(Step::Pipx), Some(true));
+        assert!(config.step_extra_args(Step::Pipx).is_empty());
+        assert!(config.should_run(Step::Emacs));
+        assert!(!config.should_run(Step::Vim));
+        assert!(!config.should_run(Step::Pipx));
+
+        assert!(toml::from_str::<ConfigFile>("[step.carg]\nenabled = false\n").is_err());
+    }
+
+    #[test]
+    fn test_env_policy() {
+        let mut config = config();
+        assert_eq!(config.env_policy(), EnvPolicy::Inherit);
+
+        config.config_file = toml::from_str(
+            r#"
+[misc]
+env_policy = "clean"
+env_allowlist = ["CARGO_*"]
+
+[step.pip3]
+env_remove = ["PYTHON*", "VIRTUAL_ENV"]
+env_set = { PIP_NO_INPUT = "1" }
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.env_policy(), EnvPolicy::Clean);
+        assert!(config.env_allowed(OsStr::new("PATH")));
+        assert!(config.env_allowed(OsStr::new("LC_ALL")));
+        assert!(config.env_allowed(OsStr::new("CARGO_HOME")));
+        assert!(!config.env_allowed(OsStr::new("NODE_OPTIONS")));
+
+        assert!(config.step_env_removed(Step::Pip3, OsStr::new("PYTHONPATH")));
+        assert!(config.step_env_removed(Step::Pip3, OsStr::new("VIRTUAL_ENV")));
+        assert!(!config.step_env_removed(Step::Pip3, OsStr::new("PATH")));
+        assert!(!config.step_env_removed(Step::Cargo, OsStr::new("PYTHONPATH")));
+        assert_eq!(config.step_env(Step::Pip3).unwrap()["PIP_NO_INPUT"], "1");
+    }
+
+    #[test]
+    fn test_resources() {
+        let mut config = config();
+        assert_eq!(config.resources(None), Resources::default());
+
+        config.config_file = toml::from_str(
+            r#"
+[misc]
+nice = 10
+ionice_class = "idle"
+
+[step.system]
+nice = 15
+cpu_quota = "50%"
+memory_max = "4G"
+"#,
+        )
+        .unwrap();
+        let misc = Resources {
+            nice: Some(10),
+            ionice_class: Some(IoniceClass::Idle),
+            ..Resources::default()
+        };
+        assert_eq!(config.resources(None), misc);
+        assert_eq!(config.resources(Some(Step::Cargo)), misc);
+        assert_eq!(
+            config.resources(Some(Step::System)),
+            Resources {
+                nice: Some(15),
+                ionice_class: Some(IoniceClass::Idle),
+                cpu_quota: Some("50%".to_string()),
+                memory_max: Some("4G".to_string()),
+            }
+        );
+
+        assert!(toml::from_str::<ConfigFile>("[misc]\nnice = -20\n").is_ok());
+        assert!(toml::from_str::<ConfigFile>("[misc]\nnice = 20\n").is_err());
+        assert!(toml::from_str::<ConfigFile>("[step.system]\nnice = -21\n").is_err());
+    }
+
+    #[test]
+    fn test_privileged_allowed() {
+        let mut config = config();
+        assert!(config.privileged_allowed(Path::new("rm")));
+
+        config.config_file = toml::from_str(
+            r#"
+[security]
+privileged_commands = ["apt-get", "flatpak", "python3", "/usr/sbin/*"]
+"#,
+        )
+        .unwrap();
+        assert!(config.privileged_allowed(Path::new("apt-get")));
+        assert!(config.privileged_allowed(Path::new("/usr/bin/flatpak")));
+        assert!(config.privileged_allowed(Path::new("/usr/bin/python3")));
+        assert!(config.privileged_allowed(Path::new("/usr/sbin/fwupdmgr")));
+        assert!(!config.privileged_allowed(Path::new("/usr/bin/fwupdmgr")));
+        assert!(!config.privileged_allowed(Path::new("sh")));
+        // The extension is part of the name, except for `.exe` on Windows
+        assert!(!config.privileged_allowed(Path::new("/usr/bin/python3.12")));
+        assert!(!config.privileged_allowed(Path::new("/tmp/apt-get.sh")));
+        assert_eq!(
+            config.privileged_allowed(Path::new("C:\\Program Files\\Flatpak\\flatpak.exe")),
+            cfg!(windows)
+        );
+    }
+
+    /// The configuration file with two keys that are merged rather than replaced between files.
+    fn merged_keys() -> ConfigFile {
+        toml::from_str(
+            r#"
+[misc]
+disable = ["emacs"]
+assume_yes = true
+
+[linux]
+yay_arguments = "--nocleanmenu"
+"#,
+        )
+        .unwrap()
+    }
+
+    /// Test that the environment variables replace the values of the files.
+    #[test]
+    fn test_environment_overrides() {
+        let vars = [
+            ("TOPGRADE__MISC__DISABLE", "containers,firmware"),
+            ("TOPGRADE__LINUX__YAY_ARGUMENTS", "--nodiffmenu"),
+        ]
+        .map(|(var, value)| (var.to_string(), value.to_string()));
+        let (overrides, diagnostics) =
+            ConfigFile::from_overrides(config_env::overrides(vars), Origin::Environment, &mut Vec::new());
+        assert!(diagnostics.is_empty());
+
+        let config_file = merged_keys().overridden(&overrides).unwrap();
+        let misc = config_file.misc.as_ref().unwrap();
+        assert_eq!(misc.disable, Some(vec![Step::Containers, Step::Firmware]));
+        assert_eq!(misc.assume_yes, Some(true));
+        assert_eq!(
+            config_file.linux.as_ref().unwrap().yay_arguments.as_deref(),
+            Some("--nodiffmenu")
+        );
+    }
+
+    /// Test that `--set` replaces the values of the files, the later arguments taking precedence.
+    #[test]
+    fn test_settings_override() {
+        let args = [
+            "misc.disable=[\"git_repos\"]",
+            "misc.disable=[\"containers\"]",
+            "linux.yay_arguments=--nodiffmenu",
+        ]
+        .map(String::from);
+        let (overrides, diagnostics) =
+            ConfigFile::from_overrides(config_env::settings(&args), Origin::Set, &mut Vec::new());
+        assert!(diagnostics.is_empty());
+
+        let config_file = merged_keys().overridden(&overrides).unwrap();
+        let misc = config_file.misc.as_ref().unwrap();
+        assert_eq!(misc.disable, Some(vec![Step::Containers]));
+        assert_eq!(misc.assume_yes, Some(true));
+        assert_eq!(
+            config_file.linux.as_ref().unwrap().yay_arguments.as_deref(),
+            Some("--nodiffmenu")
+        );
+    }
+
+    /// Test that the valid sections are kept when others cannot be parsed.
+    #[test]
+    fn test_parse_lenient() {
+        let mut strict = false;
+        let (config_file, table) = ConfigFile::parse_lenient(
+            r#"
+[misc]
+disable = ["emacs"]
+strict_config = true
+no_such_option = true
+
+[git]
+max_concurrency = 4
+
+[linux]
+yay_arguments = "--nodevel"
+arch_package_manager = "no_such_manager"
+
+[no_such_section]
+key = "value"
+"#,
+            Path::new("topgrade.toml"),
+            &mut strict,
+        )
+        .unwrap();
+
+        assert!(config_file.misc.is_none());
+        assert!(config_file.linux.is_none());
+        assert_eq!(config_file.git.unwrap().max_concurrency, Some(4));
+        assert_eq!(table.keys().collect::<Vec<_>>(), ["git"]);
+        // The option is seen even though its section is ignored
+        assert!(strict);
+
+        assert!(ConfigFile::parse_lenient("[misc", Path::new("topgrade.toml"), &mut strict).is_err());
+    }
+
+    /// Test that JSON and YAML files are read like the TOML ones.
+    #[test]
+    fn test_formats() {
+        let toml = ConfigFile::parse_lenient(
+            "[misc]\ndisable = [\"emacs\"]\n\n[git]\nmax_concurrency = 4\n\n[commands]\n\"Say hi\" = \"echo hi\"\n",
+            Path::new("topgrade.toml"),
+            &mut false,
+        )
+        .unwrap()
+        .1;
+        let json = ConfigFile::parse_lenient(
+            r#"{"misc": {"disable": ["emacs"]}, "git": {"max_concurrency": 4}, "commands": {"Say hi": "echo hi"}}"#,
+            Path::new("topgrade.d/machine.json"),
+            &mut false,
+        )
+        .unwrap()
+        .1;
+        let yaml = ConfigFile::parse_lenient(
+            "misc:\n  disable: [emacs]\ngit:\n  max_concurrency: 4\ncommands:\n  Say hi: echo hi\n",
+            Path::new("topgrade.yml"),
+            &mut false,
+        )
+        .unwrap()
+        .1;
+        assert_eq!(json, toml);
+        assert_eq!(yaml, toml);
+
+        assert_eq!(Format::of(Path::new("topgrade.d/10-base")), Format::Toml);
+        assert!(ConfigFile::parse_lenient("misc = {}", Path::new("topgrade.json"), &mut false).is_err());
+    }
+
     /// Ensure that custom commands are stored in insertion order.
     #[test]
     fn test_custom_commands_order() {
@@ -0,0 +1,298 @@
+//! Configuration set by environment variables and `--set`
+//!
+//! `TOPGRADE__<SECTION>__<KEY>` sets the key of a section, e.g. `TOPGRADE__MISC__DISABLE` sets
+//! `disable` in `[misc]`. The value is converted to the type of the key: lists are separated by
+//! commas, and tables are written as TOML inline tables.
+//!
+//! `--set <SECTION>.<KEY>=<VALUE>` sets the key written as in the configuration file, and the
+//! value is TOML, e.g. `--set misc.disable=["emacs"]`. A value that is not valid TOML is taken as
+//! a string, so that `--set linux.yay_arguments=--nodiffmenu` does not need quotes.
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::config_schema::{self, resolve};
+use crate::config_validation::Diagnostic;
+
+/// The prefix of the environment variables setting the configuration.
+pub const PREFIX: &str = "TOPGRADE__";
+
+/// The configuration set by an environment variable or a `--set` argument.
+#[derive(Debug, PartialEq)]
+pub struct Override {
+    /// The environment variable, or the key set by `--set`.
+    pub name: String,
+    /// The path to the key that is set, e.g. `misc` and `disable`.
+    pub keys: Vec<String>,
+    pub table: toml::Table,
+}
+
+impl Override {
+    /// Set the key of this override in `target`, replacing its value there rather than merging
+    /// them, so that e.g. a list that is set is not added to the configured one.
+    pub fn apply(&self, target: &mut toml::Table) {
+        let (key, parents) = self.keys.split_last().expect("an override sets a key");
+        let mut source = &self.table;
+        let mut target = target;
+        for parent in parents {
+            source = source
+                .get(parent)
+                .and_then(toml::Value::as_table)
+                .expect("the table of an override follows its keys");
+            let entry = target
+                .entry(parent.clone())
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            if !entry.is_table() {
+                *entry = toml::Value::Table(toml::Table::new());
+            }
+            target = entry.as_table_mut().unwrap();
+        }
+        if let Some(value) = source.get(key) {
+            target.insert(key.clone(), value.clone());
+        }
+    }
+}
+
+/// The configuration set by the environment variables in `vars`, ordered by name.
+pub fn overrides(vars: impl IntoIterator<Item = (String, String)>) -> Vec<Result<Override, Diagnostic>> {
+    let mut vars: Vec<(String, String)> = vars.into_iter().filter(|(var, _)| var.starts_with(PREFIX)).collect();
+    if vars.is_empty() {
+        return Vec::new();
+    }
+    vars.sort();
+
+    let schema = config_schema::schema();
+    vars.into_iter()
+        .map(|(var, raw)| {
+            parse(&schema, &var, &raw)
+                .map(|(keys, table)| Override {
+                    name: var.clone(),
+                    keys,
+                    table,
+                })
+                .map_err(|message| Diagnostic::new(Path::new(&var), message))
+        })
+        .collect()
+}
+
+/// The configuration set by the `--set KEY=VALUE` arguments, in order.
+pub fn settings(args: &[String]) -> Vec<Result<Override, Diagnostic>> {
+    if args.is_empty() {
+        return Vec::new();
+    }
+
+    let schema = config_schema::schema();
+    args.iter()
+        .map(|arg| {
+            let key = arg.split_once('=').map_or(arg.as_str(), |(key, _)| key).trim();
+            parse_setting(&schema, arg)
+                .map(|(keys, table)| Override {
+                    name: key.to_string(),
+                    keys,
+                    table,
+                })
+                .map_err(|message| Diagnostic::new(Path::new(&format!("--set {key}")), message))
+        })
+        .collect()
+}
+
+/// The path to the key written in `arg`, `KEY=VALUE`, and the table setting it.
+fn parse_setting(schema: &Value, arg: &str) -> Result<(Vec<String>, toml::Table), String> {
+    let (key, raw) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected `KEY=VALUE`, found `{arg}`"))?;
+    let (key, raw) = (key.trim(), raw.trim());
+
+    // The key is parsed by TOML too, so that it can be quoted like in the files
+    let mut value = toml::from_str::<toml::Table>(&format!("{key} = 0"))
+        .map(toml::Value::Table)
+        .map_err(|_| format!("`{key}` is not a valid key"))?;
+    let mut keys = Vec::new();
+    while let toml::Value::Table(table) = value {
+        let Some((key, inner)) = table.into_iter().next() else {
+            break;
+        };
+        keys.push(key);
+        value = inner;
+    }
+    pointer(schema, &keys.iter().map(String::as_str).collect::<Vec<_>>(), "--set")?;
+
+    toml::from_str(&format!("{key} = {raw}"))
+        .or_else(|_| toml::from_str(&format!("{key} = {}", toml::Value::String(raw.to_string()))))
+        .map(|table| (keys, table))
+        .map_err(|e: toml::de::Error| e.message().trim().to_string())
+}
+
+/// The JSON pointer to the schema of the key at `keys`, which can be set by `setter`.
+fn pointer(schema: &Value, keys: &[&str], setter: &str) -> Result<String, String> {
+    if keys.first() == Some(&"include") {
+        return Err(format!("the [include] section cannot be set by {setter}"));
+    }
+    config_schema::value_pointer(schema, keys)
+        .ok_or_else(|| format!("`{}` is not a key of the configuration", keys.join(".")))
+}
+
+/// The path to the key named by `var`, and the table setting it to `raw`.
+fn parse(schema: &Value, var: &str, raw: &str) -> Result<(Vec<String>, toml::Table), String> {
+    let keys: Vec<String> = var[PREFIX.len()..].split("__").map(str::to_lowercase).collect();
+    let pointer = pointer(
+        schema,
+        &keys.iter().map(String::as_str).collect::<Vec<_>>(),
+        "an environment variable",
+    )?;
+
+    let mut value = convert(schema, &pointer, raw)?;
+    for key in keys.iter().rev() {
+        let mut table = toml::Table::new();
+        table.insert(key.clone(), value);
+        value = toml::Value::Table(table);
+    }
+    match value {
+        toml::Value::Table(table) => Ok((keys, table)),
+        _ => unreachable!("the keys are not empty"),
+    }
+}
+
+/// Convert `raw` to a value of the type of the schema at `pointer`.
+fn convert(schema: &Value, pointer: &str, raw: &str) -> Result<toml::Value, String> {
+    let node = schema.pointer(pointer).unwrap_or(&Value::Null);
+    let types: Vec<&str> = match node.get("type") {
+        Some(Value::String(kind)) => vec![kind.as_str()],
+        Some(Value::Array(kinds)) => kinds.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    };
+
+    if types.contains(&"array") {
+        let items = resolve(schema, format!("{pointer}/items")).unwrap_or_default();
+        let values = raw
+            .split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(|value| convert(schema, &items, value))
+            .collect::<Result<_, _>>()?;
+        Ok(toml::Value::Array(values))
+    } else if types.contains(&"boolean") {
+        raw.trim()
+            .parse()
+            .map(toml::Value::Boolean)
+            .map_err(|_| format!("expected `true` or `false`, found `{raw}`"))
+    } else if types.contains(&"integer") {
+        raw.trim()
+            .parse()
+            .map(toml::Value::Integer)
+            .map_err(|_| format!("expected an integer, found `{raw}`"))
+    } else if types.contains(&"number") {
+        raw.trim()
+            .parse()
+            .map(toml::Value::Float)
+            .map_err(|_| format!("expected a number, found `{raw}`"))
+    } else if types.contains(&"object") {
+        toml::from_str::<toml::Table>(&format!("value = {raw}"))
+            .ok()
+            .and_then(|mut table| table.remove("value"))
+            .filter(toml::Value::is_table)
+            .ok_or_else(|| format!("expected an inline table such as `{{ key = \"value\" }}`, found `{raw}`"))
+    } else {
+        Ok(toml::Value::String(raw.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(var, value)| (var.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_overrides() {
+        let overrides = overrides(vars(&[
+            ("TOPGRADE__MISC__DISABLE", "containers, firmware"),
+            ("TOPGRADE__LINUX__YAY_ARGUMENTS", "--nodiffmenu"),
+            ("TOPGRADE__GIT__MAX_CONCURRENCY", "4"),
+            ("TOPGRADE__MISC__ASSUME_YES", "true"),
+            ("TOPGRADE__COMMANDS", r#"{ "Say hi" = "echo hi" }"#),
+            ("TOPGRADE__NOTIFICATIONS__NTFY__URL", "https://ntfy.sh/topic"),
+            ("TOPGRADE_SKIP_BRKC_NOTIFY", "true"),
+        ]));
+
+        let tables: Vec<String> = overrides
+            .into_iter()
+            .map(|result| toml::to_string(&result.unwrap().table).unwrap())
+            .collect();
+        assert_eq!(
+            tables,
+            [
+                "[commands]\n\"Say hi\" = \"echo hi\"\n",
+                "[git]\nmax_concurrency = 4\n",
+                "[linux]\nyay_arguments = \"--nodiffmenu\"\n",
+                "[misc]\nassume_yes = true\n",
+                "[misc]\ndisable = [\"containers\", \"firmware\"]\n",
+                "[notifications.ntfy]\nurl = \"https://ntfy.sh/topic\"\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_settings() {
+        let args: Vec<String> = [
+            "git.max_concurrency=4",
+            "misc.disable = [\"emacs\"]",
+            "linux.yay_arguments=--nodiffmenu",
+            "commands.\"Say hi\"=echo hi",
+            "misc.assume_yes=yes",
+            "misc.disabel=[]",
+            "include.paths=[]",
+            "misc.cleanup",
+        ]
+        .map(String::from)
+        .to_vec();
+
+        let settings: Vec<String> = settings(&args)
+            .into_iter()
+            .map(|result| match result {
+                Ok(r#override) => toml::to_string(&r#override.table).unwrap(),
+                Err(diagnostic) => diagnostic.to_string(),
+            })
+            .collect();
+        assert_eq!(
+            settings,
+            [
+                "[git]\nmax_concurrency = 4\n",
+                "[misc]\ndisable = [\"emacs\"]\n",
+                "[linux]\nyay_arguments = \"--nodiffmenu\"\n",
+                "[commands]\n\"Say hi\" = \"echo hi\"\n",
+                "[misc]\nassume_yes = \"yes\"\n",
+                "--set misc.disabel: `misc.disabel` is not a key of the configuration",
+                "--set include.paths: the [include] section cannot be set by --set",
+                "--set misc.cleanup: expected `KEY=VALUE`, found `misc.cleanup`",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_overrides_errors() {
+        let messages: Vec<String> = overrides(vars(&[
+            ("TOPGRADE__MISC__ASSUME_YES", "yes"),
+            ("TOPGRADE__MISC__DISABEL", "emacs"),
+            ("TOPGRADE__MISC", "true"),
+            ("TOPGRADE__INCLUDE__PATHS", "~/shared.toml"),
+        ]))
+        .into_iter()
+        .map(|result| result.unwrap_err().to_string())
+        .collect();
+
+        assert_eq!(
+            messages,
+            [
+                "TOPGRADE__INCLUDE__PATHS: the [include] section cannot be set by an environment variable",
+                "TOPGRADE__MISC: expected an inline table such as `{ key = \"value\" }`, found `true`",
+                "TOPGRADE__MISC__ASSUME_YES: expected `true` or `false`, found `yes`",
+                "TOPGRADE__MISC__DISABEL: `misc.disabel` is not a key of the configuration",
+            ]
+        );
+    }
+}
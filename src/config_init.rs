@@ -0,0 +1,324 @@
+//! `topgrade config init`, which writes a configuration tailored to this machine
+//!
+//! The tools are detected with the same probes as the steps, and only the sections of the
+//! example configuration that configure them are kept, with their comments. A few questions set
+//! the most common options of `[misc]`.
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use color_eyre::eyre::{eyre, Context, Result};
+use regex::Regex;
+use rust_i18n::t;
+
+use crate::config::EXAMPLE_CONFIG;
+use crate::config_migration::backup_path;
+use crate::step::Step;
+use crate::sudo::{Sudo, SudoKind};
+use crate::terminal::{print_info, prompt_line, prompt_yesno};
+use crate::utils::{hostname, require_one};
+
+/// The sections of the example configuration for tools, with the steps they configure.
+///
+/// A section without steps is for an operating system, see `applies`.
+const SECTIONS: &[(&str, &[Step])] = {
+    use Step::*;
+    &[
+        ("python", &[Pip3, PipReview, PipReviewLocal, Pipupgrade]),
+        ("conda", &[Conda, Mamba]),
+        ("composer", &[Composer]),
+        ("brew", &[BrewFormula, BrewCask]),
+        ("linux", &[]),
+        ("mandb", &[Mandb]),
+        ("pkgfile", &[Pkgfile]),
+        ("git", &[GitRepos]),
+        ("windows", &[]),
+        ("chezmoi", &[Chezmoi]),
+        ("mise", &[Mise]),
+        ("npm", &[Node, Pnpm]),
+        ("yarn", &[Yarn]),
+        ("deno", &[Deno]),
+        ("vim", &[Vim]),
+        ("firmware", &[Firmware]),
+        ("vagrant", &[Vagrant]),
+        ("flatpak", &[Flatpak]),
+        ("distrobox", &[Distrobox]),
+        ("containers", &[Containers]),
+        ("lensfun", &[Lensfun]),
+        ("julia", &[Julia]),
+        ("zigup", &[Zigup]),
+        ("vscode", &[Vscode, VscodeInsiders, Vscodium, VscodiumInsiders]),
+        ("pixi", &[Pixi]),
+        ("doom", &[Emacs]),
+        ("rustup", &[Rustup]),
+    ]
+};
+
+/// What was found on this machine.
+#[derive(Debug, Default)]
+struct Detected {
+    /// The sections of the example configuration that apply.
+    sections: Vec<&'static str>,
+    distribution: Option<String>,
+    sudo_kinds: Vec<SudoKind>,
+}
+
+impl Detected {
+    fn detect() -> Self {
+        #[cfg(target_os = "linux")]
+        let distribution = crate::steps::os::linux::Distribution::detect()
+            .ok()
+            .map(|distribution| distribution.to_string());
+        #[cfg(not(target_os = "linux"))]
+        let distribution = None;
+
+        Self {
+            sections: SECTIONS
+                .iter()
+                .filter(|(section, steps)| applies(section, steps))
+                .map(|(section, _)| *section)
+                .collect(),
+            distribution,
+            sudo_kinds: Sudo::available(),
+        }
+    }
+}
+
+/// Whether the section for the `steps` applies to this machine, i.e. whether one of them finds
+/// its binaries.
+fn applies(section: &str, steps: &[Step]) -> bool {
+    match section {
+        "linux" => cfg!(target_os = "linux"),
+        "windows" => cfg!(windows),
+        _ => steps.iter().any(|step| require_one(step.binaries()).is_ok()),
+    }
+}
+
+/// The answers to the questions, which set the options of `[misc]`.
+#[derive(Debug, Default)]
+struct Answers {
+    cleanup: bool,
+    assume_yes: bool,
+    run_in_tmux: bool,
+    remote_topgrades: Vec<String>,
+    sudo_command: Option<SudoKind>,
+}
+
+impl Answers {
+    fn ask(detected: &Detected) -> Result<Self> {
+        let mut answers = Self {
+            cleanup: ask_yesno(&t!("Clean up old versions and caches after upgrading?"))?,
+            assume_yes: ask_yesno(&t!("Answer yes to the package managers?"))?,
+            run_in_tmux: !cfg!(windows) && ask_yesno(&t!("Run Topgrade in a tmux session?"))?,
+            ..Self::default()
+        };
+
+        let remotes = prompt_line(&t!(
+            "Remote machines to upgrade with Topgrade over SSH, separated by commas (none if empty):"
+        ))?;
+        answers.remote_topgrades = remotes
+            .split(',')
+            .map(str::trim)
+            .filter(|remote| !remote.is_empty())
+            .map(String::from)
+            .collect();
+
+        // With a single kind there is nothing to choose, it is detected on every run
+        if detected.sudo_kinds.len() > 1 {
+            let kinds: Vec<String> = detected.sudo_kinds.iter().map(ToString::to_string).collect();
+            loop {
+                let kind = prompt_line(&t!(
+                    "Which sudo command should be used, {kinds}? (the first if empty)",
+                    kinds = kinds.join(", ")
+                ))?;
+                let kind = kind.trim();
+                if kind.is_empty() {
+                    break;
+                }
+                if let Some(i) = kinds.iter().position(|k| k == kind) {
+                    answers.sudo_command = Some(detected.sudo_kinds[i]);
+                    break;
+                }
+            }
+        }
+
+        Ok(answers)
+    }
+}
+
+/// `prompt_yesno`, ending the line of the question.
+fn ask_yesno(question: &str) -> Result<bool> {
+    let answer = prompt_yesno(question)?;
+    println!();
+    Ok(answer)
+}
+
+/// The sections of `example`, with the comments before them, by name.
+///
+/// A section ends where the comments of the next one begin.
+fn example_sections(example: &str) -> Vec<(String, String)> {
+    let header = Regex::new(r"^\[([^\[\]]+)\]\s*$").unwrap();
+    let lines: Vec<&str> = example.lines().collect();
+
+    let mut starts: Vec<(usize, String)> = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        if let Some(captures) = header.captures(line) {
+            let mut start = i;
+            while start > 0 && lines[start - 1].starts_with('#') {
+                start -= 1;
+            }
+            starts.push((start, captures[1].to_string()));
+        }
+    }
+
+    starts
+        .iter()
+        .enumerate()
+        .map(|(i, (start, name))| {
+            let end = starts.get(i + 1).map_or(lines.len(), |(end, _)| *end);
+            (name.clone(), lines[*start..end].join("\n").trim_end().to_string())
+        })
+        .collect()
+}
+
+/// The configuration for the machine.
+fn render(detected: &Detected, answers: &Answers) -> String {
+    let mut config = String::new();
+    let _ = writeln!(config, "# Generated by `topgrade config init`");
+    if let Ok(hostname) = hostname() {
+        let _ = writeln!(config, "# Machine: {hostname}");
+    }
+    if let Some(distribution) = &detected.distribution {
+        let _ = writeln!(config, "# Distribution: {distribution}");
+    }
+    if !detected.sudo_kinds.is_empty() {
+        let kinds: Vec<String> = detected.sudo_kinds.iter().map(ToString::to_string).collect();
+        let _ = writeln!(config, "# Sudo: {}", kinds.join(", "));
+    }
+    let _ = writeln!(config, "# Run `topgrade --show-config-reference` for all the options.");
+
+    let _ = writeln!(config, "\n[misc]");
+    for (key, set) in [
+        ("cleanup", answers.cleanup),
+        ("assume_yes", answers.assume_yes),
+        ("run_in_tmux", answers.run_in_tmux),
+    ] {
+        if set {
+            let _ = writeln!(config, "{key} = true");
+        }
+    }
+    if !answers.remote_topgrades.is_empty() {
+        let remotes = toml::Value::try_from(&answers.remote_topgrades).expect("a list of strings is a TOML value");
+        let _ = writeln!(config, "remote_topgrades = {remotes}");
+    }
+    if let Some(kind) = answers.sudo_command {
+        let _ = writeln!(config, "sudo_command = \"{kind}\"");
+    }
+
+    for (name, section) in example_sections(EXAMPLE_CONFIG) {
+        if detected.sections.contains(&name.as_str()) {
+            let _ = writeln!(config, "\n{section}");
+        }
+    }
+
+    config
+}
+
+/// Ask the questions and write the configuration to `path`, or print it if `dry_run` is set.
+///
+/// An existing configuration is only replaced if `force` is set, and is backed up first.
+pub fn run(path: &Path, dry_run: bool, force: bool) -> Result<()> {
+    if path.exists() && !force && !dry_run {
+        return Err(eyre!(t!(
+            "{path} already exists, run with --force to replace it",
+            path = path.display()
+        )));
+    }
+
+    let detected = Detected::detect();
+    let answers = Answers::ask(&detected)?;
+    let config = render(&detected, &answers);
+
+    if dry_run {
+        print!("{config}");
+        return Ok(());
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    if path.exists() {
+        let backup = backup_path(path);
+        if let Some(parent) = backup.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        fs::copy(path, &backup).with_context(|| format!("Failed to back up {}", path.display()))?;
+        print_info(t!(
+            "The previous configuration is saved at {backup}",
+            backup = backup.display()
+        ));
+    }
+    fs::write(path, config).with_context(|| format!("Failed to write {}", path.display()))?;
+    print_info(t!("Wrote the configuration to {path}", path = path.display()));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ConfigFile, ContainerRuntime};
+
+    #[test]
+    fn test_example_sections() {
+        let sections = example_sections(EXAMPLE_CONFIG);
+        let names: Vec<&str> = sections.iter().map(|(name, _)| name.as_str()).collect();
+        for (section, steps) in SECTIONS {
+            assert!(
+                names.contains(section),
+                "[{section}] is not in the example configuration"
+            );
+            for step in *steps {
+                assert!(!step.binaries().is_empty(), "{step:?} has no binaries for [{section}]");
+            }
+        }
+
+        let (_, git) = sections.iter().find(|(name, _)| name == "git").unwrap();
+        assert!(git.lines().any(|line| line == "[git]"));
+        assert!(!git.contains("[windows]"));
+    }
+
+    /// The containers step requires the configured runtime, which must be detected.
+    #[test]
+    fn test_container_runtimes() {
+        for runtime in [ContainerRuntime::Docker, ContainerRuntime::Podman] {
+            assert!(Step::Containers.binaries().contains(&runtime.to_string().as_str()));
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let detected = Detected {
+            sections: vec!["git", "rustup"],
+            distribution: Some("arch".to_string()),
+            sudo_kinds: vec![SudoKind::Doas],
+        };
+        let answers = Answers {
+            cleanup: true,
+            remote_topgrades: vec!["server".to_string(), "nas".to_string()],
+            sudo_command: Some(SudoKind::Doas),
+            ..Answers::default()
+        };
+
+        let config = render(&detected, &answers);
+        assert!(config.contains("# Distribution: arch\n"));
+        assert!(config
+            .contains("\n[misc]\ncleanup = true\nremote_topgrades = [\"server\", \"nas\"]\nsudo_command = \"doas\"\n"));
+        assert!(config.contains("\n[git]\n"));
+        assert!(config.contains("\n[rustup]\n"));
+        assert!(!config.contains("[brew]"));
+
+        let config_file: ConfigFile = toml::from_str(&config).unwrap();
+        assert!(toml::Table::try_from(&config_file).unwrap().contains_key("misc"));
+    }
+}
@@ -0,0 +1,613 @@
+//! Migrations of the configuration files across breaking changes
+//!
+//! Each migration is a declarative rewrite of a key or a step that changed in a major release.
+//! They are applied with `toml_edit`, which keeps the comments and the formatting of the files,
+//! and are idempotent: a file that is already migrated is left untouched.
+//!
+//! A migrated file starts with a comment recording the latest release it was migrated to, and the
+//! migrations of older releases are not applied to it again, e.g. so that a step renamed in a
+//! release can get its old name back in a later one.
+use std::fmt::{self, Display, Write as _};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use color_eyre::eyre::{Context, Result};
+use console::style;
+use rust_i18n::t;
+use toml_edit::{DocumentMut, Key, TableLike};
+
+use crate::breaking_changes::data_dir;
+use crate::config::{include_paths, split_at_includes, Format};
+use crate::terminal::{print_info, print_separator, print_warning, prompt_yesno};
+
+/// A change of the configuration that has to be made to the files.
+#[derive(Debug)]
+pub enum Rewrite {
+    /// A step was renamed.
+    RenameStep { from: &'static str, to: &'static str },
+    /// A key was moved, given by its dotted path, e.g. `misc.yay_arguments`.
+    #[allow(dead_code)] // No current migration moves a key
+    MoveKey { from: &'static str, to: &'static str },
+    /// A step was removed.
+    #[allow(dead_code)] // No current migration removes a step
+    RemoveStep(&'static str),
+}
+
+#[derive(Debug)]
+pub struct Migration {
+    /// The major release of Topgrade that made the change.
+    pub version: u64,
+    pub description: &'static str,
+    pub rewrite: Rewrite,
+}
+
+impl Display for Migration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Topgrade {}: {}", self.version, self.description)
+    }
+}
+
+/// The migrations, in the order they are applied.
+pub static MIGRATIONS: &[Migration] = &[Migration {
+    version: 17,
+    description: "the `jet_brains_toolbox` step was renamed to `jetbrains_toolbox`",
+    rewrite: Rewrite::RenameStep {
+        from: "jet_brains_toolbox",
+        to: "jetbrains_toolbox",
+    },
+}];
+
+/// The start of the comment recording the release a file was migrated to.
+const MARKER: &str = "# Migrated to Topgrade ";
+
+/// The release the file with `contents` was last migrated to, if it was.
+fn migrated_version(contents: &str) -> Option<u64> {
+    contents
+        .lines()
+        .take_while(|line| line.is_empty() || line.starts_with('#'))
+        .find_map(|line| line.strip_prefix(MARKER)?.split_whitespace().next()?.parse().ok())
+}
+
+/// `contents` with its marker set to `version`.
+fn mark(contents: &str, version: u64) -> String {
+    let mut marked = format!("{MARKER}{version} by `topgrade config migrate`\n");
+    let mut header = true;
+    for line in contents.split_inclusive('\n') {
+        header &= line.trim_end().is_empty() || line.starts_with('#');
+        if !(header && line.starts_with(MARKER)) {
+            marked.push_str(line);
+        }
+    }
+    marked
+}
+
+/// The keys holding lists of steps.
+const STEP_LISTS: &[&str] = &["misc.disable", "misc.only", "misc.ignore_failures"];
+
+/// The table at the dotted `path` of `table`, created if missing and `create` is set.
+fn table_mut<'a>(mut table: &'a mut dyn TableLike, path: &[&str], create: bool) -> Option<&'a mut dyn TableLike> {
+    for key in path {
+        table = if create {
+            table.entry(key).or_insert(toml_edit::table()).as_table_like_mut()?
+        } else {
+            table.get_mut(key)?.as_table_like_mut()?
+        };
+    }
+
+    Some(table)
+}
+
+/// Move the key at `from` to `to`, returning whether it was there.
+fn move_key(document: &mut DocumentMut, from: &[&str], to: &[&str]) -> bool {
+    let (Some((from_key, from_table)), Some((to_key, to_table))) = (from.split_last(), to.split_last()) else {
+        return false;
+    };
+
+    let Some(table) = table_mut(document.as_table_mut(), from_table, false) else {
+        return false;
+    };
+    let Some(decor) = table.key(from_key).map(|key| key.leaf_decor().clone()) else {
+        return false;
+    };
+    let Some(item) = table.remove(from_key) else {
+        return false;
+    };
+
+    // A value already set at the new place wins over the old one
+    if let Some(table) = table_mut(document.as_table_mut(), to_table, true) {
+        table
+            .entry_format(&Key::new(*to_key).with_leaf_decor(decor))
+            .or_insert(item);
+    }
+    true
+}
+
+/// Remove `step` from `steps`, with the comment following it on its line.
+fn remove_step(steps: &mut toml_edit::Array, step: &str) -> bool {
+    let mut changed = false;
+    let mut i = 0;
+    while i < steps.len() {
+        if steps.get(i).and_then(|value| value.as_str()) != Some(step) {
+            i += 1;
+            continue;
+        }
+        changed = true;
+
+        // The comment is in the prefix of the next step, or after the last one
+        let prefix = steps.remove(i).decor().prefix().cloned();
+        match steps.get_mut(i) {
+            Some(next) => {
+                if let Some(prefix) = prefix {
+                    next.decor_mut().set_prefix(prefix);
+                }
+            }
+            None => {
+                let trailing = steps.trailing().as_str().unwrap_or_default();
+                let trailing = match trailing.trim_start_matches([' ', '\t']).strip_prefix('#') {
+                    Some(comment) => comment.find('\n').map_or("", |end| &comment[end..]),
+                    None => trailing,
+                };
+                steps.set_trailing(trailing.to_string());
+            }
+        }
+    }
+
+    changed
+}
+
+impl Rewrite {
+    /// Apply the rewrite to `document`, returning whether it changed anything.
+    fn apply(&self, document: &mut DocumentMut) -> bool {
+        match *self {
+            // The options of the step in `[step.<name>]` follow it
+            Rewrite::RenameStep { from, to } => {
+                self.edit_step_lists(document, |steps| {
+                    let mut changed = false;
+                    for step in steps.iter_mut() {
+                        if step.as_str() == Some(from) {
+                            let decor = step.decor().clone();
+                            *step = to.into();
+                            *step.decor_mut() = decor;
+                            changed = true;
+                        }
+                    }
+                    changed
+                }) | move_key(document, &["step", from], &["step", to])
+            }
+            Rewrite::MoveKey { from, to } => {
+                let from: Vec<&str> = from.split('.').collect();
+                let to: Vec<&str> = to.split('.').collect();
+                move_key(document, &from, &to)
+            }
+            // The options of the step in `[step.<name>]` go with it
+            Rewrite::RemoveStep(removed) => {
+                self.edit_step_lists(document, |steps| remove_step(steps, removed))
+                    | table_mut(document.as_table_mut(), &["step"], false)
+                        .and_then(|steps| steps.remove(removed))
+                        .is_some()
+            }
+        }
+    }
+
+    fn edit_step_lists(&self, document: &mut DocumentMut, mut edit: impl FnMut(&mut toml_edit::Array) -> bool) -> bool {
+        let mut changed = false;
+        for list in STEP_LISTS {
+            let path: Vec<&str> = list.split('.').collect();
+            let Some((key, table)) = path.split_last() else {
+                continue;
+            };
+            if let Some(steps) = table_mut(document.as_table_mut(), table, false)
+                .and_then(|table| table.get_mut(key))
+                .and_then(|item| item.as_array_mut())
+            {
+                changed |= edit(steps);
+            }
+        }
+
+        changed
+    }
+}
+
+/// Apply the migrations to `contents`, the contents of a configuration file.
+///
+/// Returns the migrated contents, and the migrations that changed them. The migrations older than
+/// the release the file was last migrated to are skipped.
+pub fn migrate(contents: &str) -> Result<(String, Vec<&'static Migration>), toml_edit::TomlError> {
+    let mut migrated = String::new();
+    let mut applied: Vec<&'static Migration> = Vec::new();
+    let since = migrated_version(contents).unwrap_or(0);
+
+    // Like when reading the file, each part starting with `[include]` is a TOML document of its own
+    for part in split_at_includes(contents) {
+        let mut document: DocumentMut = part.parse()?;
+        let mut changed = false;
+        for migration in MIGRATIONS.iter().filter(|migration| migration.version >= since) {
+            if migration.rewrite.apply(&mut document) {
+                changed = true;
+                if !applied.iter().any(|m| std::ptr::eq(*m, migration)) {
+                    applied.push(migration);
+                }
+            }
+        }
+
+        if changed {
+            migrated.push_str(&document.to_string());
+        } else {
+            migrated.push_str(part);
+        }
+    }
+
+    if let Some(latest) = MIGRATIONS.iter().map(|migration| migration.version).max() {
+        if !applied.is_empty() {
+            migrated = mark(&migrated, latest.max(since));
+        }
+    }
+
+    Ok((migrated, applied))
+}
+
+/// The number of unchanged lines shown around the changes of a diff.
+const CONTEXT: usize = 2;
+
+/// A unified diff of the lines of `old` and `new`.
+fn diff(old: &str, new: &str) -> String {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+
+    // `lcs[i][j]` is the length of the longest common subsequence of `old[i..]` and `new[j..]`
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    // The lines, with their kind and their 0-based line numbers in `old` and `new`
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push((' ', old[i], i, j));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(('-', old[i], i, j));
+            i += 1;
+        } else {
+            lines.push(('+', new[j], i, j));
+            j += 1;
+        }
+    }
+
+    let mut out = String::new();
+    let mut start = 0;
+    while let Some(first) = lines[start..].iter().position(|line| line.0 != ' ') {
+        let hunk_start = (start + first).saturating_sub(CONTEXT);
+        // Extend the hunk while the next change is close enough for the contexts to overlap
+        let mut hunk_end = start + first;
+        while let Some(next) = lines[hunk_end + 1..]
+            .iter()
+            .position(|line| line.0 != ' ')
+            .filter(|next| *next < 2 * CONTEXT + 1)
+        {
+            hunk_end += next + 1;
+        }
+        let hunk_end = (hunk_end + CONTEXT + 1).min(lines.len());
+
+        let hunk = &lines[hunk_start..hunk_end];
+        let old_count = hunk.iter().filter(|line| line.0 != '+').count();
+        let new_count = hunk.iter().filter(|line| line.0 != '-').count();
+        let _ = writeln!(
+            out,
+            "@@ -{},{old_count} +{},{new_count} @@",
+            hunk[0].2 + usize::from(old_count > 0),
+            hunk[0].3 + usize::from(new_count > 0)
+        );
+        for (kind, text, _, _) in hunk {
+            let _ = writeln!(out, "{kind}{text}");
+        }
+
+        start = hunk_end;
+    }
+
+    out
+}
+
+/// Where the original of `path` is saved before it is migrated.
+///
+/// The backups are not kept next to the files, as every file in `topgrade.d` is read as a
+/// configuration file.
+pub(crate) fn backup_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let directory = data_dir().join("topgrade").join("config-backups");
+
+    // A counter keeps the backups made in the same second apart
+    let mut backup = directory.join(format!("{name}.{timestamp}"));
+    let mut counter = 1;
+    while backup.exists() {
+        backup = directory.join(format!("{name}.{timestamp}.{counter}"));
+        counter += 1;
+    }
+    backup
+}
+
+/// Migrate the configuration files at `paths`, showing the changes and asking before applying
+/// them unless `yes` is set. With `dry_run`, the changes are only shown.
+///
+/// Returns whether any file needed to be migrated.
+pub fn run(paths: &[PathBuf], dry_run: bool, yes: bool) -> Result<bool> {
+    let mut needed = false;
+    let mut included: Vec<(PathBuf, &Path)> = Vec::new();
+    // Only the TOML files keep their comments and formatting when rewritten
+    for path in paths.iter().filter(|path| Format::of(path) == Format::Toml) {
+        let Ok(contents) = fs::read_to_string(path) else {
+            continue;
+        };
+        included.extend(
+            include_paths(&contents)
+                .into_iter()
+                .map(|include| (include, path.as_path())),
+        );
+
+        let (migrated, applied) = match migrate(&contents) {
+            Ok(migration) => migration,
+            Err(e) => {
+                print_warning(t!("Failed to parse {path}: {error}", path = path.display(), error = e));
+                continue;
+            }
+        };
+        if applied.is_empty() {
+            continue;
+        }
+        needed = true;
+
+        print_separator(t!("Migrating {path}", path = path.display()));
+        for migration in &applied {
+            println!("{migration}");
+        }
+        println!();
+        for line in diff(&contents, &migrated).lines() {
+            match line.chars().next() {
+                Some('-') => println!("{}", style(line).red()),
+                Some('+') => println!("{}", style(line).green()),
+                Some('@') => println!("{}", style(line).cyan()),
+                _ => println!("{line}"),
+            }
+        }
+        println!();
+
+        if dry_run || !(yes || prompt_yesno(&t!("Apply these changes?"))?) {
+            continue;
+        }
+
+        let backup = backup_path(path);
+        if let Some(parent) = backup.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        fs::copy(path, &backup).with_context(|| format!("Failed to back up {}", path.display()))?;
+        fs::write(path, migrated).with_context(|| format!("Failed to write {}", path.display()))?;
+        print_info(t!(
+            "Migrated {path}, the original is saved at {backup}",
+            path = path.display(),
+            backup = backup.display()
+        ));
+    }
+
+    // The included files may be shared with other machines, so they are only checked
+    for (path, from) in included {
+        if paths.contains(&path) {
+            continue;
+        }
+        let Some(applied) = fs::read_to_string(&path)
+            .ok()
+            .and_then(|contents| migrate(&contents).ok())
+            .map(|(_, applied)| applied)
+            .filter(|applied| !applied.is_empty())
+        else {
+            continue;
+        };
+        needed = true;
+
+        print_warning(t!(
+            "{path}, included by {from}, is not migrated. Run `topgrade config migrate --config {path}` to migrate it.",
+            path = path.display(),
+            from = from.display()
+        ));
+        for migration in &applied {
+            println!("{migration}");
+        }
+    }
+
+    Ok(needed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate() {
+        let contents = r#"[include]
+# paths = ["~/shared.toml"]
+
+[misc]
+# Steps I don't need
+disable = [
+    "emacs",
+    "jet_brains_toolbox", # no JetBrains IDE here
+]
+only = ["jet_brains_toolbox"]
+
+[step.jet_brains_toolbox]
+env = { JAVA_HOME = "/opt/java" }
+"#;
+        let (migrated, applied) = migrate(contents).unwrap();
+        let expected = contents
+            .replace("\"jet_brains_toolbox\"", "\"jetbrains_toolbox\"")
+            .replace("[step.jet_brains_toolbox]", "[step.jetbrains_toolbox]");
+        assert_eq!(
+            migrated,
+            format!("# Migrated to Topgrade 17 by `topgrade config migrate`\n{expected}")
+        );
+        assert_eq!(migrated_version(&migrated), Some(17));
+        assert_eq!(applied.len(), 1);
+
+        let (again, applied) = migrate(&migrated).unwrap();
+        assert_eq!(again, migrated);
+        assert!(applied.is_empty());
+    }
+
+    /// Test that the migrations older than the release a file was migrated to are skipped, and
+    /// that the marker is replaced when a file is migrated again.
+    #[test]
+    fn test_migrate_since() {
+        let contents =
+            "# Migrated to Topgrade 18 by `topgrade config migrate`\n[misc]\nonly = [\"jet_brains_toolbox\"]\n";
+        let (migrated, applied) = migrate(contents).unwrap();
+        assert_eq!(migrated, contents);
+        assert!(applied.is_empty());
+
+        let contents = "# My configuration\n# Migrated to Topgrade 16\n\n[misc]\nonly = [\"jet_brains_toolbox\"]\n";
+        let (migrated, applied) = migrate(contents).unwrap();
+        assert_eq!(
+            migrated,
+            "# Migrated to Topgrade 17 by `topgrade config migrate`\n# My configuration\n\n[misc]\nonly = [\"jetbrains_toolbox\"]\n"
+        );
+        assert_eq!(applied.len(), 1);
+    }
+
+    #[test]
+    fn test_move_key() {
+        let mut document: DocumentMut = r#"[misc]
+disable = ["emacs"]
+# Used for the AUR
+yay_arguments = "--nodevel"
+
+[linux]
+dnf_arguments = "--refresh"
+"#
+        .parse()
+        .unwrap();
+
+        assert!(move_key(
+            &mut document,
+            &["misc", "yay_arguments"],
+            &["linux", "yay_arguments"]
+        ));
+        assert!(!move_key(
+            &mut document,
+            &["misc", "yay_arguments"],
+            &["linux", "yay_arguments"]
+        ));
+        assert_eq!(
+            document.to_string(),
+            r#"[misc]
+disable = ["emacs"]
+
+[linux]
+dnf_arguments = "--refresh"
+# Used for the AUR
+yay_arguments = "--nodevel"
+"#
+        );
+    }
+
+    #[test]
+    fn test_rewrite_move_key() {
+        let mut document: DocumentMut = r#"[misc]
+# Self-update is handled by the package manager
+no_self_update = true
+disable = ["emacs"]
+
+[git]
+repos = ["~/src/*"]
+"#
+        .parse()
+        .unwrap();
+        let rewrite = Rewrite::MoveKey {
+            from: "misc.no_self_update",
+            to: "self_update.disabled",
+        };
+
+        assert!(rewrite.apply(&mut document));
+        assert!(!rewrite.apply(&mut document));
+        assert_eq!(
+            document.to_string(),
+            r#"[misc]
+disable = ["emacs"]
+
+[git]
+repos = ["~/src/*"]
+
+[self_update]
+# Self-update is handled by the package manager
+disabled = true
+"#
+        );
+    }
+
+    #[test]
+    fn test_rewrite_remove_step() {
+        let mut document: DocumentMut = r#"[misc]
+# Steps I don't need
+disable = [
+    "emacs",
+    "myrepos", # removed upstream
+    "vim",
+]
+only = ["myrepos"]
+ignore_failures = ["emacs", "myrepos"]
+
+# Don't ask before running mr
+[step.myrepos]
+yes = true
+
+[step.vim]
+yes = true
+"#
+        .parse()
+        .unwrap();
+        let rewrite = Rewrite::RemoveStep("myrepos");
+
+        assert!(rewrite.apply(&mut document));
+        assert!(!rewrite.apply(&mut document));
+        assert_eq!(
+            document.to_string(),
+            r#"[misc]
+# Steps I don't need
+disable = [
+    "emacs",
+    "vim",
+]
+only = []
+ignore_failures = ["emacs"]
+
+[step.vim]
+yes = true
+"#
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n";
+        let new = "a\nb\nc\nD\ne\nf\ng\nh\ni\nj\nk\n";
+        assert_eq!(
+            diff(old, new),
+            "@@ -2,5 +2,5 @@\n b\n c\n-d\n+D\n e\n f\n@@ -9,2 +9,3 @@\n i\n j\n+k\n"
+        );
+        assert_eq!(diff(old, old), "");
+    }
+}
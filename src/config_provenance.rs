@@ -0,0 +1,180 @@
+//! Where the values of the effective configuration come from, for `topgrade config show`
+use std::fmt::{self, Display, Write};
+use std::path::PathBuf;
+
+/// Where a part of the configuration was read from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Origin {
+    /// A configuration file, either the main one or one in `topgrade.d`.
+    File(PathBuf),
+    /// A file listed in the `[include]` section of `from`.
+    Include { path: PathBuf, from: PathBuf },
+    /// A `[when]` section of another origin, which applies to this machine.
+    Overlay { section: String, origin: Box<Origin> },
+    /// An environment variable.
+    Environment(String),
+    /// A `--set` argument, by the key it sets.
+    Set(String),
+    /// A command line flag.
+    Flag(&'static str),
+}
+
+impl Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::File(path) => write!(f, "{}", path.display()),
+            Origin::Include { path, from } => write!(f, "{} (included from {})", path.display(), from.display()),
+            Origin::Overlay { section, origin } => write!(f, "{origin} [{section}]"),
+            Origin::Environment(var) => write!(f, "{var}"),
+            Origin::Set(key) => write!(f, "--set {key}"),
+            Origin::Flag(flag) => write!(f, "{flag}"),
+        }
+    }
+}
+
+/// A part of the configuration, as it was written.
+#[derive(Clone, Debug)]
+pub struct Source {
+    pub origin: Origin,
+    pub table: toml::Table,
+}
+
+/// Render `effective` as TOML, with a comment after each value telling where it comes from.
+///
+/// `sources` are the parts the effective configuration was merged from, in the order they were
+/// merged. A value comes from the first source with the same value, or from all the sources
+/// defining it if they were combined, e.g. lists that are concatenated.
+pub fn render(effective: &toml::Table, sources: &[Source]) -> String {
+    let mut out = String::new();
+    render_table(&mut out, &mut Vec::new(), effective, sources);
+    out
+}
+
+fn render_table<'a>(out: &mut String, path: &mut Vec<&'a str>, table: &'a toml::Table, sources: &[Source]) {
+    let (tables, values): (Vec<_>, Vec<_>) = table.iter().partition(|(_, value)| value.is_table());
+
+    // Tables holding only other tables don't need a header
+    if !path.is_empty() && (!values.is_empty() || tables.is_empty()) {
+        let header = path.iter().map(|k| key(k)).collect::<Vec<_>>().join(".");
+        let _ = writeln!(out, "{}[{header}]", if out.is_empty() { "" } else { "\n" });
+    }
+
+    for (k, value) in values {
+        path.push(k);
+        let _ = write!(out, "{} = {value}", key(k));
+        if let Some(origin) = origin(path, value, sources) {
+            let _ = write!(out, "  # {origin}");
+        }
+        out.push('\n');
+        path.pop();
+    }
+
+    for (k, value) in tables {
+        if let toml::Value::Table(table) = value {
+            path.push(k);
+            render_table(out, path, table, sources);
+            path.pop();
+        }
+    }
+}
+
+/// Quote `key` if it is not a bare key.
+pub fn key(key: &str) -> String {
+    if !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        key.to_string()
+    } else {
+        toml::Value::String(key.to_string()).to_string()
+    }
+}
+
+pub fn lookup<'a>(table: &'a toml::Table, path: &[&str]) -> Option<&'a toml::Value> {
+    let (last, parents) = path.split_last()?;
+    let mut table = table;
+    for k in parents {
+        table = table.get(*k)?.as_table()?;
+    }
+    table.get(*last)
+}
+
+fn origin(path: &[&str], value: &toml::Value, sources: &[Source]) -> Option<String> {
+    let defined: Vec<(&Origin, &toml::Value)> = sources
+        .iter()
+        .filter_map(|source| Some((&source.origin, lookup(&source.table, path)?)))
+        .collect();
+
+    if let Some((origin, _)) = defined.iter().find(|(_, v)| *v == value) {
+        return Some(origin.to_string());
+    }
+
+    (!defined.is_empty()).then(|| {
+        defined
+            .iter()
+            .map(|(origin, _)| origin.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(origin: Origin, contents: &str) -> Source {
+        Source {
+            origin,
+            table: toml::from_str(contents).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let sources = [
+            source(
+                Origin::File(PathBuf::from("topgrade.d/10-work.toml")),
+                "[misc]\nassume_yes = true\ndisable = [\"emacs\"]\n",
+            ),
+            source(
+                Origin::Include {
+                    path: PathBuf::from("shared.toml"),
+                    from: PathBuf::from("topgrade.toml"),
+                },
+                "[git]\nrepos = [\"~/src/*\"]\n",
+            ),
+            source(
+                Origin::File(PathBuf::from("topgrade.toml")),
+                "[misc]\nassume_yes = false\ndisable = [\"vim\"]\n\n[commands]\n\"Say hi\" = \"echo hi\"\n",
+            ),
+            source(Origin::Flag("--no-retry"), "[misc]\nno_retry = true\n"),
+        ];
+        let effective: toml::Table = toml::from_str(
+            r#"
+[misc]
+assume_yes = true
+disable = ["vim", "emacs"]
+no_retry = true
+
+[git]
+repos = ["~/src/*"]
+
+[commands]
+"Say hi" = "echo hi"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            render(&effective, &sources),
+            r#"[misc]
+assume_yes = true  # topgrade.d/10-work.toml
+disable = ["vim", "emacs"]  # topgrade.d/10-work.toml, topgrade.toml
+no_retry = true  # --no-retry
+
+[git]
+repos = ["~/src/*"]  # shared.toml (included from topgrade.toml)
+
+[commands]
+"Say hi" = "echo hi"  # topgrade.toml
+"#
+        );
+    }
+}
@@ -0,0 +1,213 @@
+//! JSON Schema of the configuration file, for `topgrade config schema`
+//!
+//! The schema is generated from the types of the configuration. The descriptions of the sections
+//! and keys are taken from the comments of `config.example.toml`, so that editors show the same
+//! documentation as the example configuration.
+use std::collections::BTreeMap;
+
+use regex::Regex;
+use schemars::generate::SchemaSettings;
+use serde_json::Value;
+
+use crate::config::{ConfigFile, EXAMPLE_CONFIG};
+
+/// The JSON Schema of `topgrade.toml`.
+///
+/// Draft 7 is used as it is the one best supported by the TOML language servers.
+pub fn schema() -> Value {
+    let mut schema = SchemaSettings::draft07()
+        .into_generator()
+        .into_root_schema_for::<ConfigFile>()
+        .to_value();
+
+    for (path, description) in descriptions(EXAMPLE_CONFIG) {
+        if let Some(pointer) = pointer(&schema, &path) {
+            if let Some(Value::Object(property)) = schema.pointer_mut(&pointer) {
+                property.insert("description".to_string(), Value::String(description));
+            }
+        }
+    }
+
+    schema
+}
+
+/// The descriptions of the sections and keys of the example configuration, by their dotted path.
+///
+/// A description is the block of comments right before a section header or a key, which are
+/// mostly commented out in the example.
+fn descriptions(example: &str) -> BTreeMap<String, String> {
+    let header = Regex::new(r"^#?\s*\[([^\[\]]+)\]\s*$").unwrap();
+    let key = Regex::new(r#"^#?\s*("[^"]*"|[A-Za-z0-9_-]+)\s*="#).unwrap();
+
+    let mut descriptions = BTreeMap::new();
+    let mut section = String::new();
+    let mut comment: Vec<&str> = Vec::new();
+    for line in example.lines().map(str::trim) {
+        let path = if let Some(captures) = header.captures(line) {
+            section = captures[1].to_string();
+            Some(section.clone())
+        } else if let Some(captures) = key.captures(line) {
+            Some(format!("{section}.{}", &captures[1]))
+        } else if let Some(text) = line.strip_prefix('#') {
+            comment.push(text.strip_prefix(' ').unwrap_or(text));
+            None
+        } else {
+            comment.clear();
+            None
+        };
+
+        if let Some(path) = path {
+            if !comment.is_empty() {
+                descriptions.entry(path).or_insert_with(|| comment.join("\n"));
+            }
+            comment.clear();
+        }
+    }
+
+    descriptions
+}
+
+/// The JSON pointer to the schema of the property at the dotted `path`, if there is one.
+///
+/// The keys of maps, such as the conditions of `[when.hostname."build-*"]`, are followed to the
+/// schema of their values, but the maps themselves have no properties to describe.
+fn pointer(schema: &Value, path: &str) -> Option<String> {
+    let keys: Vec<&str> = path.split('.').collect();
+    let mut pointer = String::new();
+    for (i, key) in keys.iter().enumerate() {
+        if i > 0 {
+            pointer = resolve(schema, pointer)?;
+        }
+        let property = format!("{pointer}/properties/{}", key.replace('~', "~0").replace('/', "~1"));
+        pointer = if schema.pointer(&property).is_some() {
+            property
+        } else if i + 1 < keys.len()
+            && schema
+                .pointer(&format!("{pointer}/additionalProperties/$ref"))
+                .is_some()
+        {
+            format!("{pointer}/additionalProperties")
+        } else {
+            return None;
+        };
+    }
+
+    Some(pointer)
+}
+
+/// The JSON pointer to the schema of the value at `keys`, following the keys of maps, with the
+/// references and optional wrappers resolved.
+pub fn value_pointer(schema: &Value, keys: &[&str]) -> Option<String> {
+    let mut pointer = String::new();
+    for key in keys {
+        pointer = resolve(schema, pointer)?;
+        let property = format!("{pointer}/properties/{}", key.replace('~', "~0").replace('/', "~1"));
+        let values = format!("{pointer}/additionalProperties");
+        pointer = if schema.pointer(&property).is_some() {
+            property
+        } else if schema.pointer(&values).is_some_and(Value::is_object) {
+            values
+        } else {
+            return None;
+        };
+    }
+
+    resolve(schema, pointer)
+}
+
+/// Follow the references and the optional wrappers, i.e. `anyOf` with `null`, from the schema at
+/// `pointer` to the schema of the section itself.
+pub fn resolve(schema: &Value, mut pointer: String) -> Option<String> {
+    loop {
+        let node = schema.pointer(&pointer)?;
+        if let Some(reference) = node.get("$ref").and_then(Value::as_str) {
+            pointer = reference.strip_prefix('#')?.to_string();
+        } else if let Some(variants) = node.get("anyOf").and_then(Value::as_array) {
+            let null = Value::from("null");
+            let i = variants.iter().position(|variant| variant.get("type") != Some(&null))?;
+            pointer = format!("{pointer}/anyOf/{i}");
+        } else {
+            return Some(pointer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_descriptions() {
+        let descriptions = descriptions(
+            r#"
+# Included files
+[include]
+# paths = ["/etc/topgrade.toml"]
+
+[misc]
+# Disable specific steps
+# (default: [])
+# disable = ["system"]
+# assume_yes = true
+
+# Send a message
+# [notifications.ntfy]
+# url = "https://ntfy.sh/topic"
+"#,
+        );
+
+        assert_eq!(descriptions["include"], "Included files");
+        assert_eq!(descriptions["misc.disable"], "Disable specific steps\n(default: [])");
+        assert_eq!(descriptions["notifications.ntfy"], "Send a message");
+        assert!(!descriptions.contains_key("misc.assume_yes"));
+        assert!(!descriptions.contains_key("include.paths"));
+    }
+
+    #[test]
+    fn test_schema() {
+        let schema = schema();
+        let description = |path: &str| {
+            let pointer = pointer(&schema, path).unwrap();
+            schema.pointer(&format!("{pointer}/description")).cloned()
+        };
+
+        assert!(description("misc.disable")
+            .and_then(|d| d.as_str().map(|d| d.starts_with("Disable specific steps")))
+            .unwrap());
+        assert!(description("notifications.webhook").is_some());
+        assert!(description("linux.arch_package_manager").is_some());
+
+        // Variants with a doc comment are listed apart in a `oneOf`
+        let allows = |definition: &str, value: &str| {
+            let definition = &schema["definitions"][definition];
+            let value = Value::from(value);
+            definition["oneOf"]
+                .as_array()
+                .map_or_else(|| vec![definition], |variants| variants.iter().collect())
+                .into_iter()
+                .any(|variant| {
+                    variant["const"] == value
+                        || variant["enum"].as_array().is_some_and(|values| values.contains(&value))
+                })
+        };
+        assert!(allows("Step", "cargo"));
+        assert!(allows("ArchPackageManager", "garuda_update"));
+        assert!(allows("ContainerRuntime", "podman"));
+        assert!(allows("SudoKind", "doas"));
+        assert!(allows("SudoKind", "null"));
+        assert!(!allows("Step", "carg"));
+    }
+
+    #[test]
+    fn test_example_keys_are_in_schema() {
+        let schema = schema();
+        for path in descriptions(EXAMPLE_CONFIG).keys() {
+            let section = path.split('.').next().unwrap();
+            // The keys of the command sections are the names of the commands
+            if ["pre_commands", "post_commands", "commands"].contains(&section) {
+                continue;
+            }
+            assert!(pointer(&schema, path).is_some(), "{path} is not in the schema");
+        }
+    }
+}
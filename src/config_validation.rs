@@ -0,0 +1,138 @@
+//! Diagnostics of the configuration files, for `topgrade config validate`
+use std::fmt::{self, Display};
+use std::path::{Path, PathBuf};
+
+use rust_i18n::t;
+
+/// An error in a configuration file.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub path: PathBuf,
+    /// The 1-based line and column of the error, if known.
+    pub location: Option<(usize, usize)>,
+    pub message: String,
+    /// The key or value that was probably meant, for unknown ones.
+    pub suggestion: Option<String>,
+}
+
+impl Diagnostic {
+    /// An error that is not located in the file, e.g. when it could not be read.
+    pub fn new(path: &Path, message: impl Display) -> Self {
+        let message = message.to_string();
+        Self {
+            path: path.to_path_buf(),
+            location: None,
+            suggestion: suggest(&message),
+            message,
+        }
+    }
+
+    /// An error from parsing the part of `contents`, the whole file, which starts at byte `offset`.
+    ///
+    /// The configuration files are parsed in parts split at the `[include]` sections, so the span
+    /// of the error is relative to the part.
+    pub fn from_toml(path: &Path, contents: &str, offset: usize, error: &toml::de::Error) -> Self {
+        let message = error.message().trim().to_string();
+        Self {
+            path: path.to_path_buf(),
+            location: error.span().map(|span| line_column(contents, offset + span.start)),
+            suggestion: suggest(&message),
+            message,
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        if let Some((line, column)) = self.location {
+            write!(f, ":{line}:{column}")?;
+        }
+        write!(f, ": {}", self.message)?;
+        if let Some(suggestion) = &self.suggestion {
+            write!(f, "\n    {}", t!("Did you mean `{name}`?", name = suggestion))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The 1-based line and column of the byte `offset` of `contents`.
+fn line_column(contents: &str, offset: usize) -> (usize, usize) {
+    let mut offset = offset.min(contents.len());
+    while !contents.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    let before = &contents[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().unwrap_or_default().chars().count() + 1;
+    (line, column)
+}
+
+/// Suggest the closest expected name for the unknown field or variant of `message`.
+///
+/// `serde` lists the expected names in its errors, e.g. "unknown field `disabel`, expected one of
+/// `pre_sudo`, `disable`, ...", which are the field names of the struct.
+fn suggest(message: &str) -> Option<String> {
+    let rest = message
+        .strip_prefix("unknown field `")
+        .or_else(|| message.strip_prefix("unknown variant `"))?;
+    let (unknown, expected) = rest.split_once('`')?;
+
+    let max_distance = 2.max(unknown.chars().count() / 3);
+    expected
+        .split('`')
+        .skip(1)
+        .step_by(2)
+        .map(|name| (strsim::levenshtein(unknown, name), name))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, name)| name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suggest() {
+        assert_eq!(
+            suggest("unknown field `disabel`, expected one of `pre_sudo`, `disable`, `ignore_failures`"),
+            Some("disable".to_string())
+        );
+        assert_eq!(
+            suggest("unknown variant `carg`, expected one of `bun`, `cargo`, `certbot`"),
+            Some("cargo".to_string())
+        );
+        assert_eq!(suggest("unknown field `foo`, expected `pre_sudo` or `disable`"), None);
+        assert_eq!(suggest("invalid type: string \"yes\", expected a boolean"), None);
+    }
+
+    #[test]
+    fn test_from_toml() {
+        #[derive(Debug, serde::Deserialize)]
+        #[serde(deny_unknown_fields)]
+        #[allow(dead_code)]
+        struct Misc {
+            disable: Option<Vec<String>>,
+            assume_yes: Option<bool>,
+        }
+
+        #[derive(Debug, serde::Deserialize)]
+        #[allow(dead_code)]
+        struct File {
+            misc: Option<Misc>,
+        }
+
+        let contents = "# comment\n[include]\npaths = []\n\n[misc]\nassume_yes = true\ndisabel = [\"emacs\"]\n";
+        let offset = contents.find("[misc]").unwrap();
+        let error = toml::from_str::<File>(&contents[offset..]).unwrap_err();
+        let diagnostic = Diagnostic::from_toml(Path::new("topgrade.toml"), contents, offset, &error);
+
+        assert_eq!(diagnostic.location, Some((7, 1)));
+        assert_eq!(diagnostic.suggestion.as_deref(), Some("disable"));
+        assert!(diagnostic
+            .to_string()
+            .starts_with("topgrade.toml:7:1: unknown field `disabel`"));
+    }
+}
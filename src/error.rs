@@ -1,5 +1,7 @@
+use std::io;
 use std::{fmt::Display, process::ExitStatus};
 
+use color_eyre::eyre;
 use rust_i18n::t;
 use thiserror::Error;
 
@@ -90,6 +92,22 @@ impl Display for UnsupportedSudo<'_> {
     }
 }
 
+#[derive(Error, Debug)]
+pub struct PrivilegedNotAllowed(pub String);
+
+impl Display for PrivilegedNotAllowed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            t!(
+                "`{command}` is not allowed to run with elevated privileges by the [security] section of the configuration",
+                command = self.0
+            )
+        )
+    }
+}
+
 #[derive(Error, Debug)]
 pub struct MissingSudo();
 
@@ -116,3 +134,106 @@ impl Display for SkipStep {
         write!(f, "{}", self.0)
     }
 }
+
+#[derive(Error, Debug)]
+pub struct ConfigError;
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", t!("Invalid configuration"))
+    }
+}
+
+/// The exit codes of Topgrade. These are stable, new ones may be added but existing ones don't change.
+///
+/// Errors in the command line arguments exit with 2, which is done by `clap`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitCode {
+    /// All steps succeeded, or were skipped.
+    Success = 0,
+    /// An unexpected error occurred.
+    Error = 1,
+    /// One or more steps failed.
+    StepFailed = 3,
+    /// No step failed, but some were skipped as sudo or equivalent could not be found.
+    SkippedMissingSudo = 4,
+    /// The user quit, e.g. with `q` at the retry prompt or by refusing to continue.
+    Interrupted = 5,
+    /// The configuration is invalid.
+    ConfigError = 6,
+}
+
+impl ExitCode {
+    /// The exit code for a run which failed with `error`.
+    pub fn from_error(error: &eyre::Report) -> Self {
+        if error.downcast_ref::<StepFailed>().is_some() {
+            ExitCode::StepFailed
+        } else if error
+            .downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::Interrupted)
+        {
+            ExitCode::Interrupted
+        } else if error.downcast_ref::<ConfigError>().is_some() {
+            ExitCode::ConfigError
+        } else {
+            ExitCode::Error
+        }
+    }
+}
+
+impl From<ExitCode> for i32 {
+    fn from(code: ExitCode) -> Self {
+        code as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::{eyre, WrapErr};
+
+    use super::*;
+
+    #[test]
+    fn test_exit_code_from_error() {
+        let code = |error: eyre::Report| ExitCode::from_error(&error);
+
+        assert_eq!(code(eyre!("unexpected")), ExitCode::Error);
+        assert_eq!(code(io::Error::from(io::ErrorKind::NotFound).into()), ExitCode::Error);
+        assert_eq!(code(MissingSudo().into()), ExitCode::Error);
+        assert_eq!(code(SkipStep("skipped".to_string()).into()), ExitCode::Error);
+        assert_eq!(
+            code(PrivilegedNotAllowed("/usr/bin/apt".to_string()).into()),
+            ExitCode::Error
+        );
+        assert_eq!(code(StepFailed.into()), ExitCode::StepFailed);
+        assert_eq!(
+            code(io::Error::from(io::ErrorKind::Interrupted).into()),
+            ExitCode::Interrupted
+        );
+        assert_eq!(
+            code(
+                Err::<(), _>(io::Error::from(io::ErrorKind::Interrupted))
+                    .context("Quit from user input")
+                    .unwrap_err()
+            ),
+            ExitCode::Interrupted
+        );
+        assert_eq!(code(ConfigError.into()), ExitCode::ConfigError);
+        assert_eq!(
+            code(
+                Err::<(), _>(eyre!("unknown key `foo`"))
+                    .wrap_err(ConfigError)
+                    .unwrap_err()
+            ),
+            ExitCode::ConfigError
+        );
+        assert_eq!(
+            code(
+                Err::<(), _>(io::Error::from(io::ErrorKind::PermissionDenied))
+                    .wrap_err(ConfigError)
+                    .unwrap_err()
+            ),
+            ExitCode::ConfigError
+        );
+    }
+}
@@ -0,0 +1,263 @@
+//! Machine-readable stream of events for front-ends
+//!
+//! When `--events` is given, every event is written to the target as a single line of JSON, so
+//! that front-ends can follow the progress of Topgrade without parsing its terminal output.
+use std::fs::File;
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{LazyLock, Mutex};
+
+use color_eyre::eyre::{Context, Result};
+use rust_i18n::t;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::packages::PackageChange;
+use crate::runner::StepResult;
+use crate::sudo::SudoKind;
+
+static EVENTS: LazyLock<Mutex<Option<Box<dyn Write + Send>>>> = LazyLock::new(|| Mutex::new(None));
+
+/// Where the events are written to.
+#[derive(Clone, Debug)]
+pub enum EventsTarget {
+    /// A file, which is truncated first.
+    File(PathBuf),
+    /// A file descriptor inherited from the parent process.
+    #[cfg(unix)]
+    Fd(RawFd),
+}
+
+impl FromStr for EventsTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("fd:") {
+            #[cfg(unix)]
+            Some(fd) => fd
+                .parse()
+                .map(EventsTarget::Fd)
+                .map_err(|_| t!("Invalid file descriptor: {fd}", fd = fd).to_string()),
+            #[cfg(not(unix))]
+            Some(_) => Err(t!("File descriptors are only supported on Unix").to_string()),
+            None => Ok(EventsTarget::File(PathBuf::from(s))),
+        }
+    }
+}
+
+/// The kind of input a prompt is waiting for.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PromptKind {
+    /// A yes/no question.
+    YesNo,
+    /// Whether a failed step should be retried.
+    Retry,
+    /// What to do at the end of the run (`--keep`).
+    KeepAtEnd,
+    /// A line of text, e.g. the remote machines asked by `topgrade config init`.
+    Text,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StepSummary<'a> {
+    pub key: &'a str,
+    pub result: &'a StepResult,
+    pub upgraded: &'a [PackageChange],
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    RunStart {
+        version: &'a str,
+        dry_run: bool,
+    },
+    StepStart {
+        key: &'a str,
+    },
+    /// `result` is `None` if the step did not produce a result, e.g. in a dry run.
+    StepFinish {
+        key: &'a str,
+        result: Option<&'a StepResult>,
+        upgraded: &'a [PackageChange],
+    },
+    CommandSpawned {
+        program: String,
+        args: Vec<String>,
+        directory: Option<String>,
+        dry_run: bool,
+    },
+    PromptRequested {
+        kind: PromptKind,
+        message: &'a str,
+    },
+    SudoRequested {
+        sudo_kind: SudoKind,
+    },
+    Summary {
+        steps: Vec<StepSummary<'a>>,
+        failed: bool,
+    },
+}
+
+/// Start writing events to `target`.
+pub fn init(target: &EventsTarget) -> Result<()> {
+    let writer: Box<dyn Write + Send> = match target {
+        EventsTarget::File(path) => Box::new(
+            File::create(path).with_context(|| format!("Failed to create the events file {}", path.display()))?,
+        ),
+        // SAFETY: the file descriptor is provided by the user, who is responsible for it being
+        // open and not used by anything else in Topgrade.
+        #[cfg(unix)]
+        EventsTarget::Fd(fd) => Box::new(unsafe { File::from_raw_fd(*fd) }),
+    };
+    *EVENTS.lock().unwrap() = Some(Box::new(io::LineWriter::new(writer)));
+
+    Ok(())
+}
+
+/// Whether events are being written, so that callers can avoid building expensive events.
+pub fn enabled() -> bool {
+    EVENTS.lock().unwrap().is_some()
+}
+
+/// Write `event` to the events target, if any.
+///
+/// Failing to write an event never fails the run; events are dropped from then on.
+pub fn emit(event: Event) {
+    let mut events = EVENTS.lock().unwrap();
+    if let Some(writer) = events.as_mut() {
+        if let Err(e) = write(writer, &event) {
+            debug!("Failed to write event, disabling events: {e}");
+            *events = None;
+        }
+    }
+}
+
+/// Write `event` to `writer`, as a line of JSON.
+fn write(writer: &mut impl Write, event: &Event) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, event)?;
+    writer.write_all(b"\n")
+}
+
+/// Read the results of the steps from the `summary` event of an events stream, e.g. one written
+/// by another instance of Topgrade.
+pub fn read_summary(events: &str) -> Option<Vec<(String, StepResult)>> {
+    #[derive(Deserialize)]
+    struct Summary {
+        steps: Vec<Step>,
+    }
+
+    #[derive(Deserialize)]
+    struct Step {
+        key: String,
+        result: StepResult,
+    }
+
+    events.lines().rev().find_map(|line| {
+        let event: serde_json::Value = serde_json::from_str(line).ok()?;
+        if event["event"] != "summary" {
+            return None;
+        }
+        let summary: Summary = serde_json::from_value(event).ok()?;
+        Some(summary.steps.into_iter().map(|step| (step.key, step.result)).collect())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn test_events_target_from_str() {
+        assert!(matches!(
+            "events.ndjson".parse(),
+            Ok(EventsTarget::File(path)) if path == Path::new("events.ndjson")
+        ));
+        assert!(matches!(
+            "/tmp/fd:3".parse(),
+            Ok(EventsTarget::File(path)) if path == Path::new("/tmp/fd:3")
+        ));
+        #[cfg(unix)]
+        {
+            assert!(matches!("fd:3".parse(), Ok(EventsTarget::Fd(3))));
+            assert!("fd:".parse::<EventsTarget>().is_err());
+            assert!("fd:three".parse::<EventsTarget>().is_err());
+            assert!("fd:-".parse::<EventsTarget>().is_err());
+        }
+        #[cfg(not(unix))]
+        assert!("fd:3".parse::<EventsTarget>().is_err());
+    }
+
+    #[test]
+    fn test_write() {
+        let mut output = Vec::new();
+        write(&mut output, &Event::StepStart { key: "cargo" }).unwrap();
+        write(
+            &mut output,
+            &Event::StepFinish {
+                key: "cargo",
+                result: Some(&StepResult::Skipped("Not installed".to_string())),
+                upgraded: &[],
+            },
+        )
+        .unwrap();
+        write(
+            &mut output,
+            &Event::CommandSpawned {
+                program: "cargo".to_string(),
+                args: vec!["install-update".to_string(), "--all".to_string()],
+                directory: None,
+                dry_run: true,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            concat!(
+                r#"{"event":"step_start","key":"cargo"}"#,
+                "\n",
+                r#"{"event":"step_finish","key":"cargo","result":{"status":"skipped","reason":"Not installed"},"upgraded":[]}"#,
+                "\n",
+                r#"{"event":"command_spawned","program":"cargo","args":["install-update","--all"],"directory":null,"dry_run":true}"#,
+                "\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_read_summary() {
+        let skipped = StepResult::Skipped("Not installed".to_string());
+        let steps = vec![
+            StepSummary {
+                key: "cargo",
+                result: &StepResult::Failure,
+                upgraded: &[],
+            },
+            StepSummary {
+                key: "pipx",
+                result: &skipped,
+                upgraded: &[],
+            },
+        ];
+        let events = [
+            serde_json::to_string(&Event::StepStart { key: "cargo" }).unwrap(),
+            serde_json::to_string(&Event::Summary { steps, failed: true }).unwrap(),
+        ]
+        .join("\n");
+
+        let summary = read_summary(&events).unwrap();
+        assert_eq!(summary.len(), 2);
+        assert_eq!(summary[0].0, "cargo");
+        assert!(summary[0].1.failed());
+        assert!(matches!(&summary[1].1, StepResult::Skipped(reason) if reason == "Not installed"));
+        assert!(read_summary("").is_none());
+    }
+}
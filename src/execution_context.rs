@@ -1,6 +1,8 @@
 #![allow(dead_code)]
-use std::env::var;
-use std::ffi::OsStr;
+use std::env::{self, var};
+use std::ffi::{OsStr, OsString};
+use std::fmt::{self, Display};
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::sync::{LazyLock, Mutex};
 
@@ -9,15 +11,21 @@ use color_eyre::eyre::Result;
 use rust_i18n::t;
 use serde::Deserialize;
 use strum::EnumString;
+use tracing::{debug, warn};
 
-use crate::config::Config;
-use crate::error::MissingSudo;
-use crate::executor::{DryCommand, Executor};
+use crate::audit;
+use crate::command::CommandExt;
+use crate::config::{Config, EnvPolicy};
+use crate::error::{MissingSudo, PrivilegedNotAllowed};
+use crate::executor::{DryCommand, Executor, WetCommand};
+use crate::packages::PackageChange;
 use crate::powershell::Powershell;
+use crate::runner::StepResult;
+use crate::step::Step;
 #[cfg(target_os = "linux")]
 use crate::steps::linux::Distribution;
-use crate::sudo::Sudo;
-use crate::utils::require_option;
+use crate::sudo::{Sudo, SudoKind};
+use crate::utils::{require_option, which};
 
 /// An enum telling whether Topgrade should perform dry runs or actually perform the steps.
 #[derive(Clone, Copy, Debug, Deserialize, Default, EnumString, ValueEnum)]
@@ -31,6 +39,12 @@ pub enum RunType {
 
     /// Executing commands will print the command and perform actual execution.
     Damp,
+
+    /// Executing commands will perform actual execution, and record them to the cassette.
+    Record,
+
+    /// Executing commands will replay their recordings from the cassette.
+    Replay,
 }
 
 impl RunType {
@@ -40,6 +54,59 @@ impl RunType {
             RunType::Dry => true,
             RunType::Wet => false,
             RunType::Damp => false,
+            RunType::Record => false,
+            RunType::Replay => false,
+        }
+    }
+
+    /// Tells whether nothing should be changed, which is also the case when replaying a cassette.
+    ///
+    /// Work that is not done by commands, and so cannot be replayed, should be skipped then.
+    pub fn simulated(self) -> bool {
+        self.dry() || matches!(self, RunType::Replay)
+    }
+}
+
+/// A user, and the results of the steps run for them.
+pub type UserReport = (String, Vec<(String, StepResult)>);
+
+/// A command that would be run with elevated privileges, see `--print-privileged-plan`.
+pub struct PrivilegedCommand {
+    pub step: Option<Step>,
+    pub command: String,
+    pub sudo_kind: SudoKind,
+    /// Whether the `[security]` section allows running it.
+    pub allowed: bool,
+}
+
+impl Display for PrivilegedCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let step = self
+            .step
+            .and_then(|step| step.to_possible_value())
+            .map_or_else(|| "-".to_string(), |step| step.get_name().to_string());
+        if self.allowed {
+            write!(
+                f,
+                "{}",
+                t!(
+                    "{step}: `{command}` with {sudo_kind}",
+                    step = step,
+                    command = self.command,
+                    sudo_kind = self.sudo_kind
+                )
+            )
+        } else {
+            write!(
+                f,
+                "{}",
+                t!(
+                    "{step}: `{command}` with {sudo_kind}, refused by [security]",
+                    step = step,
+                    command = self.command,
+                    sudo_kind = self.sudo_kind
+                )
+            )
         }
     }
 }
@@ -57,6 +124,16 @@ pub struct ExecutionContext<'a> {
     #[cfg(target_os = "linux")]
     distribution: &'a Result<Distribution>,
     powershell: LazyLock<Option<Powershell>>,
+    /// Package versions changed by the current step.
+    package_changes: Mutex<Vec<PackageChange>>,
+    /// Results of the steps run for other users, see `Step::Users`.
+    user_reports: Mutex<Vec<UserReport>>,
+    /// The step being run, whose options in `[step.<name>]` apply to the commands.
+    step: Mutex<Option<Step>>,
+    /// Whether the running step added its `extra_args` to a command, see `execute_step`.
+    extra_args_used: Mutex<bool>,
+    /// The commands that would be elevated, see `--print-privileged-plan`.
+    privileged_plan: Mutex<Vec<PrivilegedCommand>>,
 }
 
 impl<'a> ExecutionContext<'a> {
@@ -76,15 +153,182 @@ impl<'a> ExecutionContext<'a> {
             #[cfg(target_os = "linux")]
             distribution,
             powershell: LazyLock::new(Powershell::new),
+            package_changes: Mutex::new(Vec::new()),
+            user_reports: Mutex::new(Vec::new()),
+            step: Mutex::new(None),
+            extra_args_used: Mutex::new(false),
+            privileged_plan: Mutex::new(Vec::new()),
         }
     }
 
     /// Create an instance of `Executor` that should run `program`.
+    ///
+    /// The environment policy of `[misc]` and the options of the running step in `[step.<name>]`
+    /// apply: the environment variables are removed or set, the command is run with sudo if
+    /// `use_sudo` is set and the `[security]` section allows it, and with the priority and
+    /// resource limits of the step. The extra arguments of the step are only added by
+    /// `execute_step`.
     pub fn execute<S: AsRef<OsStr>>(&self, program: S) -> Executor {
+        let sudo = self.sudo.as_ref().and_then(|sudo| sudo.path().map(|path| (sudo, path)));
+        let mut executor = match (self.step(), sudo) {
+            (Some(step), Some((sudo, path))) if self.config.step_use_sudo(step) == Some(true) => {
+                match self.check_privileged(sudo.kind(), program.as_ref()) {
+                    Ok(()) => {
+                        // Cache the credentials or announce the prompt like `Sudo::execute_opts`,
+                        // but without failing the step: `sudo` asks for the password again
+                        if let Err(e) = sudo.request(self) {
+                            warn!("{e:?}");
+                        }
+                        let mut executor = self.limited_sudo(path, sudo.kind());
+                        executor.arg(program);
+                        executor
+                    }
+                    Err(_) => Executor::Refused(DryCommand::new(program)),
+                }
+            }
+            _ => self.limited(program),
+        };
+        self.apply_step_options(&mut executor);
+        executor
+    }
+
+    /// Create an instance of `Executor` that should run the main upgrade command of the running
+    /// step, `program` with `args`, followed by the `extra_args` of the step in `[step.<name>]`.
+    ///
+    /// The other commands of the step, such as `--version` probes, are run with `execute`, as the
+    /// extra arguments would not make sense for them.
+    pub fn execute_step<S, I, A>(&self, program: S, args: I) -> Executor
+    where
+        S: AsRef<OsStr>,
+        I: IntoIterator<Item = A>,
+        A: AsRef<OsStr>,
+    {
+        let mut executor = self.execute(program);
+        executor.args(args);
+        if let Some(step) = self.step() {
+            executor.args(self.config.step_extra_args(step));
+            *self.extra_args_used.lock().unwrap() = true;
+        }
+        executor
+    }
+
+    /// Whether the running step has `extra_args` in `[step.<name>]`, but did not run its main
+    /// upgrade command with `execute_step`, e.g. because it does not support them.
+    pub fn extra_args_unused(&self) -> bool {
+        self.step()
+            .is_some_and(|step| !self.config.step_extra_args(step).is_empty())
+            && !*self.extra_args_used.lock().unwrap()
+    }
+
+    /// Create an instance of `Executor` that should run `program`, without the options of the
+    /// running step.
+    pub fn command<S: AsRef<OsStr>>(&self, program: S) -> Executor {
         match self.run_type {
             RunType::Dry => Executor::Dry(DryCommand::new(program)),
-            RunType::Wet => Executor::Wet(Command::new(program)),
-            RunType::Damp => Executor::Damp(Command::new(program)),
+            RunType::Wet => Executor::Wet(WetCommand::new(program)),
+            RunType::Damp => Executor::Damp(WetCommand::new(program)),
+            // The cassette records or replays the commands
+            RunType::Record | RunType::Replay => Executor::Wet(WetCommand::new(program)),
+        }
+    }
+
+    /// Create an instance of `Executor` that should run `program` with the priority and resource
+    /// limits of the running step, see `Config::resources`.
+    ///
+    /// The command is run by `systemd-run --user --scope` with the resource limits, then by `nice`
+    /// and `ionice`, which are skipped where they are not available.
+    pub fn limited<S: AsRef<OsStr>>(&self, program: S) -> Executor {
+        self.wrapped(program, true)
+    }
+
+    /// Create an instance of `Executor` that should run `sudo`, of `kind`, with the priority of
+    /// the running step, see `limited`.
+    ///
+    /// The resource limits are not applied, as the scope of the user does not govern the commands
+    /// elevated by `sudo`.
+    pub fn limited_sudo(&self, sudo: &Path, kind: SudoKind) -> Executor {
+        let mut executor = self.wrapped(sudo, false);
+        executor.elevated_by(kind);
+        executor
+    }
+
+    fn wrapped<S: AsRef<OsStr>>(&self, program: S, scope: bool) -> Executor {
+        let resources = self.config.resources(self.step());
+        let mut wrappers: Vec<OsString> = Vec::new();
+
+        if scope && (resources.cpu_quota.is_some() || resources.memory_max.is_some()) {
+            if let Some(systemd_run) = systemd_run_user() {
+                wrappers.extend([systemd_run.into(), "--user".into(), "--scope".into(), "--quiet".into()]);
+                if let Some(quota) = &resources.cpu_quota {
+                    wrappers.extend(["-p".into(), format!("CPUQuota={quota}").into()]);
+                }
+                if let Some(max) = &resources.memory_max {
+                    wrappers.extend(["-p".into(), format!("MemoryMax={max}").into()]);
+                }
+                wrappers.push("--".into());
+            }
+        }
+        if let Some(nice) = resources.nice {
+            if let Some(path) = which("nice") {
+                wrappers.extend([path.into(), "-n".into(), nice.to_string().into()]);
+            }
+        }
+        if let Some(class) = resources.ionice_class {
+            if let Some(path) = which("ionice") {
+                // `-t` runs the command even if the class cannot be set, e.g. realtime without root
+                wrappers.extend([path.into(), "-t".into(), "-c".into(), class.number().to_string().into()]);
+            }
+        }
+
+        match wrappers.split_first() {
+            Some((wrapper, args)) => {
+                let mut executor = self.command(wrapper);
+                executor.args(args).arg(program);
+                executor
+            }
+            None => self.command(program),
+        }
+    }
+
+    /// Apply the environment policy of `[misc]` and the environment options of the running step in
+    /// `[step.<name>]` to `executor`.
+    pub fn apply_step_options(&self, executor: &mut Executor) {
+        let step = self.step();
+        let clean = self.config.env_policy() == EnvPolicy::Clean;
+        if clean || step.is_some() {
+            for (key, _) in env::vars_os() {
+                if (clean && !self.config.env_allowed(&key))
+                    || step.is_some_and(|step| self.config.step_env_removed(step, &key))
+                {
+                    executor.env_remove(key);
+                }
+            }
+        }
+
+        let Some(step) = step else {
+            return;
+        };
+        for (key, value) in self.config.step_env(step).into_iter().flatten() {
+            executor.env(key, value);
+        }
+    }
+
+    /// Set the step being run, or `None` once it is done.
+    pub fn set_step(&self, step: Option<Step>) {
+        *self.step.lock().unwrap() = step;
+        *self.extra_args_used.lock().unwrap() = false;
+        audit::set_step(step);
+    }
+
+    pub fn step(&self) -> Option<Step> {
+        *self.step.lock().unwrap()
+    }
+
+    /// Tell whether the running step should perform its cleanup.
+    pub fn cleanup(&self) -> bool {
+        match self.step() {
+            Some(step) => self.config.step_cleanup(step),
+            None => self.config.cleanup(),
         }
     }
 
@@ -120,6 +364,50 @@ impl<'a> ExecutionContext<'a> {
         self.tmux_session.lock().unwrap().clone()
     }
 
+    pub fn record_package_changes(&self, changes: Vec<PackageChange>) {
+        self.package_changes.lock().unwrap().extend(changes);
+    }
+
+    pub fn take_package_changes(&self) -> Vec<PackageChange> {
+        std::mem::take(&mut self.package_changes.lock().unwrap())
+    }
+
+    /// Record the results of the steps run for `user`, replacing those of a previous attempt.
+    pub fn record_user_report(&self, user: &str, steps: Vec<(String, StepResult)>) {
+        let mut reports = self.user_reports.lock().unwrap();
+        reports.retain(|(u, _)| u != user);
+        reports.push((user.to_string(), steps));
+    }
+
+    pub fn take_user_reports(&self) -> Vec<UserReport> {
+        std::mem::take(&mut self.user_reports.lock().unwrap())
+    }
+
+    /// Check that `command` may be run with elevated privileges by `sudo_kind`.
+    ///
+    /// With `--print-privileged-plan`, the command is added to the plan instead of being refused.
+    pub fn check_privileged(&self, sudo_kind: SudoKind, command: &OsStr) -> Result<(), PrivilegedNotAllowed> {
+        let allowed = self.config.privileged_allowed(Path::new(command));
+        let command = command.to_string_lossy().into_owned();
+        if self.config.print_privileged_plan() {
+            self.privileged_plan.lock().unwrap().push(PrivilegedCommand {
+                step: self.step(),
+                command,
+                sudo_kind,
+                allowed,
+            });
+            Ok(())
+        } else if allowed {
+            Ok(())
+        } else {
+            Err(PrivilegedNotAllowed(command))
+        }
+    }
+
+    pub fn take_privileged_plan(&self) -> Vec<PrivilegedCommand> {
+        std::mem::take(&mut self.privileged_plan.lock().unwrap())
+    }
+
     #[cfg(target_os = "linux")]
     pub fn distribution(&self) -> &Result<Distribution> {
         self.distribution
@@ -133,3 +421,223 @@ impl<'a> ExecutionContext<'a> {
         require_option(self.powershell.as_ref(), t!("Powershell is not installed").to_string())
     }
 }
+
+/// The path to `systemd-run`, if `systemd-run --user` works, which needs a user manager.
+///
+/// This is checked once, by running `true` in a scope.
+fn systemd_run_user() -> Option<&'static Path> {
+    static SYSTEMD_RUN: LazyLock<Option<PathBuf>> = LazyLock::new(|| {
+        let systemd_run = which("systemd-run")?;
+        match Command::new(&systemd_run)
+            .args(["--user", "--scope", "--quiet", "true"])
+            .output_checked()
+        {
+            Ok(_) => Some(systemd_run),
+            Err(e) => {
+                debug!("systemd-run --user does not work, the resource limits are not applied: {e:?}");
+                None
+            }
+        }
+    });
+    SYSTEMD_RUN.as_deref()
+}
+
+/// The command run by `argv` under the wrappers added by `wrapped`, i.e. `argv` without its
+/// `systemd-run --user`, `nice -n` and `ionice -t -c` prefixes.
+pub(crate) fn unwrapped(argv: &[String]) -> &[String] {
+    let mut command = argv;
+    loop {
+        let name = command
+            .first()
+            .and_then(|program| Path::new(program).file_name())
+            .and_then(OsStr::to_str);
+        let args: Vec<&str> = command.iter().skip(1).take(3).map(String::as_str).collect();
+        let wrapped = match (name, args.as_slice()) {
+            (Some("systemd-run"), ["--user", ..]) => command
+                .iter()
+                .position(|arg| arg == "--")
+                .map(|separator| &command[separator + 1..]),
+            (Some("nice"), ["-n", _, ..]) => Some(&command[3..]),
+            (Some("ionice"), ["-t", "-c", _]) => Some(&command[4..]),
+            _ => None,
+        };
+        match wrapped {
+            Some(wrapped) if !wrapped.is_empty() => command = wrapped,
+            _ => return command,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use color_eyre::eyre::eyre;
+
+    use super::*;
+
+    fn argv(executor: &Executor) -> Vec<String> {
+        match executor {
+            Executor::Wet(cmd) | Executor::Damp(cmd) => std::iter::once(cmd.get_program())
+                .chain(cmd.get_args())
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+            Executor::Dry(_) | Executor::Refused(_) => unreachable!(),
+        }
+    }
+
+    /// Test that `extra_args` are only added to the main upgrade command of the step.
+    #[test]
+    fn test_execute_step() {
+        let config = Config::from_toml("[step.cargo]\nextra_args = [\"--locked\"]\n");
+        #[cfg(target_os = "linux")]
+        let distribution = Err(eyre!("No distribution"));
+        let ctx = ExecutionContext::new(
+            RunType::Wet,
+            None,
+            &config,
+            #[cfg(target_os = "linux")]
+            &distribution,
+        );
+
+        ctx.set_step(Some(Step::Cargo));
+        assert!(ctx.extra_args_unused());
+        assert_eq!(
+            argv(ctx.execute("cargo-install-update").arg("--version")),
+            ["cargo-install-update", "--version"]
+        );
+        assert_eq!(
+            argv(&ctx.execute_step("cargo-install-update", ["install-update", "--git", "--all"])),
+            ["cargo-install-update", "install-update", "--git", "--all", "--locked"]
+        );
+        assert_eq!(argv(ctx.execute("cargo-cache").arg("-a")), ["cargo-cache", "-a"]);
+        assert!(!ctx.extra_args_unused());
+
+        ctx.set_step(Some(Step::Pipx));
+        assert!(!ctx.extra_args_unused());
+        assert_eq!(
+            argv(&ctx.execute_step("pipx", ["upgrade-all"])),
+            ["pipx", "upgrade-all"]
+        );
+        ctx.set_step(None);
+    }
+
+    /// Test that the commands that the `[security]` section does not allow are refused elevated
+    /// privileges, whether through `use_sudo` or `Sudo::execute_opts`.
+    #[test]
+    fn test_privileged_not_allowed() {
+        let config =
+            Config::from_toml("[security]\nprivileged_commands = [\"apt-get\"]\n\n[step.pipx]\nuse_sudo = true\n");
+        #[cfg(target_os = "linux")]
+        let distribution = Err(eyre!("No distribution"));
+        let sudo = Sudo::with_path(SudoKind::Sudo, PathBuf::from("/usr/bin/sudo"));
+        let ctx = ExecutionContext::new(
+            RunType::Wet,
+            Some(sudo),
+            &config,
+            #[cfg(target_os = "linux")]
+            &distribution,
+        );
+        let refused = |result: Result<()>| {
+            result
+                .unwrap_err()
+                .downcast_ref::<PrivilegedNotAllowed>()
+                .is_some_and(|e| e.0 == "pipx")
+        };
+
+        ctx.set_step(Some(Step::Pipx));
+        assert_eq!(argv(&ctx.execute("apt-get")), ["/usr/bin/sudo", "apt-get"]);
+        assert!(refused(ctx.execute("pipx").arg("upgrade-all").status_checked()));
+        assert!(refused(ctx.execute("pipx").output().map(|_| ())));
+        assert!(refused(ctx.execute("pipx").spawn().map(|_| ())));
+
+        let sudo = ctx.sudo().as_ref().unwrap();
+        assert!(refused(sudo.execute(&ctx, "pipx").map(|_| ())));
+        ctx.set_step(Some(Step::Cargo));
+        assert!(refused(sudo.execute(&ctx, "pipx").map(|_| ())));
+        assert!(sudo.execute(&ctx, "apt-get").is_ok());
+        ctx.set_step(None);
+    }
+
+    /// Test that the commands elevated by `sudo` get the priority, but not the resource limits,
+    /// of the step.
+    #[test]
+    fn test_limited() {
+        let config = Config::from_toml(
+            "[misc]\nnice = 10\nionice_class = \"realtime\"\ncpu_quota = \"50%\"\nmemory_max = \"4G\"\n",
+        );
+        #[cfg(target_os = "linux")]
+        let distribution = Err(eyre!("No distribution"));
+        let ctx = ExecutionContext::new(
+            RunType::Wet,
+            None,
+            &config,
+            #[cfg(target_os = "linux")]
+            &distribution,
+        );
+
+        let mut priority = Vec::new();
+        if let Some(nice) = which("nice") {
+            priority.extend([nice.display().to_string(), "-n".to_string(), "10".to_string()]);
+        }
+        if let Some(ionice) = which("ionice") {
+            priority.extend([
+                ionice.display().to_string(),
+                "-t".to_string(),
+                "-c".to_string(),
+                "1".to_string(),
+            ]);
+        }
+
+        let mut limited = Vec::new();
+        if let Some(systemd_run) = systemd_run_user() {
+            limited.extend([systemd_run.display().to_string(), "--user".to_string()]);
+            limited.extend(["--scope", "--quiet", "-p", "CPUQuota=50%", "-p", "MemoryMax=4G", "--"].map(String::from));
+        }
+        limited.extend(priority.iter().cloned());
+        limited.push("cargo".to_string());
+        assert_eq!(argv(&ctx.limited("cargo")), limited);
+
+        priority.push("/usr/bin/sudo".to_string());
+        assert_eq!(
+            argv(&ctx.limited_sudo(Path::new("/usr/bin/sudo"), SudoKind::Sudo)),
+            priority
+        );
+    }
+
+    #[test]
+    fn test_unwrapped() {
+        let argv = |args: &[&str]| args.iter().map(ToString::to_string).collect::<Vec<_>>();
+
+        let limited = argv(&[
+            "/usr/bin/systemd-run",
+            "--user",
+            "--scope",
+            "--quiet",
+            "-p",
+            "CPUQuota=50%",
+            "--",
+            "/usr/bin/nice",
+            "-n",
+            "10",
+            "/usr/bin/ionice",
+            "-t",
+            "-c",
+            "3",
+            "cargo",
+            "install-update",
+        ]);
+        assert_eq!(unwrapped(&limited), argv(&["cargo", "install-update"]));
+
+        let sudo = argv(&["nice", "-n", "5", "/usr/bin/sudo", "apt-get", "upgrade"]);
+        assert_eq!(unwrapped(&sudo), argv(&["/usr/bin/sudo", "apt-get", "upgrade"]));
+
+        for command in [
+            argv(&["nice", "-n", "5"]),
+            argv(&["nice", "cargo"]),
+            argv(&["cargo", "--"]),
+        ] {
+            assert_eq!(unwrapped(&command), command);
+        }
+    }
+}
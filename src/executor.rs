@@ -1,24 +1,44 @@
 //! Utilities for command execution
+use std::cell::Cell;
 use std::ffi::{OsStr, OsString};
-use std::fmt::Debug;
-use std::iter;
+use std::io::{self, Read, Write};
+use std::ops::{Deref, DerefMut};
 use std::path::Path;
-use std::process::{Child, Command, ExitStatus, Output};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{eyre, Context, Report, Result};
 use rust_i18n::t;
 use tracing::{debug, enabled, Level};
 
-use crate::command::CommandExt;
-use crate::error::DryRun;
+use crate::audit;
+use crate::cassette;
+use crate::command::{format_program_and_args, get_program_and_args, CommandExt};
+use crate::error::{DryRun, PrivilegedNotAllowed, TopgradeError};
+use crate::events::{self, Event};
+use crate::script;
+use crate::sudo::SudoKind;
+use crate::terminal::{capture_output, capturing_output, clear_status_line, flush_step_output, tick_spinner};
+
+/// How often the spinner is redrawn while a command's output is captured.
+const SPINNER_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a command may stay silent after printing an incomplete line before we assume it is
+/// waiting for input and show its output.
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(1);
 
 /// An enum providing a similar interface to `std::process::Command`.
 /// If the enum is set to `Wet`, execution will be performed with `std::process::Command`.
 /// If the enum is set to `Dry`, execution will just print the command with its arguments.
+/// If the enum is set to `Refused`, execution fails with `PrivilegedNotAllowed`, as the command
+/// should have run with elevated privileges that the `[security]` section does not allow.
 pub enum Executor {
-    Wet(Command),
-    Damp(Command),
+    Wet(WetCommand),
+    Damp(WetCommand),
     Dry(DryCommand),
+    Refused(DryCommand),
 }
 
 impl Executor {
@@ -28,8 +48,17 @@ impl Executor {
     pub fn get_program(&self) -> String {
         match self {
             Executor::Wet(c) | Executor::Damp(c) => c.get_program().to_string_lossy().into_owned(),
-            Executor::Dry(c) => c.program.to_string_lossy().into_owned(),
+            Executor::Dry(c) | Executor::Refused(c) => c.program.to_string_lossy().into_owned(),
+        }
+    }
+
+    /// Record that the command is elevated by `sudo_kind`, for the audit log.
+    pub fn elevated_by(&mut self, sudo_kind: SudoKind) -> &mut Executor {
+        if let Executor::Wet(c) | Executor::Damp(c) = self {
+            c.sudo = Some(sudo_kind);
         }
+
+        self
     }
 
     /// See `std::process::Command::arg`
@@ -38,7 +67,7 @@ impl Executor {
             Executor::Wet(c) | Executor::Damp(c) => {
                 c.arg(arg);
             }
-            Executor::Dry(c) => {
+            Executor::Dry(c) | Executor::Refused(c) => {
                 c.args.push(arg.as_ref().into());
             }
         }
@@ -56,7 +85,7 @@ impl Executor {
             Executor::Wet(c) | Executor::Damp(c) => {
                 c.args(args);
             }
-            Executor::Dry(c) => {
+            Executor::Dry(c) | Executor::Refused(c) => {
                 c.args.extend(args.into_iter().map(|arg| arg.as_ref().into()));
             }
         }
@@ -71,7 +100,7 @@ impl Executor {
             Executor::Wet(c) | Executor::Damp(c) => {
                 c.current_dir(dir);
             }
-            Executor::Dry(c) => c.directory = Some(dir.as_ref().into()),
+            Executor::Dry(c) | Executor::Refused(c) => c.directory = Some(dir.as_ref().into()),
         }
 
         self
@@ -87,7 +116,7 @@ impl Executor {
             Executor::Wet(c) | Executor::Damp(c) => {
                 c.env_remove(key);
             }
-            Executor::Dry(_) => (),
+            Executor::Dry(c) | Executor::Refused(c) => c.env.push((key.as_ref().into(), None)),
         }
 
         self
@@ -104,7 +133,7 @@ impl Executor {
             Executor::Wet(c) | Executor::Damp(c) => {
                 c.env(key, val);
             }
-            Executor::Dry(_) => (),
+            Executor::Dry(c) | Executor::Refused(c) => c.env.push((key.as_ref().into(), Some(val.as_ref().into()))),
         }
 
         self
@@ -115,13 +144,28 @@ impl Executor {
         self.log_command();
         let result = match self {
             Executor::Wet(c) | Executor::Damp(c) => {
-                debug!("Running {:?}", c);
+                debug!("Running {:?}", c.command);
+                if cassette::replaying() {
+                    return Err(eyre!(
+                        "Cannot replay `{}`, which runs in the background",
+                        format_program_and_args(c)
+                    ));
+                }
                 // We should use `spawn()` here rather than `spawn_checked()` since
                 // their semantics and behaviors are different.
+                let running = audit::start(c, c.sudo);
                 #[allow(clippy::disallowed_methods)]
-                c.spawn().map(ExecutorChild::Wet)?
+                let child = c.spawn();
+                if let Some(running) = running {
+                    match child {
+                        Ok(_) => running.spawned(),
+                        Err(_) => running.finish(None),
+                    }
+                }
+                ExecutorChild::Wet(child?)
             }
             Executor::Dry(_) => ExecutorChild::Dry,
+            Executor::Refused(c) => return Err(c.refused()),
         };
 
         Ok(result)
@@ -133,11 +177,17 @@ impl Executor {
         match self {
             Executor::Wet(c) | Executor::Damp(c) => {
                 // We should use `output()` here rather than `output_checked()` since
-                // their semantics and behaviors are different.
-                #[allow(clippy::disallowed_methods)]
-                Ok(ExecutorOutput::Wet(c.output()?))
+                // their semantics and behaviors are different, through the cassette which
+                // records or replays it if there is one.
+                let output = audited(c, |c, code| {
+                    let output = cassette::output(c)?;
+                    code.set(output.status.code());
+                    Ok::<_, io::Error>(output)
+                })?;
+                Ok(ExecutorOutput::Wet(output))
             }
             Executor::Dry(_) => Ok(ExecutorOutput::Dry),
+            Executor::Refused(c) => Err(c.refused()),
         }
     }
 
@@ -147,7 +197,7 @@ impl Executor {
     pub fn status_checked_with_codes(&mut self, codes: &[i32]) -> Result<()> {
         self.log_command();
         match self {
-            Executor::Wet(c) | Executor::Damp(c) => c.status_checked_with(|status| {
+            Executor::Wet(c) | Executor::Damp(c) => status_checked_with(c, |status| {
                 if status.success() || status.code().as_ref().is_some_and(|c| codes.contains(c)) {
                     Ok(())
                 } else {
@@ -155,12 +205,38 @@ impl Executor {
                 }
             }),
             Executor::Dry(_) => Ok(()),
+            Executor::Refused(c) => Err(c.refused()),
+        }
+    }
+
+    /// Like `status_checked`, but the output is never captured, even in compact output mode.
+    ///
+    /// Use this for commands that are expected to interact with the user.
+    pub fn status_checked_interactive(&mut self) -> Result<()> {
+        self.log_command();
+        match self {
+            Executor::Wet(c) | Executor::Damp(c) => {
+                clear_status_line();
+                audited(c, |c, code| {
+                    c.status_checked_with(|status| {
+                        code.set(status.code());
+                        if status.success() {
+                            Ok(())
+                        } else {
+                            Err(())
+                        }
+                    })
+                })
+            }
+            Executor::Dry(_) => Ok(()),
+            Executor::Refused(c) => Err(c.refused()),
         }
     }
 
     fn log_command(&self) {
+        self.emit_spawned();
         match self {
-            Executor::Wet(_) => (),
+            Executor::Wet(_) | Executor::Refused(_) => (),
             Executor::Damp(c) => {
                 log_command(
                     "Executing: {program_name} {arguments}",
@@ -168,19 +244,83 @@ impl Executor {
                     c.get_args(),
                     c.get_envs(),
                     c.get_current_dir(),
+                    true,
                 );
             }
-            Executor::Dry(c) => log_command(
-                "Dry running: {program_name} {arguments}",
-                &c.program,
-                &c.args,
-                iter::empty(),
-                c.directory.as_ref(),
+            Executor::Dry(c) => {
+                log_command(
+                    "Dry running: {program_name} {arguments}",
+                    &c.program,
+                    &c.args,
+                    c.env(),
+                    c.directory.as_ref(),
+                    enabled!(Level::DEBUG),
+                );
+                script::push(
+                    &c.program,
+                    c.args.iter().map(OsString::as_os_str),
+                    c.env(),
+                    c.directory.as_deref().map(Path::new),
+                );
+            }
+        }
+    }
+
+    /// Emit the event of this command being run.
+    fn emit_spawned(&self) {
+        if !events::enabled() {
+            return;
+        }
+        let (program, args, directory, dry_run) = match self {
+            Executor::Refused(_) => return,
+            Executor::Wet(c) | Executor::Damp(c) => {
+                (c.get_program(), c.get_args().collect(), c.get_current_dir(), false)
+            }
+            Executor::Dry(c) => (
+                c.program.as_os_str(),
+                c.args.iter().map(OsString::as_os_str).collect::<Vec<_>>(),
+                c.directory.as_deref().map(Path::new),
+                true,
             ),
+        };
+        events::emit(Event::CommandSpawned {
+            program: program.to_string_lossy().into_owned(),
+            args: args.iter().map(|arg| arg.to_string_lossy().into_owned()).collect(),
+            directory: directory.map(|d| d.display().to_string()),
+            dry_run,
+        });
+    }
+}
+
+/// A command that is run, with the kind of `sudo` elevating it, if any, for the audit log.
+pub struct WetCommand {
+    command: Command,
+    sudo: Option<SudoKind>,
+}
+
+impl WetCommand {
+    pub fn new<S: AsRef<OsStr>>(program: S) -> Self {
+        Self {
+            command: Command::new(program),
+            sudo: None,
         }
     }
 }
 
+impl Deref for WetCommand {
+    type Target = Command;
+
+    fn deref(&self) -> &Command {
+        &self.command
+    }
+}
+
+impl DerefMut for WetCommand {
+    fn deref_mut(&mut self) -> &mut Command {
+        &mut self.command
+    }
+}
+
 pub enum ExecutorOutput {
     Wet(Output),
     Dry,
@@ -191,6 +331,8 @@ pub struct DryCommand {
     program: OsString,
     args: Vec<OsString>,
     directory: Option<OsString>,
+    /// The environment variables set, or removed if `None`.
+    env: Vec<(OsString, Option<OsString>)>,
 }
 
 impl DryCommand {
@@ -199,8 +341,19 @@ impl DryCommand {
             program: program.as_ref().to_os_string(),
             args: Vec::new(),
             directory: None,
+            env: Vec::new(),
         }
     }
+
+    /// The error of running this command, which was refused elevated privileges.
+    fn refused(&self) -> Report {
+        PrivilegedNotAllowed(self.program.to_string_lossy().into_owned()).into()
+    }
+
+    /// Like `std::process::Command::get_envs`.
+    fn env(&self) -> impl Iterator<Item = (&OsStr, Option<&OsStr>)> {
+        self.env.iter().map(|(key, value)| (key.as_os_str(), value.as_deref()))
+    }
 }
 
 /// The Result of spawn. Contains an actual `std::process::Child` if executed by a wet command.
@@ -220,16 +373,23 @@ impl CommandExt for Executor {
     fn output_checked_with(&mut self, succeeded: impl Fn(&Output) -> Result<(), ()>) -> Result<Output> {
         self.log_command();
         match self {
-            Executor::Wet(c) | Executor::Damp(c) => c.output_checked_with(succeeded),
+            Executor::Wet(c) | Executor::Damp(c) => audited(c, |c, code| {
+                c.output_checked_with(|output| {
+                    code.set(output.status.code());
+                    succeeded(output)
+                })
+            }),
             Executor::Dry(_) => Err(DryRun().into()),
+            Executor::Refused(c) => Err(c.refused()),
         }
     }
 
     fn status_checked_with(&mut self, succeeded: impl Fn(ExitStatus) -> Result<(), ()>) -> Result<()> {
         self.log_command();
         match self {
-            Executor::Wet(c) | Executor::Damp(c) => c.status_checked_with(succeeded),
+            Executor::Wet(c) | Executor::Damp(c) => status_checked_with(c, succeeded),
             Executor::Dry(_) => Ok(()),
+            Executor::Refused(c) => Err(c.refused()),
         }
     }
 
@@ -238,41 +398,187 @@ impl CommandExt for Executor {
     }
 }
 
-fn log_command<
-    'a,
-    I: ExactSizeIterator<Item = (&'a (impl Debug + 'a + ?Sized), Option<&'a (impl Debug + 'a + ?Sized)>)>,
->(
+/// Run `cmd` like `CommandExt::status_checked_with`, capturing its output in compact output mode.
+///
+/// The output is not captured with a cassette, which copies it itself.
+fn status_checked_with(cmd: &mut WetCommand, succeeded: impl Fn(ExitStatus) -> Result<(), ()>) -> Result<()> {
+    audited(cmd, |cmd, code| {
+        let succeeded = |status: ExitStatus| {
+            code.set(status.code());
+            succeeded(status)
+        };
+        if capturing_output() && !cassette::active() {
+            status_checked_captured(cmd, succeeded)
+        } else {
+            cmd.status_checked_with(succeeded)
+        }
+    })
+}
+
+/// Run `cmd` with `run`, which sets the exit code of the command, and write it to the audit log.
+fn audited<T>(cmd: &mut WetCommand, run: impl FnOnce(&mut Command, &Cell<Option<i32>>) -> T) -> T {
+    let running = audit::start(cmd, cmd.sudo);
+    let code = Cell::new(None);
+    let result = run(cmd, &code);
+    if let Some(running) = running {
+        running.finish(code.get());
+    }
+    result
+}
+
+/// Where the output of a captured command currently goes.
+struct CaptureState {
+    /// The command seems to wait for input, so its output is shown directly.
+    passthrough: bool,
+    /// The last output didn't end with a newline.
+    partial_line: bool,
+    last_output: Instant,
+}
+
+fn status_checked_captured(cmd: &mut Command, succeeded: impl Fn(ExitStatus) -> Result<(), ()>) -> Result<()> {
+    let command = format_program_and_args(cmd);
+    debug!("Executing command `{command}` with captured output");
+
+    // Captured commands are spawned with piped stdio, the output is read by `forward_output()`.
+    #[allow(clippy::disallowed_methods)]
+    let mut child = cmd
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to execute `{command}`"))?;
+
+    let state = Arc::new(Mutex::new(CaptureState {
+        passthrough: false,
+        partial_line: false,
+        last_output: Instant::now(),
+    }));
+    let mut readers = Vec::with_capacity(2);
+    if let Some(stdout) = child.stdout.take() {
+        readers.push(forward_output(stdout, io::stdout, Arc::clone(&state)));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        readers.push(forward_output(stderr, io::stderr, Arc::clone(&state)));
+    }
+
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        {
+            let mut state = state.lock().unwrap();
+            if !state.passthrough && state.partial_line && state.last_output.elapsed() >= PROMPT_TIMEOUT {
+                debug!("`{command}` seems to wait for input, showing its output");
+                flush_step_output();
+                state.passthrough = true;
+            }
+            if !state.passthrough {
+                tick_spinner();
+            }
+        }
+
+        thread::sleep(SPINNER_INTERVAL);
+    };
+
+    for reader in readers {
+        reader.join().ok();
+    }
+
+    if succeeded(status).is_ok() {
+        Ok(())
+    } else {
+        let (program, _) = get_program_and_args(cmd);
+        let err = TopgradeError::ProcessFailed(program, status);
+        let ret = Err(err).with_context(|| format!("Command failed: `{command}`"));
+        debug!("Command failed: {ret:?}");
+        ret
+    }
+}
+
+/// Read `source` until EOF, storing the output in the buffer of the current step, or writing it to
+/// `sink` once the command is in passthrough mode.
+fn forward_output<R, W>(mut source: R, sink: fn() -> W, state: Arc<Mutex<CaptureState>>) -> JoinHandle<()>
+where
+    R: Read + Send + 'static,
+    W: Write + 'static,
+{
+    thread::spawn(move || {
+        let mut buf = [0; 4096];
+        loop {
+            let n = match source.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            let output = &buf[..n];
+
+            let mut state = state.lock().unwrap();
+            if state.passthrough || !capture_output(output) {
+                let mut sink = sink();
+                sink.write_all(output).ok();
+                sink.flush().ok();
+            }
+            state.partial_line = output.last() != Some(&b'\n');
+            state.last_output = Instant::now();
+        }
+    })
+}
+
+/// Print `line`, or add it to the captured output of the current step in compact output mode.
+fn print_or_capture(line: String) {
+    if !capture_output(format!("{line}\n").as_bytes()) {
+        println!("{line}");
+    }
+}
+
+/// Print the command, and the environment variables it sets or removes if `show_env` is set.
+fn log_command<'a>(
     prefix: &str,
     exec: &OsStr,
     args: impl IntoIterator<Item = &'a (impl AsRef<OsStr> + ?Sized + 'a)>,
-    env: impl IntoIterator<Item = (&'a OsStr, Option<&'a OsStr>), IntoIter = I>,
+    env: impl IntoIterator<Item = (&'a OsStr, Option<&'a OsStr>)>,
     dir: Option<&'a (impl AsRef<Path> + ?Sized)>,
+    show_env: bool,
 ) {
-    println!(
-        "{}",
+    print_or_capture(
         t!(
             prefix,
             program_name = exec.to_string_lossy(),
             arguments = shell_words::join(args.into_iter().map(|s| s.as_ref().to_string_lossy()))
         )
+        .into_owned(),
     );
 
-    let env_iter = env.into_iter();
-    if env_iter.len() != 0 && enabled!(Level::DEBUG) {
-        println!(
-            "  {}",
-            t!(
-                "with env: {env}",
-                env = env_iter
-                    .filter(|(_, val)| val.is_some())
-                    .map(|(key, val)| format!("{:?}={:?}", key, val.unwrap()))
-                    .collect::<Vec<_>>()
-                    .join(" ")
-            )
-        )
+    if show_env {
+        let (set, removed): (Vec<_>, Vec<_>) = env.into_iter().partition(|(_, val)| val.is_some());
+        if !set.is_empty() {
+            print_or_capture(format!(
+                "  {}",
+                t!(
+                    "with env: {env}",
+                    env = set
+                        .iter()
+                        .map(|(key, val)| format!("{:?}={:?}", key, val.unwrap()))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                )
+            ))
+        }
+        if !removed.is_empty() {
+            print_or_capture(format!(
+                "  {}",
+                t!(
+                    "without env: {env}",
+                    env = removed
+                        .iter()
+                        .map(|(key, _)| key.to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                )
+            ))
+        }
     }
 
     if let Some(d) = dir {
-        println!("  {}", t!("in {directory}", directory = d.as_ref().display()));
+        print_or_capture(format!("  {}", t!("in {directory}", directory = d.as_ref().display())));
     }
 }
@@ -4,7 +4,7 @@ use std::env;
 use std::io;
 use std::path::PathBuf;
 use std::process::exit;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
 use crate::breaking_changes::{first_run_of_major_release, print_breaking_changes, should_skip, write_keep_file};
 use clap::CommandFactory;
@@ -20,8 +20,13 @@ use rust_i18n::{i18n, t};
 use std::sync::LazyLock;
 use tracing::debug;
 
-use self::config::{CommandLineArgs, Config};
-use self::error::StepFailed;
+use self::cassette::Cassette;
+use self::config::{CommandLineArgs, Config, ConfigCommand, SubCommand};
+use self::error::{ConfigError, ExitCode, StepFailed};
+use self::events::{Event, PromptKind, StepSummary};
+use self::execution_context::RunType;
+use self::metrics::{RunMetrics, StepMetrics};
+use self::notifications::RunSummary;
 use self::runner::StepResult;
 #[allow(clippy::wildcard_imports)]
 use self::steps::{remote::*, *};
@@ -30,14 +35,27 @@ use self::sudo::{Sudo, SudoCreateError, SudoKind};
 use self::terminal::*;
 use self::utils::{install_color_eyre, install_tracing, is_elevated, update_tracing};
 
+mod audit;
 mod breaking_changes;
+mod cassette;
 mod command;
 mod config;
+mod config_env;
+mod config_init;
+mod config_migration;
+mod config_provenance;
+mod config_schema;
+mod config_validation;
 mod ctrlc;
 mod error;
+mod events;
 mod execution_context;
 mod executor;
+mod metrics;
+mod notifications;
+mod packages;
 mod runner;
+mod script;
 #[cfg(windows)]
 mod self_renamer;
 #[cfg(feature = "self-update")]
@@ -59,7 +77,8 @@ pub(crate) static WINDOWS_DIRS: LazyLock<Windows> = LazyLock::new(|| Windows::ne
 i18n!("locales", fallback = "en");
 
 #[allow(clippy::too_many_lines)]
-fn run() -> Result<()> {
+fn run() -> Result<ExitCode> {
+    let started = Instant::now();
     install_color_eyre()?;
     ctrlc::set_handler();
 
@@ -85,13 +104,13 @@ fn run() -> Result<()> {
     if let Some(shell) = opt.gen_completion {
         let cmd = &mut CommandLineArgs::command();
         clap_complete::generate(shell, cmd, clap::crate_name!(), &mut io::stdout());
-        return Ok(());
+        return Ok(ExitCode::Success);
     }
 
     if opt.gen_manpage {
         let man = clap_mangen::Man::new(CommandLineArgs::command());
         man.render(&mut io::stdout())?;
-        return Ok(());
+        return Ok(ExitCode::Success);
     }
 
     for env in opt.env_variables() {
@@ -103,20 +122,89 @@ fn run() -> Result<()> {
 
     if opt.edit_config() {
         Config::edit()?;
-        return Ok(());
+        return Ok(ExitCode::Success);
     };
 
     if opt.show_config_reference() {
         print!("{}", config::EXAMPLE_CONFIG);
-        return Ok(());
+        return Ok(ExitCode::Success);
     }
 
-    let config = Config::load(opt)?;
+    if let Some(SubCommand::Config(ConfigCommand::Schema)) = opt.subcommand() {
+        println!("{}", serde_json::to_string_pretty(&config_schema::schema())?);
+        return Ok(ExitCode::Success);
+    }
+
+    if let Some(SubCommand::Config(ConfigCommand::Init { dry_run, force })) = opt.subcommand() {
+        config_init::run(&Config::main_file(&opt), *dry_run, *force)?;
+        return Ok(ExitCode::Success);
+    }
+
+    if let Some(SubCommand::Config(ConfigCommand::Migrate { dry_run, yes })) = opt.subcommand() {
+        if !config_migration::run(&Config::files(&opt)?, *dry_run, *yes)? {
+            print_info(t!("The configuration is up to date"));
+        }
+        return Ok(ExitCode::Success);
+    }
+
+    if let Some(SubCommand::Config(ConfigCommand::Validate)) = opt.subcommand() {
+        let validation = Config::validate(&opt)?;
+        for diagnostic in &validation.diagnostics {
+            println!("{diagnostic}");
+        }
+
+        return if validation.is_valid() {
+            print_info(t!("The configuration is valid"));
+            Ok(ExitCode::Success)
+        } else {
+            print_warning(t!(
+                "Found {count} errors in the configuration",
+                count = validation.diagnostics.len()
+            ));
+            Ok(ExitCode::ConfigError)
+        };
+    }
+
+    // The configuration may not be valid anymore after the breaking changes of a major release,
+    // so it is migrated before being loaded
+    if opt.subcommand().is_none() && !should_skip() && first_run_of_major_release()? {
+        config_migration::run(&Config::files(&opt)?, opt.dry_run(), false)?;
+    }
+
+    let config = Config::load(opt).wrap_err(ConfigError)?;
+
+    if let Some(SubCommand::Config(ConfigCommand::Show { effective })) = config.subcommand() {
+        if *effective {
+            print!("{}", config.show_effective()?);
+        } else {
+            for origin in config.sources() {
+                println!("{origin}");
+            }
+        }
+        return Ok(ExitCode::Success);
+    }
+    let notifiers = config.notifiers().wrap_err(ConfigError)?;
     // Update the logger with the full filter directives.
     update_tracing(&reload_handle, &config.tracing_filter_directives())?;
     set_title(config.set_title());
     display_time(config.display_time());
     set_desktop_notifications(config.notify_each_step());
+    set_output_mode(config.output_mode());
+    if let Some(target) = config.events() {
+        events::init(target)?;
+    }
+    match (config.run_type(), config.cassette()) {
+        (RunType::Record, Some(path)) => cassette::init(Cassette::record(path)?),
+        (RunType::Replay, Some(path)) => cassette::init(Cassette::replay(path)?),
+        _ => (),
+    }
+    if let Some(path) = config.emit_script() {
+        script::init(path)?;
+    }
+    events::emit(Event::RunStart {
+        version: crate_version!(),
+        dry_run: config.run_type().dry(),
+    });
 
     debug!("Version: {}", crate_version!());
     debug!("OS: {}", env!("TARGET"));
@@ -128,8 +216,8 @@ fn run() -> Result<()> {
     if config.run_in_tmux() && env::var("TOPGRADE_INSIDE_TMUX").is_err() {
         #[cfg(unix)]
         {
-            tmux::run_in_tmux(config.tmux_config()?)?;
-            return Ok(());
+            tmux::run_in_tmux(config.tmux_config().wrap_err(ConfigError)?)?;
+            return Ok(ExitCode::Success);
         }
     }
 
@@ -141,7 +229,7 @@ fn run() -> Result<()> {
             "Topgrade should not be run as root, it will run commands with sudo or equivalent where needed."
         ));
         if !prompt_yesno(&t!("Continue?"))? {
-            exit(1)
+            exit(ExitCode::Interrupted.into())
         }
     }
 
@@ -157,6 +245,10 @@ fn run() -> Result<()> {
         Err(e) => (None, Some(e)),
     };
 
+    if let Some(path) = config.audit_log() {
+        audit::init(&path, elevated)?;
+    }
+
     #[cfg(target_os = "linux")]
     let distribution = linux::Distribution::detect();
 
@@ -182,7 +274,7 @@ fn run() -> Result<()> {
         if prompt_yesno(&t!("Continue?"))? {
             write_keep_file()?;
         } else {
-            exit(1);
+            exit(ExitCode::Interrupted.into());
         }
     }
 
@@ -207,6 +299,7 @@ fn run() -> Result<()> {
         }
     }
 
+    let mut interrupted = false;
     for step in step::default_steps() {
         match step.run(&mut runner, &ctx) {
             Ok(()) => (),
@@ -217,6 +310,7 @@ fn run() -> Result<()> {
             {
                 println!();
                 debug!("Interrupted (possibly with 'q' during retry prompt). Printing summary.");
+                interrupted = true;
                 break;
             }
             Err(error) => return Err(error),
@@ -224,13 +318,12 @@ fn run() -> Result<()> {
     }
 
     let mut failed = false;
+    let mut skipped_missing_sudo = false;
 
     let report = runner.report();
     if !report.is_empty() {
         print_separator(t!("Summary"));
 
-        let mut skipped_missing_sudo = false;
-
         for (key, result) in report {
             if !failed && result.failed() {
                 failed = true;
@@ -241,6 +334,13 @@ fn run() -> Result<()> {
             print_result(key, result);
         }
 
+        if report.iter().any(|(key, _)| !runner.package_changes(key).is_empty()) {
+            print_info(t!("\nUpgraded:"));
+            for (key, _) in report {
+                print_package_changes(key, runner.package_changes(key));
+            }
+        }
+
         if skipped_missing_sudo {
             print_warning(t!(
                 "\nSome steps were skipped as sudo or equivalent could not be found."
@@ -274,11 +374,74 @@ fn run() -> Result<()> {
         }
     }
 
+    // The failures of other users already failed their `Users` step
+    for (user, steps) in ctx.take_user_reports() {
+        print_separator(t!("Summary for {user}", user = user));
+        for (key, result) in &steps {
+            print_result(key, result);
+        }
+    }
+
+    if config.print_privileged_plan() {
+        print_separator(t!("Privileged plan"));
+        let plan = ctx.take_privileged_plan();
+        if plan.is_empty() {
+            println!("{}", t!("No command would be run with elevated privileges"));
+        }
+        for command in plan {
+            println!("{command}");
+        }
+    }
+
+    let step_summaries = || {
+        report
+            .iter()
+            .map(|(key, result)| StepSummary {
+                key,
+                result,
+                upgraded: runner.package_changes(key),
+            })
+            .collect()
+    };
+    events::emit(Event::Summary {
+        steps: step_summaries(),
+        failed,
+    });
+
+    if let Some(path) = config.metrics_file() {
+        if !config.run_type().simulated() {
+            let metrics = RunMetrics {
+                finished_at: SystemTime::now(),
+                duration: started.elapsed(),
+                steps: report
+                    .iter()
+                    .map(|(key, result)| StepMetrics {
+                        key,
+                        result,
+                        duration: runner.duration(key),
+                    })
+                    .collect(),
+            };
+            if let Err(e) = metrics::write(&path, &metrics) {
+                print_warning(t!("Failed to write the metrics: {error}", error = format!("{e:?}")));
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    let reboot_status = match &distribution {
+        Ok(distribution) if config.show_distribution_summary() || config.keep_at_end() => distribution.reboot_status(),
+        _ => linux::RebootStatus::Unknown,
+    };
+
     #[cfg(target_os = "linux")]
     if config.show_distribution_summary() {
         if let Ok(distribution) = &distribution {
             distribution.show_summary();
         }
+        if let linux::RebootStatus::Required(required) = &reboot_status {
+            required.show();
+        }
     }
 
     if let Some(commands) = config.post_commands() {
@@ -290,14 +453,34 @@ fn run() -> Result<()> {
         }
     }
 
+    // Nothing was done in a dry run or a replay, so there is nothing to tell
+    if !config.skip_notify() && !config.run_type().simulated() && !notifiers.is_empty() {
+        notifications::notify(&notifiers, &RunSummary::new(step_summaries(), failed));
+    }
+
     if config.keep_at_end() {
-        print_info(t!("\n(R)eboot\n(S)hell\n(Q)uit"));
+        // Only leave out rebooting when we know that it is not needed
+        #[cfg(target_os = "linux")]
+        let suggest_reboot = !matches!(reboot_status, linux::RebootStatus::NotRequired);
+        #[cfg(not(target_os = "linux"))]
+        let suggest_reboot = true;
+
+        let prompt = if suggest_reboot {
+            t!("\n(R)eboot\n(S)hell\n(Q)uit")
+        } else {
+            t!("\n(S)hell\n(Q)uit")
+        };
+        print_info(&prompt);
+        events::emit(Event::PromptRequested {
+            kind: PromptKind::KeepAtEnd,
+            message: prompt.trim(),
+        });
         loop {
             match get_key() {
                 Ok(Key::Char('s' | 'S')) => {
                     run_shell().context("Failed to execute shell")?;
                 }
-                Ok(Key::Char('r' | 'R')) => {
+                Ok(Key::Char('r' | 'R')) if suggest_reboot => {
                     println!("{}", t!("Rebooting..."));
                     reboot(&ctx).context("Failed to reboot")?;
                 }
@@ -321,17 +504,21 @@ fn run() -> Result<()> {
         );
     }
 
-    if failed {
+    if interrupted {
+        Err(io::Error::from(io::ErrorKind::Interrupted).into())
+    } else if failed {
         Err(StepFailed.into())
+    } else if skipped_missing_sudo {
+        Ok(ExitCode::SkippedMissingSudo)
     } else {
-        Ok(())
+        Ok(ExitCode::Success)
     }
 }
 
 fn main() {
     match run() {
-        Ok(()) => {
-            exit(0);
+        Ok(code) => {
+            exit(code.into());
         }
         Err(error) => {
             let skip_print = (error.downcast_ref::<StepFailed>().is_some())
@@ -346,7 +533,7 @@ fn main() {
                 // `.with_context(...)` calls.
                 println!("{}", t!("Error: {error}", error = format!("{:?}", error)));
             }
-            exit(1);
+            exit(ExitCode::from_error(&error).into());
         }
     }
 }
@@ -0,0 +1,242 @@
+//! Metrics of a run for the Prometheus textfile collector
+//!
+//! The metrics are written in the Prometheus text format to a `.prom` file, which is replaced
+//! atomically so that the collector of `node_exporter` never reads a partially written file.
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use color_eyre::eyre::{Context, Result};
+
+use crate::runner::StepResult;
+
+/// The outcome of a step, as exported in the metrics.
+pub struct StepMetrics<'a> {
+    pub key: &'a str,
+    pub result: &'a StepResult,
+    pub duration: Duration,
+}
+
+/// The metrics of a whole run.
+pub struct RunMetrics<'a> {
+    pub finished_at: SystemTime,
+    pub duration: Duration,
+    pub steps: Vec<StepMetrics<'a>>,
+}
+
+const LAST_SUCCESS: &str = "topgrade_last_success_timestamp_seconds";
+
+impl RunMetrics<'_> {
+    fn failed(&self) -> usize {
+        self.steps.iter().filter(|step| step.result.failed()).count()
+    }
+
+    fn skipped(&self) -> usize {
+        self.steps
+            .iter()
+            .filter(|step| matches!(step.result, StepResult::Skipped(_) | StepResult::SkippedMissingSudo))
+            .count()
+    }
+
+    /// Render the metrics in the Prometheus text format.
+    ///
+    /// `last_success` is the time of the last run without failed steps, which is this run if
+    /// nothing failed.
+    fn render(&self, last_success: Option<f64>) -> String {
+        let finished_at = timestamp(self.finished_at);
+        let last_success = if self.failed() == 0 {
+            Some(finished_at)
+        } else {
+            last_success
+        };
+
+        let mut out = String::new();
+        let mut gauge = |name: &str, help: &str, values: &[(Option<&str>, f64)]| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} gauge");
+            for (step, value) in values {
+                match step {
+                    Some(step) => {
+                        let _ = writeln!(out, "{name}{{step=\"{}\"}} {value}", escape_label(step));
+                    }
+                    None => {
+                        let _ = writeln!(out, "{name} {value}");
+                    }
+                }
+            }
+        };
+
+        gauge(
+            "topgrade_last_run_timestamp_seconds",
+            "Unix time at which the last run of Topgrade finished.",
+            &[(None, finished_at)],
+        );
+        if let Some(last_success) = last_success {
+            gauge(
+                LAST_SUCCESS,
+                "Unix time at which the last run of Topgrade without failed steps finished.",
+                &[(None, last_success)],
+            );
+        }
+        gauge(
+            "topgrade_run_duration_seconds",
+            "Duration of the last run of Topgrade.",
+            &[(None, self.duration.as_secs_f64())],
+        );
+        gauge(
+            "topgrade_steps_failed",
+            "Number of steps that failed in the last run.",
+            &[(None, self.failed() as f64)],
+        );
+        gauge(
+            "topgrade_steps_skipped",
+            "Number of steps that were skipped in the last run.",
+            &[(None, self.skipped() as f64)],
+        );
+
+        let ran = || {
+            self.steps
+                .iter()
+                .filter(|step| !matches!(step.result, StepResult::Skipped(_) | StepResult::SkippedMissingSudo))
+        };
+        gauge(
+            "topgrade_step_success",
+            "Whether the step succeeded in the last run (1) or not (0).",
+            &ran()
+                .map(|step| {
+                    (
+                        Some(step.key),
+                        f64::from(u8::from(matches!(step.result, StepResult::Success))),
+                    )
+                })
+                .collect::<Vec<_>>(),
+        );
+        gauge(
+            "topgrade_step_duration_seconds",
+            "Duration of the step in the last run.",
+            &ran()
+                .map(|step| (Some(step.key), step.duration.as_secs_f64()))
+                .collect::<Vec<_>>(),
+        );
+
+        out
+    }
+}
+
+fn timestamp(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64()
+}
+
+/// Escape a label value as required by the Prometheus text format.
+fn escape_label(value: &str) -> String {
+    value.replace('\\', r"\\").replace('"', r#"\""#).replace('\n', r"\n")
+}
+
+/// Find the time of the last successful run in the previous metrics file, if any.
+fn previous_last_success(contents: &str) -> Option<f64> {
+    contents.lines().find_map(|line| {
+        let value = line.strip_prefix(LAST_SUCCESS)?.strip_prefix(' ')?;
+        value.trim().parse().ok()
+    })
+}
+
+/// Replace `path` with the metrics of the run.
+///
+/// The metrics are written to a temporary file in the same directory first, which is then
+/// renamed over `path`.
+pub fn write(path: &Path, metrics: &RunMetrics) -> Result<()> {
+    let last_success = fs::read_to_string(path).ok().as_deref().and_then(previous_last_success);
+    let contents = metrics.render(last_success);
+
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    // A leading dot and a different extension keep the collector from reading the temporary file
+    let temporary = path.with_file_name(format!(".{file_name}.{}.tmp", process::id()));
+    fs::write(&temporary, contents)
+        .with_context(|| format!("Failed to write the metrics to {}", temporary.display()))?;
+    if let Err(e) = fs::rename(&temporary, path) {
+        let _ = fs::remove_file(&temporary);
+        return Err(e).with_context(|| format!("Failed to replace the metrics file {}", path.display()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metrics(steps: Vec<StepMetrics>) -> RunMetrics {
+        RunMetrics {
+            finished_at: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            duration: Duration::from_millis(95_500),
+            steps,
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let skipped = StepResult::Skipped("Not installed".to_string());
+        let metrics = metrics(vec![
+            StepMetrics {
+                key: "System update",
+                result: &StepResult::Success,
+                duration: Duration::from_secs(90),
+            },
+            StepMetrics {
+                key: "My \"custom\" step",
+                result: &StepResult::Failure,
+                duration: Duration::from_millis(1500),
+            },
+            StepMetrics {
+                key: "Flatpak",
+                result: &skipped,
+                duration: Duration::ZERO,
+            },
+        ]);
+        let rendered = metrics.render(Some(1_690_000_000.0));
+
+        assert!(rendered.contains("topgrade_last_run_timestamp_seconds 1700000000\n"));
+        assert!(rendered.contains("topgrade_last_success_timestamp_seconds 1690000000\n"));
+        assert!(rendered.contains("topgrade_run_duration_seconds 95.5\n"));
+        assert!(rendered.contains("topgrade_steps_failed 1\n"));
+        assert!(rendered.contains("topgrade_steps_skipped 1\n"));
+        assert!(rendered.contains("topgrade_step_success{step=\"System update\"} 1\n"));
+        assert!(rendered.contains("topgrade_step_success{step=\"My \\\"custom\\\" step\"} 0\n"));
+        assert!(rendered.contains("topgrade_step_duration_seconds{step=\"My \\\"custom\\\" step\"} 1.5\n"));
+        assert!(!rendered.contains("Flatpak"));
+    }
+
+    #[test]
+    fn test_write_keeps_last_success() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("topgrade.prom");
+
+        write(
+            &path,
+            &metrics(vec![StepMetrics {
+                key: "Cargo",
+                result: &StepResult::Success,
+                duration: Duration::from_secs(3),
+            }]),
+        )
+        .unwrap();
+
+        let mut failed = metrics(vec![StepMetrics {
+            key: "Cargo",
+            result: &StepResult::Failure,
+            duration: Duration::from_secs(3),
+        }]);
+        failed.finished_at += Duration::from_secs(3600);
+        write(&path, &failed).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(previous_last_success(&contents), Some(1_700_000_000.0));
+        assert!(contents.contains("topgrade_last_run_timestamp_seconds 1700003600\n"));
+        assert_eq!(fs::read_dir(directory.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,437 @@
+//! Notifications sent at the end of a run to remote services
+//!
+//! Unlike desktop notifications, these also work on headless machines. HTTP requests are sent
+//! with `curl`, and emails with `sendmail`.
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use color_eyre::eyre::{eyre, Context, Result};
+use rust_i18n::t;
+use serde::Serialize;
+
+use crate::command::CommandExt;
+use crate::config::NotifyOn;
+use crate::events::StepSummary;
+use crate::runner::StepResult;
+use crate::terminal::print_warning;
+use crate::utils::{hostname, require};
+
+/// Where a notification is sent.
+#[derive(Debug)]
+pub enum Backend {
+    /// POST the summary of the run as JSON.
+    Webhook { url: String },
+    /// Publish to a ntfy topic.
+    Ntfy { url: String, token: Option<String> },
+    /// Push a message to a Gotify server.
+    Gotify { url: String, token: String },
+    /// Send an email through the local `sendmail`.
+    Sendmail { to: String, command: Option<String> },
+}
+
+#[derive(Debug)]
+pub struct Notifier {
+    pub backend: Backend,
+    pub notify_on: NotifyOn,
+}
+
+/// The payload of webhooks.
+#[derive(Debug, Serialize)]
+pub struct RunSummary<'a> {
+    pub hostname: String,
+    pub failed: bool,
+    pub title: String,
+    pub message: String,
+    pub steps: Vec<StepSummary<'a>>,
+}
+
+impl<'a> RunSummary<'a> {
+    pub fn new(steps: Vec<StepSummary<'a>>, failed: bool) -> Self {
+        let hostname = hostname().unwrap_or_default();
+        let title = if failed {
+            t!("Topgrade finished with errors")
+        } else {
+            t!("Topgrade finished successfully")
+        };
+        let title = if hostname.is_empty() {
+            title.to_string()
+        } else {
+            format!("{title} ({hostname})")
+        };
+        let message = steps
+            .iter()
+            .map(|step| format!("{}: {}", step.key, result_text(step.result)))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        Self {
+            hostname,
+            failed,
+            title,
+            message,
+            steps,
+        }
+    }
+}
+
+fn result_text(result: &StepResult) -> String {
+    match result {
+        StepResult::Success => t!("OK").to_string(),
+        StepResult::Failure => t!("FAILED").to_string(),
+        StepResult::Ignored => t!("IGNORED").to_string(),
+        StepResult::SkippedMissingSudo => format!("{}: {}", t!("SKIPPED"), t!("Could not find sudo")),
+        StepResult::Skipped(reason) => format!("{}: {}", t!("SKIPPED"), reason),
+    }
+}
+
+/// Send `summary` with every notifier whose policy matches the outcome of the run.
+///
+/// Failing to send a notification only prints a warning.
+pub fn notify(notifiers: &[Notifier], summary: &RunSummary) {
+    for notifier in notifiers {
+        if notifier.notify_on == NotifyOn::Failure && !summary.failed {
+            continue;
+        }
+
+        if let Err(e) = notifier.backend.send(summary) {
+            print_warning(t!("Failed to send a notification: {error}", error = format!("{e:?}")));
+        }
+    }
+}
+
+impl Backend {
+    fn send(&self, summary: &RunSummary) -> Result<()> {
+        match self {
+            Backend::Webhook { url } => post(
+                url,
+                &["Content-Type: application/json".to_string()],
+                &serde_json::to_string(summary)?,
+            ),
+            Backend::Ntfy { url, token } => {
+                let mut headers = vec![
+                    format!("Title: {}", summary.title),
+                    format!("Tags: {}", if summary.failed { "warning" } else { "white_check_mark" }),
+                ];
+                if summary.failed {
+                    headers.push("Priority: high".to_string());
+                }
+                if let Some(token) = token {
+                    headers.push(format!("Authorization: Bearer {token}"));
+                }
+                post(url, &headers, &summary.message)
+            }
+            Backend::Gotify { url, token } => {
+                let message = serde_json::json!({
+                    "title": summary.title,
+                    "message": summary.message,
+                    "priority": if summary.failed { 8 } else { 4 },
+                });
+                post(
+                    &format!("{}/message", url.trim_end_matches('/')),
+                    &[
+                        "Content-Type: application/json".to_string(),
+                        format!("X-Gotify-Key: {token}"),
+                    ],
+                    &message.to_string(),
+                )
+            }
+            Backend::Sendmail { to, command } => sendmail(command.as_deref().unwrap_or("sendmail"), to, summary),
+        }
+    }
+}
+
+/// POST `body` to `url` with `curl`.
+///
+/// The URL, the headers and the body are passed in a config file on the standard input rather
+/// than as arguments, where other users could read tokens through `ps`.
+fn post(url: &str, headers: &[String], body: &str) -> Result<()> {
+    let curl = require("curl")?;
+    let mut config = format!("url = {}\n", curl_string(url));
+    for header in headers {
+        config.push_str(&format!("header = {}\n", curl_string(header)));
+    }
+    // `data-raw` does not treat a leading `@` as a file name, unlike `data-binary`
+    config.push_str(&format!("data-raw = {}\n", curl_string(body)));
+
+    let mut child = Command::new(curl)
+        .args([
+            "--silent",
+            "--show-error",
+            "--fail",
+            "--max-time",
+            "30",
+            "--config",
+            "-",
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn_checked()?;
+    // Take `stdin` so that it is closed before waiting, otherwise curl never reads the end of it.
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(config.as_bytes())
+            .wrap_err("Failed to write the request to curl")?;
+    }
+
+    let output = child.wait_with_output()?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(eyre!(
+            "curl failed: {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+/// `s` quoted for a curl config file.
+fn curl_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Pipe an email to `sendmail -t`, which reads the recipients from the headers.
+fn sendmail(sendmail: &str, to: &str, summary: &RunSummary) -> Result<()> {
+    let sendmail = require(sendmail)?;
+    let mut child = Command::new(sendmail).arg("-t").stdin(Stdio::piped()).spawn_checked()?;
+
+    let email = format!(
+        "To: {to}\nSubject: {}\nContent-Type: text/plain; charset=utf-8\n\n{}\n",
+        summary.title, summary.message
+    );
+    // Take `stdin` so that it is closed before waiting, otherwise sendmail never exits.
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(email.as_bytes())
+            .wrap_err("Failed to write the email to sendmail")?;
+    }
+
+    let status = child.wait()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(eyre!("sendmail failed: {status}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+    use crate::packages::PackageChange;
+    use crate::utils::which;
+
+    /// Accept a single request, answer it with `200 OK`, and return its headers and body.
+    fn serve_once(listener: TcpListener) -> thread::JoinHandle<(Vec<String>, String)> {
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+
+            let mut headers = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end().to_string();
+                if line.is_empty() {
+                    break;
+                }
+                headers.push(line);
+            }
+
+            let length = headers
+                .iter()
+                .find_map(|h| h.to_lowercase().strip_prefix("content-length: ").map(str::to_string))
+                .map_or(0, |l| l.parse().unwrap());
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                .unwrap();
+
+            (headers, String::from_utf8(body).unwrap())
+        })
+    }
+
+    /// Fail rather than silently pass the tests needing `curl` when it is missing.
+    fn require_curl() {
+        assert!(which("curl").is_some(), "curl is required to test the notifications");
+    }
+
+    #[test]
+    fn test_webhook() {
+        require_curl();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = serve_once(listener);
+
+        let upgraded = [PackageChange {
+            name: "ripgrep".to_string(),
+            from: "14.1.0".to_string(),
+            to: "14.1.1".to_string(),
+        }];
+        let summary = RunSummary::new(
+            vec![
+                StepSummary {
+                    key: "Cargo",
+                    result: &StepResult::Success,
+                    upgraded: &upgraded,
+                },
+                StepSummary {
+                    key: "System update",
+                    result: &StepResult::Failure,
+                    upgraded: &[],
+                },
+            ],
+            true,
+        );
+        Backend::Webhook { url }.send(&summary).unwrap();
+
+        let (headers, body) = server.join().unwrap();
+        assert_eq!(headers[0], "POST /hook HTTP/1.1");
+        assert!(headers.iter().any(|h| h == "Content-Type: application/json"));
+
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["failed"], true);
+        assert_eq!(body["steps"][0]["key"], "Cargo");
+        assert_eq!(body["steps"][0]["result"]["status"], "success");
+        assert_eq!(body["steps"][0]["upgraded"][0]["to"], "14.1.1");
+        assert_eq!(body["steps"][1]["result"]["status"], "failure");
+    }
+
+    #[test]
+    fn test_ntfy() {
+        require_curl();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/topgrade", listener.local_addr().unwrap());
+        let server = serve_once(listener);
+
+        let summary = RunSummary::new(
+            vec![StepSummary {
+                key: "@ Flatpak",
+                result: &StepResult::Success,
+                upgraded: &[],
+            }],
+            false,
+        );
+        Backend::Ntfy {
+            url,
+            token: Some("tk_secret".to_string()),
+        }
+        .send(&summary)
+        .unwrap();
+
+        let (headers, body) = server.join().unwrap();
+        assert_eq!(headers[0], "POST /topgrade HTTP/1.1");
+        assert!(headers.iter().any(|h| h == "Authorization: Bearer tk_secret"));
+        assert!(headers.iter().any(|h| h == "Tags: white_check_mark"));
+        assert_eq!(body, "@ Flatpak: OK");
+    }
+
+    #[test]
+    fn test_gotify() {
+        require_curl();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let server = serve_once(listener);
+
+        let summary = RunSummary::new(
+            vec![
+                StepSummary {
+                    key: "Cargo",
+                    result: &StepResult::Success,
+                    upgraded: &[],
+                },
+                StepSummary {
+                    key: r#"Custom "backup""#,
+                    result: &StepResult::Failure,
+                    upgraded: &[],
+                },
+            ],
+            true,
+        );
+        Backend::Gotify {
+            url,
+            token: "A\\secret".to_string(),
+        }
+        .send(&summary)
+        .unwrap();
+
+        let (headers, body) = server.join().unwrap();
+        assert_eq!(headers[0], "POST /message HTTP/1.1");
+        assert!(headers.iter().any(|h| h == r"X-Gotify-Key: A\secret"));
+        assert!(headers.iter().any(|h| h == "Content-Type: application/json"));
+
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["title"], summary.title);
+        assert_eq!(body["message"], "Cargo: OK\nCustom \"backup\": FAILED");
+        assert_eq!(body["priority"], 8);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_sendmail() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let email = dir.path().join("email");
+        let sendmail = dir.path().join("sendmail");
+        std::fs::write(
+            &sendmail,
+            format!("#!/bin/sh\necho \"$@\" > '{0}.args'\ncat > '{0}'\n", email.display()),
+        )
+        .unwrap();
+        std::fs::set_permissions(&sendmail, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let summary = RunSummary::new(
+            vec![StepSummary {
+                key: "Cargo",
+                result: &StepResult::Success,
+                upgraded: &[],
+            }],
+            false,
+        );
+        Backend::Sendmail {
+            to: "admin@example.com".to_string(),
+            command: Some(sendmail.display().to_string()),
+        }
+        .send(&summary)
+        .unwrap();
+
+        assert_eq!(std::fs::read_to_string(dir.path().join("email.args")).unwrap(), "-t\n");
+        assert_eq!(
+            std::fs::read_to_string(&email).unwrap(),
+            format!(
+                "To: admin@example.com\nSubject: {}\nContent-Type: text/plain; charset=utf-8\n\nCargo: OK\n",
+                summary.title
+            )
+        );
+    }
+
+    #[test]
+    fn test_curl_string() {
+        assert_eq!(curl_string("Title: done"), r#""Title: done""#);
+        assert_eq!(curl_string("a \"b\"\\c\nd"), r#""a \"b\"\\c\nd""#);
+    }
+}
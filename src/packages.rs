@@ -0,0 +1,267 @@
+//! Tracking of the package versions changed by a step
+//!
+//! Steps list the installed packages of their package manager before and after upgrading, and
+//! record the difference in the `ExecutionContext`, so that the summary can show what was upgraded.
+use std::collections::{BTreeMap, BTreeSet};
+use std::process::Command;
+
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::command::CommandExt;
+use crate::execution_context::ExecutionContext;
+
+/// Installed packages, mapped to their versions.
+pub type Versions = BTreeMap<String, String>;
+
+/// A package whose version was changed by a step.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PackageChange {
+    pub name: String,
+    pub from: String,
+    pub to: String,
+}
+
+/// Lists the installed packages before a step upgrades them, to find out what changed afterward.
+pub struct PackageTracker<F: Fn() -> Result<Versions>> {
+    list: F,
+    before: Option<Versions>,
+}
+
+impl<F: Fn() -> Result<Versions>> PackageTracker<F> {
+    /// List the installed packages with `list`. Nothing is listed in dry runs, nor when replaying.
+    pub fn start(ctx: &ExecutionContext, list: F) -> Self {
+        let before = if ctx.run_type().simulated() {
+            None
+        } else {
+            list().inspect_err(|e| debug!("Failed to list packages: {e:?}")).ok()
+        };
+
+        Self { list, before }
+    }
+
+    /// List the installed packages again, and record the versions that changed in `ctx`.
+    pub fn finish(self, ctx: &ExecutionContext) {
+        let Some(before) = self.before else {
+            return;
+        };
+
+        match (self.list)() {
+            Ok(after) => ctx.record_package_changes(diff(&before, &after)),
+            Err(e) => debug!("Failed to list packages: {e:?}"),
+        }
+    }
+}
+
+/// Run `command`, and parse its output with `parse`.
+pub fn list(command: &mut Command, parse: fn(&str) -> Result<Versions>) -> Result<Versions> {
+    parse(&command.output_checked_utf8()?.stdout)
+}
+
+/// The packages present both in `before` and `after` with a different version.
+pub fn diff(before: &Versions, after: &Versions) -> Vec<PackageChange> {
+    after
+        .iter()
+        .filter_map(|(name, to)| {
+            let from = before.get(name)?;
+            (from != to).then(|| PackageChange {
+                name: name.clone(),
+                from: from.clone(),
+                to: to.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Collect `(name, version)` pairs, joining the versions of packages installed more than once.
+fn collect<N: Into<String>, V: Into<String>>(packages: impl IntoIterator<Item = (N, V)>) -> Versions {
+    let mut versions: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for (name, version) in packages {
+        versions.entry(name.into()).or_default().insert(version.into());
+    }
+
+    versions
+        .into_iter()
+        .map(|(name, versions)| (name, versions.into_iter().collect::<Vec<_>>().join(", ")))
+        .collect()
+}
+
+/// Parse lines made of a package name followed by its versions, separated by whitespace.
+///
+/// This is the output of `dpkg-query`, `pacman -Q`, `rpm -qa` (with a suitable `--queryformat`)
+/// and `brew list --versions`. Lines without a version are ignored.
+pub fn parse_name_version(output: &str) -> Result<Versions> {
+    Ok(collect(output.lines().filter_map(|line| {
+        let mut words = line.split_whitespace();
+        let name = words.next()?;
+        let version = words.collect::<Vec<_>>().join(" ");
+        (!version.is_empty()).then_some((name, version))
+    })))
+}
+
+/// Parse the output of `flatpak list --columns=application,version,active`.
+///
+/// The commit is used as the version of the refs without one, which is common for runtimes.
+pub fn parse_flatpak(output: &str) -> Result<Versions> {
+    Ok(collect(output.lines().filter_map(|line| {
+        let mut columns = line.split('\t').map(str::trim);
+        let application = columns.next().filter(|a| !a.is_empty())?;
+        let version = columns.next().unwrap_or_default();
+        let commit = columns.next().unwrap_or_default();
+        let version = if version.is_empty() { commit } else { version };
+        (!version.is_empty()).then_some((application, version))
+    })))
+}
+
+/// Parse the output of `npm ls --global --depth=0 --json`.
+pub fn parse_npm(output: &str) -> Result<Versions> {
+    #[derive(Deserialize)]
+    struct List {
+        #[serde(default)]
+        dependencies: BTreeMap<String, Package>,
+    }
+
+    #[derive(Deserialize)]
+    struct Package {
+        version: Option<String>,
+    }
+
+    let list: List = serde_json::from_str(output)?;
+    Ok(collect(
+        list.dependencies
+            .into_iter()
+            .filter_map(|(name, package)| Some((name, package.version?))),
+    ))
+}
+
+/// Parse the output of `pipx list --json`.
+pub fn parse_pipx(output: &str) -> Result<Versions> {
+    #[derive(Deserialize)]
+    struct List {
+        venvs: BTreeMap<String, Venv>,
+    }
+
+    #[derive(Deserialize)]
+    struct Venv {
+        metadata: Metadata,
+    }
+
+    #[derive(Deserialize)]
+    struct Metadata {
+        main_package: Package,
+    }
+
+    #[derive(Deserialize)]
+    struct Package {
+        package_version: String,
+    }
+
+    let list: List = serde_json::from_str(output)?;
+    Ok(collect(list.venvs.into_iter().map(|(name, venv)| {
+        (name, venv.metadata.main_package.package_version)
+    })))
+}
+
+/// Parse the `.crates.toml` file where cargo records the installed crates.
+pub fn parse_crates_toml(contents: &str) -> Result<Versions> {
+    #[derive(Deserialize)]
+    struct CratesToml {
+        #[serde(default)]
+        v1: BTreeMap<String, toml::Value>,
+    }
+
+    let crates: CratesToml = toml::from_str(contents)?;
+    // The keys look like `ripgrep 14.1.1 (registry+https://github.com/rust-lang/crates.io-index)`
+    Ok(collect(crates.v1.keys().filter_map(|key| {
+        let mut words = key.split_whitespace();
+        Some((words.next()?, words.next()?))
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn versions(packages: &[(&str, &str)]) -> Versions {
+        packages
+            .iter()
+            .map(|(name, version)| (name.to_string(), version.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_diff() {
+        let before = versions(&[("bash", "5.2.21-2"), ("curl", "8.5.0-1"), ("removed", "1.0")]);
+        let after = versions(&[("bash", "5.2.26-1"), ("curl", "8.5.0-1"), ("installed", "2.0")]);
+
+        assert_eq!(
+            diff(&before, &after),
+            vec![PackageChange {
+                name: "bash".to_string(),
+                from: "5.2.21-2".to_string(),
+                to: "5.2.26-1".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_name_version() {
+        let output = "bash 5.2.26-1\nkernel 6.8.5-301.fc40\nkernel 6.7.12-200.fc40\nlibfoo\nnode 21.7.3 20.11.1\n";
+
+        assert_eq!(
+            parse_name_version(output).unwrap(),
+            versions(&[
+                ("bash", "5.2.26-1"),
+                ("kernel", "6.7.12-200.fc40, 6.8.5-301.fc40"),
+                ("node", "21.7.3 20.11.1"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_flatpak() {
+        let output =
+            "org.mozilla.firefox\t125.0.1\t6bd71b9a8a86\norg.freedesktop.Platform.GL.default\t\t1f5a6ac0fd2c\n";
+
+        assert_eq!(
+            parse_flatpak(output).unwrap(),
+            versions(&[
+                ("org.freedesktop.Platform.GL.default", "1f5a6ac0fd2c"),
+                ("org.mozilla.firefox", "125.0.1"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_npm() {
+        let output = r#"{"name": "lib", "dependencies": {"npm": {"version": "10.5.2", "overridden": false}, "typescript": {"version": "5.4.5"}}}"#;
+
+        assert_eq!(
+            parse_npm(output).unwrap(),
+            versions(&[("npm", "10.5.2"), ("typescript", "5.4.5")])
+        );
+        assert_eq!(parse_npm("{}").unwrap(), Versions::new());
+    }
+
+    #[test]
+    fn test_parse_pipx() {
+        let output = r#"{"pipx_spec_version": "0.1", "venvs": {"black": {"metadata": {"main_package": {"package": "black", "package_version": "24.4.0"}}}}}"#;
+
+        assert_eq!(parse_pipx(output).unwrap(), versions(&[("black", "24.4.0")]));
+    }
+
+    #[test]
+    fn test_parse_crates_toml() {
+        let contents = r#"
+[v1]
+"cargo-update 13.4.0 (registry+https://github.com/rust-lang/crates.io-index)" = ["cargo-install-update", "cargo-install-update-config"]
+"ripgrep 14.1.0 (registry+https://github.com/rust-lang/crates.io-index)" = ["rg"]
+"#;
+
+        assert_eq!(
+            parse_crates_toml(contents).unwrap(),
+            versions(&[("cargo-update", "13.4.0"), ("ripgrep", "14.1.0")])
+        );
+    }
+}
@@ -1,16 +1,24 @@
+use clap::ValueEnum;
 use color_eyre::eyre::{Result, WrapErr};
 use rust_i18n::t;
+use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use std::fmt::Debug;
 use std::io;
+use std::time::{Duration, Instant};
 use tracing::debug;
 
 use crate::ctrlc;
 use crate::error::{DryRun, MissingSudo, SkipStep};
+use crate::events::{self, Event};
 use crate::execution_context::ExecutionContext;
+use crate::packages::PackageChange;
+use crate::script;
 use crate::step::Step;
-use crate::terminal::{print_error, print_warning, should_retry, ShouldRetry};
+use crate::terminal::{finish_step, print_error, print_warning, should_retry, start_step, ShouldRetry};
 
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "status", content = "reason", rename_all = "snake_case")]
 pub enum StepResult {
     Success,
     Failure,
@@ -32,9 +40,15 @@ impl StepResult {
 
 type Report<'a> = Vec<(Cow<'a, str>, StepResult)>;
 
+type PackageChanges<'a> = Vec<(Cow<'a, str>, Vec<PackageChange>)>;
+
+type Durations<'a> = Vec<(Cow<'a, str>, Duration)>;
+
 pub struct Runner<'a> {
     ctx: &'a ExecutionContext<'a>,
     report: Report<'a>,
+    package_changes: PackageChanges<'a>,
+    durations: Durations<'a>,
 }
 
 impl<'a> Runner<'a> {
@@ -42,6 +56,8 @@ impl<'a> Runner<'a> {
         Runner {
             ctx,
             report: Vec::new(),
+            package_changes: Vec::new(),
+            durations: Vec::new(),
         }
     }
 
@@ -61,6 +77,14 @@ impl<'a> Runner<'a> {
 
         let key: Cow<'a, str> = key.into();
         debug!("Step {:?}", key);
+        start_step(&key);
+        events::emit(Event::StepStart { key: &key });
+        script::start_step(&key);
+        let status_key = key.clone();
+        let started = Instant::now();
+        // Drop the changes recorded outside of any step, e.g. by the pre commands
+        self.ctx.take_package_changes();
+        self.ctx.set_step(Some(step));
 
         // alter the `func` to put it in a span
         let func = || {
@@ -73,6 +97,14 @@ impl<'a> Runner<'a> {
         loop {
             match func() {
                 Ok(()) => {
+                    if self.ctx.extra_args_unused() {
+                        print_warning(t!(
+                            "The {step} step does not support extra_args, they were not used",
+                            step = step
+                                .to_possible_value()
+                                .map_or_else(String::new, |v| v.get_name().to_string())
+                        ));
+                    }
                     self.push_result(key, StepResult::Success);
                     break;
                 }
@@ -99,7 +131,13 @@ impl<'a> Runner<'a> {
                     let should_ask = interrupted || !(self.ctx.config().no_retry() || ignore_failure);
                     let should_retry = if should_ask {
                         print_error(&key, format!("{e:?}"));
-                        should_retry(key.as_ref())?
+                        match should_retry(key.as_ref()) {
+                            Ok(should_retry) => should_retry,
+                            Err(e) => {
+                                self.finish_step(status_key, started);
+                                return Err(e);
+                            }
+                        }
                     } else {
                         ShouldRetry::No
                     };
@@ -115,6 +153,7 @@ impl<'a> Runner<'a> {
                                 },
                             );
                             if let ShouldRetry::Quit = should_retry {
+                                self.finish_step(status_key, started);
                                 return Err(io::Error::from(io::ErrorKind::Interrupted))
                                     .context("Quit from user input");
                             }
@@ -126,10 +165,46 @@ impl<'a> Runner<'a> {
             }
         }
 
+        self.finish_step(status_key, started);
         Ok(())
     }
 
+    /// Print the status line of the step `key` in compact output mode, store the package versions
+    /// it changed and its duration, and emit its result.
+    fn finish_step(&mut self, key: Cow<'a, str>, started: Instant) {
+        self.ctx.set_step(None);
+        self.durations.push((key.clone(), started.elapsed()));
+        let changes = self.ctx.take_package_changes();
+        if !changes.is_empty() {
+            self.package_changes.push((key.clone(), changes));
+        }
+
+        let result = self.report.last().filter(|(k, _)| k == &key).map(|(_, result)| result);
+        finish_step(&key, result);
+        events::emit(Event::StepFinish {
+            key: &key,
+            result,
+            upgraded: self.package_changes(&key),
+        });
+    }
+
     pub fn report(&self) -> &Report<'_> {
         &self.report
     }
+
+    /// The package versions changed by the step `key`.
+    pub fn package_changes(&self, key: &str) -> &[PackageChange] {
+        self.package_changes
+            .iter()
+            .find(|(k, _)| k == key)
+            .map_or(&[], |(_, changes)| changes)
+    }
+
+    /// How long the step `key` took, retries included.
+    pub fn duration(&self, key: &str) -> Duration {
+        self.durations
+            .iter()
+            .find(|(k, _)| k == key)
+            .map_or(Duration::ZERO, |(_, duration)| *duration)
+    }
 }
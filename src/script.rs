@@ -0,0 +1,138 @@
+//! Shell script of a dry run, for `--dry-run --emit-script FILE`
+//!
+//! Every command of the dry run is written to a POSIX shell script, in order and under a comment
+//! naming its step, so that it can be reviewed and run by hand, e.g. on an air-gapped machine.
+//! Work that steps do without running commands, such as pulling git repositories, is left out.
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::sync::{LazyLock, Mutex};
+
+use color_eyre::eyre::{Context, Result};
+use tracing::error;
+
+static SCRIPT: LazyLock<Mutex<Option<Script>>> = LazyLock::new(|| Mutex::new(None));
+
+struct Script {
+    writer: LineWriter<File>,
+    /// The step running the commands, whose comment is written before its first command.
+    step: Option<String>,
+}
+
+/// Start writing the commands to the script at `path`.
+pub fn init(path: &Path) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("Failed to create the script {}", path.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o755))
+            .with_context(|| format!("Failed to make the script {} executable", path.display()))?;
+    }
+
+    let mut writer = LineWriter::new(file);
+    writeln!(
+        writer,
+        "#!/bin/sh\n# The commands of a dry run of Topgrade {}, grouped by step.",
+        clap::crate_version!()
+    )
+    .with_context(|| format!("Failed to write the script {}", path.display()))?;
+    *SCRIPT.lock().unwrap() = Some(Script { writer, step: None });
+
+    Ok(())
+}
+
+/// Set the step running the next commands.
+pub fn start_step(key: &str) {
+    if let Some(script) = SCRIPT.lock().unwrap().as_mut() {
+        script.step = Some(key.to_string());
+    }
+}
+
+/// Write a command to the script, if there is one.
+///
+/// Failing to write the script is reported, and the commands are not written from then on.
+pub fn push<'a>(
+    program: &'a OsStr,
+    args: impl IntoIterator<Item = &'a OsStr>,
+    env: impl IntoIterator<Item = (&'a OsStr, Option<&'a OsStr>)>,
+    directory: Option<&Path>,
+) {
+    let mut script = SCRIPT.lock().unwrap();
+    let Some(writer) = script.as_mut() else {
+        return;
+    };
+
+    let mut text = String::new();
+    if let Some(step) = writer.step.take() {
+        text.push_str(&format!("\n# {}\n", step.replace('\n', " ")));
+    }
+    text.push_str(&line(program, args, env, directory));
+    text.push('\n');
+
+    if let Err(e) = writer.writer.write_all(text.as_bytes()) {
+        error!("Failed to write the script: {e}");
+        *script = None;
+    }
+}
+
+/// The line of the script running `program`, in `directory` and with the environment variables
+/// set or removed by `env`.
+fn line<'a>(
+    program: &'a OsStr,
+    args: impl IntoIterator<Item = &'a OsStr>,
+    env: impl IntoIterator<Item = (&'a OsStr, Option<&'a OsStr>)>,
+    directory: Option<&Path>,
+) -> String {
+    let mut setup = Vec::new();
+    if let Some(directory) = directory {
+        setup.push(format!("cd {}", quote(directory.as_os_str())));
+    }
+    let mut assignments = Vec::new();
+    for (key, value) in env {
+        match value {
+            Some(value) => assignments.push(format!("{}={}", key.to_string_lossy(), quote(value))),
+            None => setup.push(format!("unset {}", key.to_string_lossy())),
+        }
+    }
+
+    let command = assignments
+        .into_iter()
+        .chain(std::iter::once(program).chain(args).map(quote))
+        .collect::<Vec<_>>()
+        .join(" ");
+    if setup.is_empty() {
+        command
+    } else {
+        // The directory and the variables only apply to this command
+        format!("({} && {command})", setup.join(" && "))
+    }
+}
+
+fn quote(s: &OsStr) -> String {
+    shell_words::quote(&s.to_string_lossy()).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line() {
+        let os = |s: &'static str| OsStr::new(s);
+
+        assert_eq!(
+            line(os("/usr/bin/sudo"), [os("apt-get"), os("install"), os("a b")], [], None),
+            "/usr/bin/sudo apt-get install 'a b'"
+        );
+        assert_eq!(
+            line(
+                os("cargo"),
+                [os("install-update"), os("--all")],
+                [(os("CARGO_NET_RETRY"), Some(os("5"))), (os("RUSTFLAGS"), None)],
+                Some(Path::new("/home/me/my project")),
+            ),
+            "(cd '/home/me/my project' && unset RUSTFLAGS && CARGO_NET_RETRY=5 cargo install-update --all)"
+        );
+    }
+}
@@ -19,7 +19,7 @@ use crate::execution_context::ExecutionContext;
 pub fn self_update(ctx: &ExecutionContext) -> Result<()> {
     print_separator(t!("Self update"));
 
-    if ctx.run_type().dry() {
+    if ctx.run_type().simulated() {
         println!("{}", t!("Would self-update"));
         Ok(())
     } else {
@@ -4,7 +4,8 @@ use clap::ValueEnum;
 use color_eyre::Result;
 #[cfg(target_os = "linux")]
 use rust_i18n::t;
-use serde::Deserialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use strum::{EnumCount, EnumIter, EnumString, VariantNames};
 
 #[cfg(feature = "self-update")]
@@ -14,7 +15,22 @@ use crate::steps::remote::vagrant;
 use crate::steps::*;
 use crate::utils::hostname;
 
-#[derive(ValueEnum, EnumString, VariantNames, Debug, Clone, PartialEq, Eq, Deserialize, EnumIter, Copy, EnumCount)]
+#[derive(
+    ValueEnum,
+    EnumString,
+    VariantNames,
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    Deserialize,
+    Serialize,
+    JsonSchema,
+    EnumIter,
+    Copy,
+    EnumCount,
+)]
 #[clap(rename_all = "snake_case")]
 #[serde(rename_all = "snake_case")]
 #[strum(serialize_all = "snake_case")]
@@ -152,6 +168,7 @@ pub enum Step {
     Tmux,
     Toolbx,
     Typst,
+    Users,
     Uv,
     Vagrant,
     Vcpkg,
@@ -628,6 +645,15 @@ impl Step {
                 runner.execute(*self, "toolbx", || toolbx::run_toolbx(ctx))?
             }
             Typst => runner.execute(*self, "Typst", || generic::run_typst(ctx))?,
+            Users =>
+            {
+                #[cfg(unix)]
+                for user in ctx.config().users() {
+                    runner.execute(*self, format!("User ({user})"), || {
+                        unix::run_topgrade_for_user(ctx, user)
+                    })?;
+                }
+            }
             Uv => runner.execute(*self, "uv", || generic::run_uv(ctx))?,
             Vagrant => {
                 if ctx.config().should_run(Vagrant) {
@@ -699,8 +725,116 @@ impl Step {
 
         Ok(())
     }
+
+    /// The binaries the step looks for with `require`, any of which lets it run, so that
+    /// `topgrade config init` detects the tools with the same probes as the steps.
+    ///
+    /// Only the steps with a section in the configuration are listed. The step functions require
+    /// the same constants of `binaries`.
+    pub(crate) fn binaries(self) -> &'static [&'static str] {
+        use binaries::*;
+        use Step::*;
+
+        match self {
+            BrewCask | BrewFormula => &[BREW],
+            Chezmoi => &[CHEZMOI],
+            Composer => &[COMPOSER],
+            Conda => &[CONDA],
+            Containers => &[DOCKER, PODMAN],
+            Deno => &[DENO],
+            Distrobox => &[DISTROBOX],
+            Emacs => &[EMACS],
+            Firmware => &[FWUPDMGR],
+            Flatpak => &[FLATPAK],
+            GitRepos => &[GIT],
+            Julia => &[JULIA],
+            Lensfun => &[LENSFUN_UPDATE_DATA],
+            Mamba => &[MAMBA],
+            Mandb => &[MANDB],
+            Mise => &[MISE],
+            Node => &[NPM],
+            Pip3 => &[PYTHON, PYTHON3],
+            PipReview | PipReviewLocal => &[PIP_REVIEW],
+            Pipupgrade => &[PIPUPGRADE],
+            Pixi => &[PIXI],
+            Pkgfile => &[PKGFILE],
+            Pnpm => &[PNPM],
+            Rustup => &[RUSTUP],
+            Vagrant => &[VAGRANT],
+            Vim => &[VIM, NVIM],
+            Vscode => &[CODE],
+            VscodeInsiders => &[CODE_INSIDERS],
+            Vscodium => &[CODIUM],
+            VscodiumInsiders => &[CODIUM_INSIDERS],
+            Yarn => &[YARN],
+            Zigup => &[ZIGUP],
+            _ => &[],
+        }
+    }
 }
 
+/// The steps which only update what belongs to the user running them, and which `misc.users` runs
+/// again for each of the users.
+#[cfg(unix)]
+pub(crate) const USER_STEPS: &[Step] = {
+    use Step::*;
+    &[
+        Asdf,
+        Atuin,
+        Bin,
+        Bob,
+        Bun,
+        BunPackages,
+        Cargo,
+        Chezmoi,
+        Choosenim,
+        Composer,
+        Conda,
+        CustomCommands,
+        Deno,
+        Dotnet,
+        Elan,
+        Emacs,
+        Gem,
+        Ghcup,
+        GitRepos,
+        GithubCliExtensions,
+        Go,
+        Helix,
+        HomeManager,
+        Juliaup,
+        Kakoune,
+        Krew,
+        Mamba,
+        Micro,
+        Mise,
+        Myrepos,
+        Node,
+        Opam,
+        Pipx,
+        Pipxu,
+        Pixi,
+        Pnpm,
+        Poetry,
+        Pyenv,
+        Rcm,
+        Rustup,
+        Rye,
+        Sdkman,
+        Sheldon,
+        Shell,
+        Stack,
+        Stew,
+        Tmux,
+        Uv,
+        Vim,
+        VoltaPackages,
+        Yadm,
+        Yarn,
+        Yazi,
+    ]
+};
+
 #[allow(clippy::too_many_lines)]
 pub(crate) fn default_steps() -> Vec<Step> {
     // For now, SelfRenamer and SelfUpdate isn't included as they're ran before the other non-steps (pre-commands, sudo, etc)
@@ -896,6 +1030,10 @@ pub(crate) fn default_steps() -> Vec<Step> {
         Typst,
     ]);
 
+    // Last, so that the user-level steps run after the system-level ones
+    #[cfg(unix)]
+    steps.push(Users);
+
     steps.shrink_to_fit();
 
     steps
@@ -0,0 +1,37 @@
+//! The binaries the steps look for with `require`, shared with `Step::binaries` so that
+//! `topgrade config init` detects the tools with the same probes as the steps.
+pub const BREW: &str = "brew";
+pub const CHEZMOI: &str = "chezmoi";
+pub const CODE: &str = "code";
+pub const CODE_INSIDERS: &str = "code-insiders";
+pub const CODIUM: &str = "codium";
+pub const CODIUM_INSIDERS: &str = "codium-insiders";
+pub const COMPOSER: &str = "composer";
+pub const CONDA: &str = "conda";
+pub const DENO: &str = "deno";
+pub const DISTROBOX: &str = "distrobox";
+pub const DOCKER: &str = "docker";
+pub const EMACS: &str = "emacs";
+pub const FLATPAK: &str = "flatpak";
+pub const FWUPDMGR: &str = "fwupdmgr";
+pub const GIT: &str = "git";
+pub const JULIA: &str = "julia";
+pub const LENSFUN_UPDATE_DATA: &str = "lensfun-update-data";
+pub const MAMBA: &str = "mamba";
+pub const MANDB: &str = "mandb";
+pub const MISE: &str = "mise";
+pub const NPM: &str = "npm";
+pub const NVIM: &str = "nvim";
+pub const PIP_REVIEW: &str = "pip-review";
+pub const PIPUPGRADE: &str = "pipupgrade";
+pub const PIXI: &str = "pixi";
+pub const PKGFILE: &str = "pkgfile";
+pub const PNPM: &str = "pnpm";
+pub const PODMAN: &str = "podman";
+pub const PYTHON: &str = "python";
+pub const PYTHON3: &str = "python3";
+pub const RUSTUP: &str = "rustup";
+pub const VAGRANT: &str = "vagrant";
+pub const VIM: &str = "vim";
+pub const YARN: &str = "yarn";
+pub const ZIGUP: &str = "zigup";
@@ -229,7 +229,7 @@ pub fn run_containers(ctx: &ExecutionContext) -> Result<()> {
             .args(["system", "prune", "--force"])
             .status_checked()?
     // Only run `image prune` if we don't run `system prune`
-    } else if ctx.config().cleanup() {
+    } else if ctx.cleanup() {
         // Remove dangling images
         debug!("Removing dangling images");
         ctx.execute(&crt).args(["image", "prune", "-f"]).status_checked()?
@@ -237,3 +237,54 @@ pub fn run_containers(ctx: &ExecutionContext) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::eyre;
+
+    use super::*;
+    use crate::cassette::{self, Cassette};
+    use crate::config::Config;
+    use crate::execution_context::RunType;
+
+    #[test]
+    fn test_list_containers() {
+        cassette::init(Cassette::parse(include_str!("cassettes/docker.jsonl")).unwrap());
+        let docker = require("docker").unwrap();
+        let ignored = vec!["ghcr.io/home-assistant/*".to_string()];
+
+        let containers: Vec<String> = list_containers(&docker, Some(&ignored))
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            containers,
+            [
+                "`alpine:3.20` for `linux/amd64`",
+                "`nixos/nix:latest` for `linux/arm64`",
+                "`mycompany/internal:dev` for `linux/amd64`",
+            ]
+        );
+    }
+
+    /// Test that the containers are pulled for their platform, and that the containers missing
+    /// from the registry are skipped.
+    #[test]
+    fn test_run_containers() {
+        cassette::init(Cassette::parse(include_str!("cassettes/docker.jsonl")).unwrap());
+        let config = Config::from_toml("[containers]\nignored_containers = [\"ghcr.io/home-assistant/*\"]\n");
+        #[cfg(target_os = "linux")]
+        let distribution = Err(eyre!("No distribution"));
+        let ctx = ExecutionContext::new(
+            RunType::Replay,
+            None,
+            &config,
+            #[cfg(target_os = "linux")]
+            &distribution,
+        );
+
+        run_containers(&ctx).unwrap();
+        assert_eq!(cassette::unreplayed(), 0);
+    }
+}
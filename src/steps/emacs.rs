@@ -9,6 +9,7 @@ use rust_i18n::t;
 use crate::command::CommandExt;
 use crate::execution_context::ExecutionContext;
 use crate::step::Step;
+use crate::steps::binaries;
 use crate::terminal::print_separator;
 use crate::utils::{require, require_option, PathExt};
 
@@ -75,7 +76,7 @@ impl Emacs {
     }
 
     pub fn upgrade(&self, ctx: &ExecutionContext) -> Result<()> {
-        let emacs = require("emacs")?;
+        let emacs = require(binaries::EMACS)?;
         if let Some(doom) = &self.doom {
             Emacs::update_doom(doom, ctx)?;
         }
@@ -20,7 +20,9 @@ use crate::command::{CommandExt, Utf8Output};
 use crate::execution_context::ExecutionContext;
 use crate::executor::ExecutorOutput;
 use crate::output_changed_message;
+use crate::packages::{self, PackageTracker};
 use crate::step::Step;
+use crate::steps::binaries;
 use crate::sudo::SudoExecuteOpts;
 use crate::terminal::{print_separator, shell};
 use crate::utils::{check_is_python_2_or_shim, require, require_one, require_option, which, PathExt};
@@ -70,11 +72,12 @@ pub fn run_cargo_update(ctx: &ExecutionContext) -> Result<()> {
         return Err(SkipStep(message).into());
     };
 
-    ctx.execute(cargo_update)
-        .args(["install-update", "--git", "--all"])
+    let packages = PackageTracker::start(ctx, || packages::parse_crates_toml(&fs::read_to_string(&toml_file)?));
+    ctx.execute_step(cargo_update, ["install-update", "--git", "--all"])
         .status_checked()?;
+    packages.finish(ctx);
 
-    if ctx.config().cleanup() {
+    if ctx.cleanup() {
         let cargo_cache = require("cargo-cache")
             .ok()
             .or_else(|| cargo_dir.join("bin/cargo-cache").if_exists());
@@ -93,7 +96,7 @@ pub fn run_flutter_upgrade(ctx: &ExecutionContext) -> Result<()> {
     let flutter = require("flutter")?;
 
     print_separator("Flutter");
-    ctx.execute(flutter).arg("upgrade").status_checked()
+    ctx.execute_step(flutter, ["upgrade"]).status_checked()
 }
 
 pub fn run_gem(ctx: &ExecutionContext) -> Result<()> {
@@ -102,16 +105,14 @@ pub fn run_gem(ctx: &ExecutionContext) -> Result<()> {
 
     print_separator("Gems");
 
-    let mut command = ctx.execute(gem);
-    command.arg("update");
-
+    let mut args = vec!["update"];
     if env::var_os("RBENV_SHELL").is_none() {
-        command.arg("--user-install");
+        args.push("--user-install");
     } else {
         debug!("Detected rbenv. Avoiding --user-install");
     }
 
-    command.status_checked()
+    ctx.execute_step(gem, args).status_checked()
 }
 
 pub fn run_rubygems(ctx: &ExecutionContext) -> Result<()> {
@@ -260,14 +261,13 @@ pub fn run_aqua(ctx: &ExecutionContext) -> Result<()> {
 }
 
 pub fn run_rustup(ctx: &ExecutionContext) -> Result<()> {
-    let rustup = require("rustup")?;
+    let rustup = require(binaries::RUSTUP)?;
 
     print_separator("rustup");
 
-    ctx.execute(rustup)
-        .arg("update")
-        .args(ctx.config().rustup_channels())
-        .status_checked()
+    let mut args = vec!["update".to_string()];
+    args.extend(ctx.config().rustup_channels());
+    ctx.execute_step(rustup, args).status_checked()
 }
 
 pub fn run_rye(ctx: &ExecutionContext) -> Result<()> {
@@ -338,9 +338,9 @@ pub fn run_juliaup(ctx: &ExecutionContext) -> Result<()> {
         ctx.execute(&juliaup).args(["self", "update"]).status_checked()?;
     }
 
-    ctx.execute(&juliaup).arg("update").status_checked()?;
+    ctx.execute_step(&juliaup, ["update"]).status_checked()?;
 
-    if ctx.config().cleanup() {
+    if ctx.cleanup() {
         ctx.execute(&juliaup).arg("gc").status_checked()?;
     }
 
@@ -434,7 +434,7 @@ pub fn run_opam_update(ctx: &ExecutionContext) -> Result<()> {
     }
     command.status_checked()?;
 
-    if ctx.config().cleanup() {
+    if ctx.cleanup() {
         ctx.execute(&opam).arg("clean").status_checked()?;
     }
 
@@ -480,10 +480,10 @@ impl VSCodeVariant {
 
     fn bin_name(&self) -> &'static str {
         match self {
-            VSCodeVariant::Code => "code",
-            VSCodeVariant::CodeInsiders => "code-insiders",
-            VSCodeVariant::Codium => "codium",
-            VSCodeVariant::CodiumInsiders => "codium-insiders",
+            VSCodeVariant::Code => binaries::CODE,
+            VSCodeVariant::CodeInsiders => binaries::CODE_INSIDERS,
+            VSCodeVariant::Codium => binaries::CODIUM,
+            VSCodeVariant::CodiumInsiders => binaries::CODIUM_INSIDERS,
         }
     }
 
@@ -609,18 +609,24 @@ pub fn run_pipx_update(ctx: &ExecutionContext) -> Result<()> {
         command_args.push("--quiet");
     }
 
-    ctx.execute(pipx).args(command_args).status_checked()
+    let packages = PackageTracker::start(ctx, || {
+        packages::list(Command::new(&pipx).args(["list", "--json"]), packages::parse_pipx)
+    });
+    ctx.execute_step(&pipx, command_args).status_checked()?;
+    packages.finish(ctx);
+
+    Ok(())
 }
 
 pub fn run_pipxu_update(ctx: &ExecutionContext) -> Result<()> {
     let pipxu = require("pipxu")?;
     print_separator("pipxu");
 
-    ctx.execute(pipxu).args(["upgrade", "--all"]).status_checked()
+    ctx.execute_step(pipxu, ["upgrade", "--all"]).status_checked()
 }
 
 pub fn run_conda_update(ctx: &ExecutionContext) -> Result<()> {
-    let conda = require("conda")?;
+    let conda = require(binaries::CONDA)?;
 
     let output = Command::new(&conda)
         .args(["config", "--show", "auto_activate"])
@@ -659,7 +665,7 @@ pub fn run_conda_update(ctx: &ExecutionContext) -> Result<()> {
     }
 
     // Cleanup (conda clean) is global (not tied to a particular environment)
-    if ctx.config().cleanup() {
+    if ctx.cleanup() {
         let mut command = ctx.execute(conda);
         command.args(["clean", "--all"]);
         if ctx.config().yes(Step::Conda) {
@@ -672,7 +678,7 @@ pub fn run_conda_update(ctx: &ExecutionContext) -> Result<()> {
 }
 
 pub fn run_pixi_update(ctx: &ExecutionContext) -> Result<()> {
-    let pixi = require("pixi")?;
+    let pixi = require(binaries::PIXI)?;
     print_separator("Pixi");
 
     // Check if `pixi --help` mentions self-update, if yes, self-update must be enabled.
@@ -697,7 +703,7 @@ pub fn run_pixi_update(ctx: &ExecutionContext) -> Result<()> {
 }
 
 pub fn run_mamba_update(ctx: &ExecutionContext) -> Result<()> {
-    let mamba = require("mamba")?;
+    let mamba = require(binaries::MAMBA)?;
 
     print_separator("Mamba");
 
@@ -708,7 +714,7 @@ pub fn run_mamba_update(ctx: &ExecutionContext) -> Result<()> {
     }
     command.status_checked()?;
 
-    if ctx.config().cleanup() {
+    if ctx.cleanup() {
         let mut command = ctx.execute(&mamba);
         command.args(["clean", "--all"]);
         if ctx.config().yes(Step::Mamba) {
@@ -728,8 +734,8 @@ pub fn run_miktex_packages_update(ctx: &ExecutionContext) -> Result<()> {
 }
 
 pub fn run_pip3_update(ctx: &ExecutionContext) -> Result<()> {
-    let py = require("python").and_then(check_is_python_2_or_shim);
-    let py3 = require("python3").and_then(check_is_python_2_or_shim);
+    let py = require(binaries::PYTHON).and_then(check_is_python_2_or_shim);
+    let py3 = require(binaries::PYTHON3).and_then(check_is_python_2_or_shim);
 
     let python3 = match (py, py3) {
         // prefer `python` if it is available and is a valid Python 3.
@@ -808,7 +814,7 @@ pub fn run_pip3_update(ctx: &ExecutionContext) -> Result<()> {
 }
 
 pub fn run_pip_review_update(ctx: &ExecutionContext) -> Result<()> {
-    let pip_review = require("pip-review")?;
+    let pip_review = require(binaries::PIP_REVIEW)?;
 
     print_separator("pip-review");
 
@@ -824,7 +830,7 @@ pub fn run_pip_review_update(ctx: &ExecutionContext) -> Result<()> {
 }
 
 pub fn run_pip_review_local_update(ctx: &ExecutionContext) -> Result<()> {
-    let pip_review = require("pip-review")?;
+    let pip_review = require(binaries::PIP_REVIEW)?;
 
     print_separator("pip-review (local)");
 
@@ -843,7 +849,7 @@ pub fn run_pip_review_local_update(ctx: &ExecutionContext) -> Result<()> {
 }
 
 pub fn run_pipupgrade_update(ctx: &ExecutionContext) -> Result<()> {
-    let pipupgrade = require("pipupgrade")?;
+    let pipupgrade = require(binaries::PIPUPGRADE)?;
 
     print_separator("Pipupgrade");
     if !ctx.config().enable_pipupgrade() {
@@ -928,7 +934,7 @@ pub fn run_tlmgr_update(ctx: &ExecutionContext) -> Result<()> {
 }
 
 pub fn run_chezmoi_update(ctx: &ExecutionContext) -> Result<()> {
-    let chezmoi = require("chezmoi")?;
+    let chezmoi = require(binaries::CHEZMOI)?;
     HOME_DIR.join(".local/share/chezmoi").require()?;
 
     let mut cmd = ctx.execute(chezmoi);
@@ -976,7 +982,7 @@ pub fn run_custom_command(name: &str, command: &str, ctx: &ExecutionContext) ->
 }
 
 pub fn run_composer_update(ctx: &ExecutionContext) -> Result<()> {
-    let composer = require("composer")?;
+    let composer = require(binaries::COMPOSER)?;
     let composer_home = Command::new(&composer)
         .args(["global", "config", "--absolute", "--quiet", "home"])
         .output_checked_utf8()
@@ -1205,7 +1211,7 @@ pub fn run_ghcli_extensions_upgrade(ctx: &ExecutionContext) -> Result<()> {
 }
 
 pub fn update_julia_packages(ctx: &ExecutionContext) -> Result<()> {
-    let julia = require("julia")?;
+    let julia = require(binaries::JULIA)?;
 
     print_separator(t!("Julia Packages"));
 
@@ -1332,7 +1338,7 @@ pub fn run_platform_io(ctx: &ExecutionContext) -> Result<()> {
 pub fn run_lensfun_update_data(ctx: &ExecutionContext) -> Result<()> {
     const EXIT_CODE_WHEN_NO_UPDATE: i32 = 1;
 
-    let lensfun_update_data = require("lensfun-update-data")?;
+    let lensfun_update_data = require(binaries::LENSFUN_UPDATE_DATA)?;
 
     print_separator("Lensfun's database update");
 
@@ -1560,7 +1566,7 @@ pub fn run_uv(ctx: &ExecutionContext) -> Result<()> {
         .args(["tool", "upgrade", "--all"])
         .status_checked()?;
 
-    if ctx.config().cleanup() {
+    if ctx.cleanup() {
         // 3. Prune cache
         ctx.execute(&uv_exec).args(["cache", "prune"]).status_checked()?;
     }
@@ -1602,7 +1608,7 @@ pub fn run_bun(ctx: &ExecutionContext) -> Result<()> {
 }
 
 pub fn run_zigup(ctx: &ExecutionContext) -> Result<()> {
-    let zigup = require("zigup")?;
+    let zigup = require(binaries::ZIGUP)?;
     let config = ctx.config();
 
     print_separator("zigup");
@@ -1663,7 +1669,7 @@ pub fn run_jetbrains_toolbox(ctx: &ExecutionContext) -> Result<()> {
         Ok(installation) => {
             print_separator("JetBrains Toolbox");
 
-            if ctx.run_type().dry() {
+            if ctx.run_type().simulated() {
                 println!("Dry running jetbrains-toolbox-updater");
                 return Ok(());
             }
@@ -15,6 +15,7 @@ use tracing::{debug, error};
 use crate::command::CommandExt;
 use crate::execution_context::ExecutionContext;
 use crate::step::Step;
+use crate::steps::binaries;
 use crate::steps::emacs::Emacs;
 use crate::terminal::print_separator;
 use crate::utils::{require, PathExt};
@@ -157,7 +158,7 @@ fn get_head_revision<P: AsRef<Path>>(git: &Path, repo: P) -> Option<String> {
 impl RepoStep {
     /// Try to create a `RepoStep`, fail if `git` is not found.
     pub fn try_new() -> Result<Self> {
-        let git = require("git")?;
+        let git = require(binaries::GIT)?;
         let mut glob_match_options = MatchOptions::new();
 
         if cfg!(windows) {
@@ -372,7 +373,7 @@ impl RepoStep {
     /// This function will create an async runtime and do the real job so the
     /// function itself is not async.
     fn pull_repos(&self, ctx: &ExecutionContext) -> Result<()> {
-        if ctx.run_type().dry() {
+        if ctx.run_type().simulated() {
             self.repos
                 .iter()
                 .for_each(|repo| println!("{}", t!("Would pull {repo}", repo = repo.display())));
@@ -1,3 +1,4 @@
+pub mod binaries;
 pub mod containers;
 pub mod emacs;
 pub mod generic;
@@ -13,6 +13,8 @@ use semver::Version;
 use tracing::debug;
 
 use crate::command::CommandExt;
+use crate::packages::{self, PackageTracker};
+use crate::steps::binaries;
 use crate::terminal::{print_info, print_separator};
 use crate::utils::{require, PathExt};
 use crate::{error::SkipStep, execution_context::ExecutionContext};
@@ -90,6 +92,15 @@ impl NPM {
     }
 
     fn upgrade(&self, ctx: &ExecutionContext, use_sudo: bool) -> Result<()> {
+        let packages = self.variant.is_npm().then(|| {
+            PackageTracker::start(ctx, || {
+                packages::list(
+                    Command::new(&self.command).args(["ls", "--global", "--depth=0", "--json"]),
+                    packages::parse_npm,
+                )
+            })
+        });
+
         let args = ["update", self.global_location_arg()];
         if use_sudo {
             let sudo = ctx.require_sudo()?;
@@ -98,6 +109,10 @@ impl NPM {
             ctx.execute(&self.command).args(args).status_checked()?;
         }
 
+        if let Some(packages) = packages {
+            packages.finish(ctx);
+        }
+
         Ok(())
     }
 
@@ -282,7 +297,7 @@ fn should_use_sudo_yarn(yarn: &Yarn, ctx: &ExecutionContext) -> Result<bool> {
 }
 
 pub fn run_npm_upgrade(ctx: &ExecutionContext) -> Result<()> {
-    let npm = require("npm").map(|b| NPM::new(b, NPMVariant::Npm))?;
+    let npm = require(binaries::NPM).map(|b| NPM::new(b, NPMVariant::Npm))?;
 
     print_separator(t!("Node Package Manager"));
 
@@ -298,7 +313,7 @@ pub fn run_npm_upgrade(ctx: &ExecutionContext) -> Result<()> {
 }
 
 pub fn run_pnpm_upgrade(ctx: &ExecutionContext) -> Result<()> {
-    let pnpm = require("pnpm").map(|b| NPM::new(b, NPMVariant::Pnpm))?;
+    let pnpm = require(binaries::PNPM).map(|b| NPM::new(b, NPMVariant::Pnpm))?;
 
     print_separator(t!("Performant Node Package Manager"));
 
@@ -314,7 +329,7 @@ pub fn run_pnpm_upgrade(ctx: &ExecutionContext) -> Result<()> {
 }
 
 pub fn run_yarn_upgrade(ctx: &ExecutionContext) -> Result<()> {
-    let yarn = require("yarn").map(Yarn::new)?;
+    let yarn = require(binaries::YARN).map(Yarn::new)?;
 
     if !yarn.has_global_subcmd() {
         debug!("Yarn is 2.x or above, skipping global upgrade");
@@ -335,7 +350,7 @@ pub fn run_yarn_upgrade(ctx: &ExecutionContext) -> Result<()> {
 }
 
 pub fn deno_upgrade(ctx: &ExecutionContext) -> Result<()> {
-    let deno = require("deno").map(Deno::new)?;
+    let deno = require(binaries::DENO).map(Deno::new)?;
     let deno_dir = HOME_DIR.join(".deno");
 
     if !deno.command.canonicalize()?.is_descendant_of(&deno_dir) {
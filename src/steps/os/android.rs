@@ -22,7 +22,7 @@ pub fn upgrade_packages(ctx: &ExecutionContext) -> Result<()> {
     }
     command.status_checked()?;
 
-    if !is_nala && ctx.config().cleanup() {
+    if !is_nala && ctx.cleanup() {
         ctx.execute(&pkg).arg("clean").status_checked()?;
 
         let apt = require("apt")?;
@@ -51,7 +51,7 @@ impl ArchPackageManager for YayParu {
         }
         command.status_checked()?;
 
-        if ctx.config().cleanup() {
+        if ctx.cleanup() {
             let mut command = ctx.execute(&self.executable);
             command.arg("--pacman").arg(&self.pacman).arg("-Scc");
             if ctx.config().yes(Step::System) {
@@ -122,7 +122,7 @@ impl ArchPackageManager for Trizen {
         }
         command.status_checked()?;
 
-        if ctx.config().cleanup() {
+        if ctx.cleanup() {
             let mut command = ctx.execute(&self.executable);
             command.arg("-Sc");
             if ctx.config().yes(Step::System) {
@@ -157,7 +157,7 @@ impl ArchPackageManager for Pacman {
         }
         command.status_checked()?;
 
-        if ctx.config().cleanup() {
+        if ctx.cleanup() {
             let mut command = sudo.execute(ctx, &self.executable)?;
             command.arg("-Scc");
             if ctx.config().yes(Step::System) {
@@ -205,7 +205,7 @@ impl ArchPackageManager for Pikaur {
 
         command.status_checked()?;
 
-        if ctx.config().cleanup() {
+        if ctx.cleanup() {
             let mut command = ctx.execute(&self.executable);
             command.arg("-Sc");
             if ctx.config().yes(Step::System) {
@@ -244,7 +244,7 @@ impl ArchPackageManager for Pamac {
 
         command.status_checked()?;
 
-        if ctx.config().cleanup() {
+        if ctx.cleanup() {
             let mut command = ctx.execute(&self.executable);
             command.arg("clean");
             if ctx.config().yes(Step::System) {
@@ -1,26 +1,32 @@
+use std::fs;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{eyre, Result};
 use ini::Ini;
 use rust_i18n::t;
+use serde::Deserialize;
+use strum::Display;
 use tracing::{debug, warn};
 
 use crate::command::CommandExt;
 use crate::error::{SkipStep, TopgradeError};
 use crate::execution_context::ExecutionContext;
+use crate::packages::{self, PackageTracker, Versions};
 use crate::step::Step;
+use crate::steps::binaries;
 use crate::steps::generic::is_wsl;
 use crate::steps::os::archlinux;
 use crate::sudo::SudoExecuteOpts;
-use crate::terminal::{print_separator, prompt_yesno};
+use crate::terminal::{print_separator, print_warning, prompt_yesno};
 use crate::utils::{require, require_one, which, PathExt};
 use crate::HOME_DIR;
 
 static OS_RELEASE_PATH: &str = "/etc/os-release";
 
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Display)]
+#[strum(serialize_all = "lowercase")]
 pub enum Distribution {
     Alpine,
     AOSC,
@@ -141,7 +147,8 @@ impl Distribution {
     pub fn upgrade(self, ctx: &ExecutionContext) -> Result<()> {
         print_separator(t!("System update"));
 
-        match self {
+        let packages = self.list_packages().map(|list| PackageTracker::start(ctx, list));
+        let result = match self {
             Distribution::Alpine => upgrade_alpine_linux(ctx),
             Distribution::Chimera => upgrade_chimera_linux(ctx),
             Distribution::Wolfi => upgrade_wolfi_linux(ctx),
@@ -166,6 +173,97 @@ impl Distribution {
             Distribution::Nobara => upgrade_nobara(ctx),
             Distribution::NILRT => upgrade_nilrt(ctx),
             Distribution::AOSC => upgrade_aosc(ctx),
+        };
+        if let Some(packages) = packages {
+            packages.finish(ctx);
+        }
+
+        result
+    }
+
+    /// The function listing the installed packages of the system package manager, if supported.
+    fn list_packages(self) -> Option<fn() -> Result<Versions>> {
+        match self {
+            Distribution::Debian | Distribution::KDENeon => Some(|| {
+                packages::list(
+                    Command::new("dpkg-query").args(["--show", "--showformat=${Package} ${Version}\n"]),
+                    packages::parse_name_version,
+                )
+            }),
+            Distribution::Arch => {
+                Some(|| packages::list(Command::new("pacman").arg("-Q"), packages::parse_name_version))
+            }
+            Distribution::CentOS
+            | Distribution::Fedora
+            | Distribution::Nobara
+            | Distribution::Suse
+            | Distribution::OpenSuseTumbleweed => Some(|| {
+                packages::list(
+                    Command::new("rpm").args(["--query", "--all", "--queryformat", "%{NAME} %{VERSION}-%{RELEASE}\n"]),
+                    packages::parse_name_version,
+                )
+            }),
+            _ => None,
+        }
+    }
+
+    /// Detect whether a reboot is needed to complete the upgrades.
+    pub fn reboot_status(self) -> RebootStatus {
+        let mut checks = Vec::new();
+
+        // Debian-based distributions create this file when a package asks for a reboot; others only
+        // do so if `update-notifier` is around, so its absence proves nothing there.
+        if matches!(self, Distribution::Debian | Distribution::KDENeon) || Path::new(REBOOT_REQUIRED_PATH).exists() {
+            checks.push((t!("A package requested a reboot").to_string(), reboot_required_file()));
+        }
+
+        match self {
+            Distribution::CentOS | Distribution::Fedora | Distribution::Nobara => checks.push((
+                t!("Core libraries or services have been updated").to_string(),
+                dnf_needs_restarting(),
+            )),
+            Distribution::Suse | Distribution::OpenSuseTumbleweed | Distribution::SuseMicro => checks.push((
+                t!("Core libraries or services have been updated").to_string(),
+                zypper_needs_rebooting(),
+            )),
+            Distribution::Arch => checks.push((
+                t!("The running kernel is no longer installed").to_string(),
+                running_kernel_removed(),
+            )),
+            Distribution::FedoraImmutable => checks.push((
+                t!("A new rpm-ostree deployment is pending").to_string(),
+                rpm_ostree_pending(),
+            )),
+            _ => (),
+        }
+
+        if which("bootc").is_some() {
+            checks.push((t!("A new bootc deployment is pending").to_string(), bootc_pending()));
+        }
+
+        let mut checked = false;
+        let mut required = RebootRequired::default();
+        for (reason, check) in checks {
+            match check {
+                Ok(packages) => {
+                    checked = true;
+                    if let Some(packages) = packages {
+                        required.reasons.push(reason);
+                        required.packages.extend(packages);
+                    }
+                }
+                Err(e) => debug!("Failed to check whether a reboot is required: {e:?}"),
+            }
+        }
+
+        if !required.reasons.is_empty() {
+            required.packages.sort();
+            required.packages.dedup();
+            RebootStatus::Required(required)
+        } else if checked {
+            RebootStatus::NotRequired
+        } else {
+            RebootStatus::Unknown
         }
     }
 
@@ -581,7 +679,7 @@ fn upgrade_debian(ctx: &ExecutionContext) -> Result<()> {
     }
     command.status_checked()?;
 
-    if ctx.config().cleanup() {
+    if ctx.cleanup() {
         sudo.execute(ctx, &apt)?.arg("clean").status_checked()?;
 
         let mut command = sudo.execute(ctx, &apt)?;
@@ -610,7 +708,7 @@ pub fn run_deb_get(ctx: &ExecutionContext) -> Result<()> {
         .arg("--dg-only")
         .status_checked()?;
 
-    if ctx.config().cleanup() {
+    if ctx.cleanup() {
         let output = ctx.execute(&deb_get).arg("clean").output_checked()?;
         // Swallow the output, as it's very noisy and not useful.
         //  The output is automatically printed as part of `output_checked` when an error occurs.
@@ -710,7 +808,7 @@ pub fn run_pacstall(ctx: &ExecutionContext) -> Result<()> {
 }
 
 pub fn run_pkgfile(ctx: &ExecutionContext) -> Result<()> {
-    let pkgfile = require("pkgfile")?;
+    let pkgfile = require(binaries::PKGFILE)?;
 
     if !ctx.config().enable_pkgfile() {
         return Err(SkipStep("Pkgfile isn't enabled".to_string()).into());
@@ -723,7 +821,7 @@ pub fn run_pkgfile(ctx: &ExecutionContext) -> Result<()> {
 }
 
 pub fn run_mandb(ctx: &ExecutionContext) -> Result<()> {
-    let mandb = require("mandb")?;
+    let mandb = require(binaries::MANDB)?;
 
     if !ctx.config().enable_mandb() {
         return Err(SkipStep(t!("ManDB isn't enabled").to_string()).into());
@@ -782,7 +880,7 @@ fn upgrade_exherbo(ctx: &ExecutionContext) -> Result<()> {
         .args(["resolve", "world", "-c1", "-Cs", "-km", "-Km", "-x"])
         .status_checked()?;
 
-    if ctx.config().cleanup() {
+    if ctx.cleanup() {
         sudo.execute(ctx, &cave)?.args(["purge", "-x"]).status_checked()?;
     }
 
@@ -808,7 +906,7 @@ fn upgrade_nixos(ctx: &ExecutionContext) -> Result<()> {
     }
     command.status_checked()?;
 
-    if ctx.config().cleanup() {
+    if ctx.cleanup() {
         sudo.execute(ctx, "/run/current-system/sw/bin/nix-collect-garbage")?
             .arg("-d")
             .status_checked()?;
@@ -835,7 +933,7 @@ fn upgrade_neon(ctx: &ExecutionContext) -> Result<()> {
     if ctx.config().yes(Step::System) {
         cmd.arg("-y");
     }
-    if ctx.config().cleanup() {
+    if ctx.cleanup() {
         cmd.arg("--autoremove");
     }
     // from pkcon man, exit code 5 is 'Nothing useful was done.'
@@ -844,6 +942,151 @@ fn upgrade_neon(ctx: &ExecutionContext) -> Result<()> {
     Ok(())
 }
 
+static REBOOT_REQUIRED_PATH: &str = "/var/run/reboot-required";
+
+/// Whether a reboot is needed to complete the upgrades.
+#[derive(Debug)]
+pub enum RebootStatus {
+    /// There is no way to tell on this system.
+    Unknown,
+    NotRequired,
+    Required(RebootRequired),
+}
+
+#[derive(Debug, Default)]
+pub struct RebootRequired {
+    /// Why a reboot is required.
+    pub reasons: Vec<String>,
+    /// The packages which caused the reboot to be required, when known.
+    pub packages: Vec<String>,
+}
+
+impl RebootRequired {
+    pub fn show(&self) {
+        print_warning(t!("\nA reboot is required:"));
+        for reason in &self.reasons {
+            println!("  {reason}");
+        }
+        if !self.packages.is_empty() {
+            println!("  {}", t!("Packages: {packages}", packages = self.packages.join(", ")));
+        }
+    }
+}
+
+/// The checks below return `Ok(None)` if no reboot is required, `Ok(Some(packages))` with the
+/// responsible packages (if known) if one is, and an error if they can't tell.
+type RebootCheck = Result<Option<Vec<String>>>;
+
+/// Check for the file that Debian-based distributions create when a package requests a reboot, and
+/// the list of packages which did so.
+fn reboot_required_file() -> RebootCheck {
+    if !Path::new(REBOOT_REQUIRED_PATH).exists() {
+        return Ok(None);
+    }
+
+    let packages = fs::read_to_string(format!("{REBOOT_REQUIRED_PATH}.pkgs")).unwrap_or_default();
+    Ok(Some(
+        packages
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(String::from)
+            .collect(),
+    ))
+}
+
+/// `dnf needs-restarting -r` exits with 1 if a reboot is required, and lists the responsible
+/// packages as `  * kernel`.
+fn dnf_needs_restarting() -> RebootCheck {
+    let dnf = require("dnf")?;
+    let output = Command::new(dnf)
+        .args(["needs-restarting", "-r"])
+        .output_checked_with_utf8(|output| match output.status.code() {
+            Some(0 | 1) => Ok(()),
+            _ => Err(()),
+        })?;
+
+    Ok((output.status.code() == Some(1)).then(|| parse_dnf_needs_restarting(&output.stdout)))
+}
+
+fn parse_dnf_needs_restarting(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("* "))
+        .map(|package| package.trim().to_string())
+        .collect()
+}
+
+/// `zypper needs-rebooting` exits with 102 if a reboot is required.
+fn zypper_needs_rebooting() -> RebootCheck {
+    let zypper = require("zypper")?;
+    let output = Command::new(zypper)
+        .arg("needs-rebooting")
+        .output_checked_with(|output| match output.status.code() {
+            Some(0 | 102) => Ok(()),
+            _ => Err(()),
+        })?;
+
+    Ok((output.status.code() == Some(102)).then(Vec::new))
+}
+
+/// On Arch Linux, upgrading the kernel removes the modules of the running kernel.
+fn running_kernel_removed() -> RebootCheck {
+    let release = fs::read_to_string("/proc/sys/kernel/osrelease")?;
+    let modules = Path::new("/usr/lib/modules");
+    if !modules.exists() {
+        return Err(eyre!("{} does not exist", modules.display()));
+    }
+
+    Ok((!modules.join(release.trim()).exists()).then(Vec::new))
+}
+
+/// rpm-ostree lists the pending deployment first, ahead of the booted one.
+fn rpm_ostree_pending() -> RebootCheck {
+    let rpm_ostree = require("rpm-ostree")?;
+    let output = Command::new(rpm_ostree)
+        .args(["status", "--json"])
+        .output_checked_utf8()?;
+
+    Ok(parse_rpm_ostree_pending(&output.stdout)?.then(Vec::new))
+}
+
+fn parse_rpm_ostree_pending(output: &str) -> Result<bool> {
+    #[derive(Deserialize)]
+    struct Status {
+        deployments: Vec<Deployment>,
+    }
+
+    #[derive(Deserialize)]
+    struct Deployment {
+        booted: bool,
+    }
+
+    let status: Status = serde_json::from_str(output)?;
+    Ok(status.deployments.first().is_some_and(|deployment| !deployment.booted))
+}
+
+/// bootc reports the staged deployment, which is applied on the next boot.
+fn bootc_pending() -> RebootCheck {
+    #[derive(Deserialize)]
+    struct Host {
+        status: Status,
+    }
+
+    #[derive(Deserialize)]
+    struct Status {
+        staged: Option<serde_json::Value>,
+    }
+
+    let bootc = require("bootc")?;
+    let output = Command::new(bootc)
+        .args(["status", "--format", "json"])
+        .output_checked_utf8()?;
+    let host: Host = serde_json::from_str(&output.stdout)?;
+
+    Ok(host.status.staged.is_some().then(Vec::new))
+}
+
 /// `needrestart` should be skipped if:
 ///
 /// 1. This is a redhat-based distribution
@@ -881,7 +1124,7 @@ pub fn run_needrestart(ctx: &ExecutionContext) -> Result<()> {
 }
 
 pub fn run_fwupdmgr(ctx: &ExecutionContext) -> Result<()> {
-    let fwupdmgr = require("fwupdmgr")?;
+    let fwupdmgr = require(binaries::FWUPDMGR)?;
 
     if is_wsl()? {
         return Err(SkipStep(t!("Should not run in WSL").to_string()).into());
@@ -905,12 +1148,19 @@ pub fn run_fwupdmgr(ctx: &ExecutionContext) -> Result<()> {
 }
 
 pub fn run_flatpak(ctx: &ExecutionContext) -> Result<()> {
-    let flatpak = require("flatpak")?;
+    let flatpak = require(binaries::FLATPAK)?;
 
-    let cleanup = ctx.config().cleanup();
+    let cleanup = ctx.cleanup();
     let yes = ctx.config().yes(Step::Flatpak);
     print_separator("Flatpak User Packages");
 
+    let packages = PackageTracker::start(ctx, || {
+        packages::list(
+            Command::new(&flatpak).args(["list", "--columns=application,version,active"]),
+            packages::parse_flatpak,
+        )
+    });
+
     let mut update_args = vec!["update", "--user"];
     if yes {
         update_args.push("-y");
@@ -951,10 +1201,12 @@ pub fn run_flatpak(ctx: &ExecutionContext) -> Result<()> {
             if yes {
                 cleanup_args.push("-y");
             }
-            ctx.execute(flatpak).args(&cleanup_args).status_checked()?;
+            ctx.execute(&flatpak).args(&cleanup_args).status_checked()?;
         }
     }
 
+    packages.finish(ctx);
+
     Ok(())
 }
 
@@ -995,7 +1247,7 @@ pub fn run_protonup_update(ctx: &ExecutionContext) -> Result<()> {
 }
 
 pub fn run_distrobox_update(ctx: &ExecutionContext) -> Result<()> {
-    let distrobox = require("distrobox")?;
+    let distrobox = require(binaries::DISTROBOX)?;
 
     print_separator("Distrobox");
     match (
@@ -1028,7 +1280,7 @@ pub fn run_dkp_pacman_update(ctx: &ExecutionContext) -> Result<()> {
 
     sudo.execute(ctx, &dkp_pacman)?.arg("-Syu").status_checked()?;
 
-    if ctx.config().cleanup() {
+    if ctx.cleanup() {
         sudo.execute(ctx, &dkp_pacman)?.arg("-Scc").status_checked()?;
     }
 
@@ -1158,6 +1410,22 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_dnf_needs_restarting() {
+        let output = "Core libraries or services have been updated since boot-up:\n  * glibc\n  * kernel\n\nReboot is required to fully utilize these updates.\nMore information: https://access.redhat.com/solutions/27943\n";
+
+        assert_eq!(parse_dnf_needs_restarting(output), vec!["glibc", "kernel"]);
+    }
+
+    #[test]
+    fn test_parse_rpm_ostree_pending() {
+        let pending = r#"{"deployments": [{"booted": false, "staged": true}, {"booted": true}]}"#;
+        let booted = r#"{"deployments": [{"booted": true}, {"booted": false}]}"#;
+
+        assert!(parse_rpm_ostree_pending(pending).unwrap());
+        assert!(!parse_rpm_ostree_pending(booted).unwrap());
+    }
+
     #[test]
     fn test_wolfi() {
         test_template(include_str!("os_release/wolfi"), Distribution::Wolfi);
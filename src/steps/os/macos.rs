@@ -21,7 +21,7 @@ pub fn run_macports(ctx: &ExecutionContext) -> Result<()> {
     sudo.execute(ctx, &port)?
         .args(["-u", "upgrade", "outdated"])
         .status_checked()?;
-    if ctx.config().cleanup() {
+    if ctx.cleanup() {
         sudo.execute(ctx, &port)?.args(["-N", "reclaim"]).status_checked()?;
     }
 
@@ -36,7 +36,7 @@ pub fn upgrade_packages(ctx: &ExecutionContext) -> Result<()> {
 
     let is_current = is_openbsd_current()?;
 
-    if ctx.config().cleanup() {
+    if ctx.cleanup() {
         sudo.execute(ctx, "/usr/sbin/pkg_delete")?.arg("-ac").status_checked()?;
     }
 
@@ -1,3 +1,4 @@
+use clap::ValueEnum;
 use color_eyre::eyre::Context;
 use color_eyre::eyre::Result;
 use color_eyre::eyre::{eyre, OptionExt};
@@ -6,6 +7,7 @@ use home;
 use ini::Ini;
 #[cfg(target_os = "linux")]
 use nix::unistd::Uid;
+use nix::unistd::User;
 use regex::Regex;
 use rust_i18n::t;
 use semver::Version;
@@ -18,10 +20,13 @@ use std::process::Command;
 use std::sync::LazyLock;
 use std::{env::var, path::Path};
 use std::{fs, io};
+use strum::IntoEnumIterator;
 use tracing::{debug, warn};
 
 use crate::command::CommandExt;
-use crate::sudo::SudoExecuteOpts;
+use crate::config::Config;
+use crate::events;
+use crate::sudo::{Sudo, SudoExecuteOpts};
 use crate::XDG_DIRS;
 use crate::{output_changed_message, HOME_DIR};
 
@@ -31,9 +36,12 @@ use crate::error::{SkipStep, StepFailed};
 use crate::execution_context::ExecutionContext;
 #[cfg(any(target_os = "linux", target_os = "macos"))]
 use crate::executor::Executor;
-use crate::step::Step;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use crate::packages::{self, PackageTracker};
+use crate::step::{Step, USER_STEPS};
+use crate::steps::binaries;
 use crate::terminal::print_separator;
-use crate::utils::{require, PathExt};
+use crate::utils::{is_elevated, require, PathExt};
 
 #[cfg(any(target_os = "linux", target_os = "macos"))]
 const INTEL_BREW: &str = "/usr/local/bin/brew";
@@ -54,7 +62,7 @@ pub enum BrewVariant {
 impl BrewVariant {
     fn binary_name(self) -> &'static str {
         match self {
-            BrewVariant::Path => "brew",
+            BrewVariant::Path => binaries::BREW,
             BrewVariant::MacIntel => INTEL_BREW,
             BrewVariant::MacArm => ARM_BREW,
         }
@@ -344,6 +352,12 @@ pub fn run_brew_formula(ctx: &ExecutionContext, variant: BrewVariant) -> Result<
 
     variant.execute(ctx).arg("update").status_checked()?;
 
+    let packages = PackageTracker::start(ctx, || {
+        packages::list(
+            variant.execute_internal().args(["list", "--formula", "--versions"]),
+            packages::parse_name_version,
+        )
+    });
     let mut command = variant.execute(ctx);
     command.args(["upgrade", "--formula"]);
 
@@ -352,8 +366,9 @@ pub fn run_brew_formula(ctx: &ExecutionContext, variant: BrewVariant) -> Result<
     }
 
     command.status_checked()?;
+    packages.finish(ctx);
 
-    if ctx.config().cleanup() {
+    if ctx.cleanup() {
         variant.execute(ctx).arg("cleanup").status_checked()?;
     }
 
@@ -434,9 +449,16 @@ pub fn run_brew_cask(ctx: &ExecutionContext, variant: BrewVariant) -> Result<()>
         }
     }
 
+    let packages = PackageTracker::start(ctx, || {
+        packages::list(
+            variant.execute_internal().args(["list", "--cask", "--versions"]),
+            packages::parse_name_version,
+        )
+    });
     variant.execute(ctx).args(&brew_args).status_checked()?;
+    packages.finish(ctx);
 
-    if ctx.config().cleanup() {
+    if ctx.cleanup() {
         variant.execute(ctx).arg("cleanup").status_checked()?;
     }
 
@@ -833,7 +855,7 @@ pub fn run_asdf(ctx: &ExecutionContext) -> Result<()> {
 }
 
 pub fn run_mise(ctx: &ExecutionContext) -> Result<()> {
-    let mise = require("mise")?;
+    let mise = require(binaries::MISE)?;
 
     print_separator("mise");
 
@@ -958,7 +980,7 @@ pub fn run_sdkman(ctx: &ExecutionContext) -> Result<()> {
     let cmd_upgrade = format!("source {} && sdk upgrade", &sdkman_init_path);
     ctx.execute(&bash).args(["-c", cmd_upgrade.as_str()]).status_checked()?;
 
-    if ctx.config().cleanup() {
+    if ctx.cleanup() {
         let cmd_flush_archives = format!("source {} && sdk flush archives", &sdkman_init_path);
         ctx.execute(&bash)
             .args(["-c", cmd_flush_archives.as_str()])
@@ -1022,9 +1044,113 @@ pub fn run_atuin(ctx: &ExecutionContext) -> Result<()> {
     ctx.execute(atuin).status_checked()
 }
 
+/// Run the user-level steps as `user`, with the configuration of that user.
+///
+/// This runs another instance of Topgrade through sudo, which reports the results of its steps
+/// through an events file, so that they can be shown in the summary.
+pub fn run_topgrade_for_user(ctx: &ExecutionContext, user: &str) -> Result<()> {
+    if !is_elevated() {
+        return Err(SkipStep(t!("Running steps for other users requires root").to_string()).into());
+    }
+
+    if !USER_STEPS.iter().any(|step| ctx.config().should_run(*step)) {
+        return Err(SkipStep(t!("No user-level step is enabled").to_string()).into());
+    }
+
+    let account = User::from_name(user)?.ok_or_else(|| eyre!(t!("User {user} does not exist", user = user)))?;
+    // Topgrade running as root uses no sudo at all, which cannot run commands as another user
+    let sudo = match ctx.sudo() {
+        Some(sudo) if sudo.path().is_some() => sudo.clone(),
+        _ => Sudo::detect()?,
+    };
+
+    print_separator(format!("User ({user})"));
+
+    // The other instance writes to the events file, so it has to own it
+    let events_file = tempfile::NamedTempFile::new()?;
+    std::os::unix::fs::chown(events_file.path(), Some(account.uid.as_raw()), None)?;
+
+    // A login shell sets up the `PATH` of the user, where most of the tools are installed
+    let mut command = sudo.execute_opts(ctx, "env", SudoExecuteOpts::new().login_shell().set_home().user(user))?;
+    command
+        .arg(format!("TOPGRADE_PREFIX={user}"))
+        // Nobody can answer the breaking changes prompt of the other instance
+        .arg("TOPGRADE_SKIP_BRKC_NOTIFY=true")
+        .arg(std::env::current_exe()?)
+        .args(["--no-self-update", "--skip-notify", "--events"])
+        .arg(events_file.path())
+        .arg("--disable")
+        .args(
+            steps_disabled_for_users(ctx.config())
+                .iter()
+                .filter_map(|step| step.to_possible_value().map(|value| value.get_name().to_string())),
+        );
+    if ctx.config().yes(Step::Users) {
+        command.arg("--yes");
+    }
+    if ctx.config().no_retry() {
+        command.arg("--no-retry");
+    }
+    if ctx.config().verbose() {
+        command.arg("-v");
+    }
+
+    let result = command.status_checked();
+    if let Some(steps) = fs::read_to_string(events_file.path())
+        .ok()
+        .as_deref()
+        .and_then(events::read_summary)
+    {
+        ctx.record_user_report(user, steps);
+    }
+
+    result
+}
+
+/// The steps that the instances of Topgrade running for other users must not run: all of them
+/// but the user-level steps that this instance runs.
+///
+/// They are passed with `--disable` rather than the others with `--only`, which would take
+/// precedence over the `misc.disable` of the users.
+fn steps_disabled_for_users(config: &Config) -> Vec<Step> {
+    Step::iter()
+        .filter(|step| !(USER_STEPS.contains(step) && config.should_run(*step)))
+        .collect()
+}
+
 pub fn reboot(ctx: &ExecutionContext) -> Result<()> {
     match ctx.sudo() {
         Some(sudo) => sudo.execute(ctx, "reboot")?.status_checked(),
         None => ctx.execute("reboot").status_checked(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that the instances of Topgrade running for other users only run the user-level steps
+    /// of this one, and still follow the configuration of the users.
+    #[test]
+    fn test_steps_disabled_for_users() {
+        let config = Config::from_toml("[misc]\ndisable = [\"cargo\"]\n");
+        let mut args = vec!["--disable".to_string()];
+        args.extend(
+            steps_disabled_for_users(&config)
+                .iter()
+                .filter_map(|step| Some(step.to_possible_value()?.get_name().to_string())),
+        );
+
+        let user = Config::from_toml_args("[misc]\ndisable = [\"git_repos\"]\n", &args);
+        assert!(user.should_run(Step::Rustup));
+        assert!(!user.should_run(Step::GitRepos));
+        assert!(!user.should_run(Step::Cargo));
+        assert!(!user.should_run(Step::System));
+        assert!(!user.should_run(Step::Users));
+
+        let user = Config::from_toml_args("[misc]\nonly = [\"git_repos\", \"system\"]\n", &args);
+        assert!(user.should_run(Step::GitRepos));
+        assert!(!user.should_run(Step::Rustup));
+        assert!(!user.should_run(Step::System));
+    }
+}
@@ -64,7 +64,7 @@ pub fn run_scoop(ctx: &ExecutionContext) -> Result<()> {
     ctx.execute(&scoop).args(["update"]).status_checked()?;
     ctx.execute(&scoop).args(["update", "*"]).status_checked()?;
 
-    if ctx.config().cleanup() {
+    if ctx.cleanup() {
         ctx.execute(&scoop).args(["cleanup", "*"]).status_checked()?;
         ctx.execute(&scoop).args(["cache", "rm", "-a"]).status_checked()?
     }
@@ -11,6 +11,7 @@ use tracing::{debug, error};
 use crate::command::CommandExt;
 use crate::execution_context::ExecutionContext;
 use crate::step::Step;
+use crate::steps::binaries;
 use crate::terminal::print_separator;
 use crate::{error::SkipStep, utils};
 
@@ -154,7 +155,7 @@ pub fn collect_boxes(ctx: &ExecutionContext) -> Result<Vec<VagrantBox>> {
         String::from(t!("No Vagrant directories were specified in the configuration file")),
     )?;
     let vagrant = Vagrant {
-        path: utils::require("vagrant")?,
+        path: utils::require(binaries::VAGRANT)?,
     };
 
     print_separator("Vagrant");
@@ -176,7 +177,7 @@ pub fn collect_boxes(ctx: &ExecutionContext) -> Result<Vec<VagrantBox>> {
 
 pub fn topgrade_vagrant_box(ctx: &ExecutionContext, vagrant_box: &VagrantBox) -> Result<()> {
     let vagrant = Vagrant {
-        path: utils::require("vagrant")?,
+        path: utils::require(binaries::VAGRANT)?,
     };
 
     let separator = format!("Vagrant ({})", vagrant_box.smart_name());
@@ -207,7 +208,7 @@ pub fn topgrade_vagrant_box(ctx: &ExecutionContext, vagrant_box: &VagrantBox) ->
 }
 
 pub fn upgrade_vagrant_boxes(ctx: &ExecutionContext) -> Result<()> {
-    let vagrant = utils::require("vagrant")?;
+    let vagrant = utils::require(binaries::VAGRANT)?;
     print_separator(t!("Vagrant boxes"));
 
     let outdated = Command::new(&vagrant)
@@ -236,3 +237,35 @@ pub fn upgrade_vagrant_boxes(ctx: &ExecutionContext) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cassette::{self, Cassette};
+
+    #[test]
+    fn test_get_boxes() {
+        cassette::init(Cassette::parse(include_str!("../cassettes/vagrant.jsonl")).unwrap());
+        let vagrant = Vagrant {
+            path: utils::require(binaries::VAGRANT).unwrap(),
+        };
+
+        let boxes = vagrant.get_boxes("/home/user/vagrant/web").unwrap();
+        let boxes: Vec<_> = boxes
+            .iter()
+            .map(|vagrant_box| (vagrant_box.smart_name(), vagrant_box.initial_status))
+            .collect();
+        assert!(matches!(
+            boxes.as_slice(),
+            [("web", BoxStatus::Running), ("db", BoxStatus::PowerOff)]
+        ));
+
+        // Vagrant on Windows ends its lines with `\r\n`
+        let boxes = vagrant.get_boxes("/home/user/vagrant/windows").unwrap();
+        let boxes: Vec<_> = boxes
+            .iter()
+            .map(|vagrant_box| (vagrant_box.smart_name(), vagrant_box.initial_status))
+            .collect();
+        assert!(matches!(boxes.as_slice(), [("windows", BoxStatus::Saved)]));
+    }
+}
@@ -5,6 +5,7 @@ use color_eyre::eyre::Result;
 use etcetera::base_strategy::BaseStrategy;
 
 use crate::executor::{Executor, ExecutorOutput};
+use crate::steps::binaries;
 use crate::terminal::print_separator;
 use crate::{
     execution_context::ExecutionContext,
@@ -118,7 +119,7 @@ pub fn upgrade_ultimate_vimrc(ctx: &ExecutionContext) -> Result<()> {
 }
 
 pub fn upgrade_vim(ctx: &ExecutionContext) -> Result<()> {
-    let vim = require("vim")?;
+    let vim = require(binaries::VIM)?;
 
     let output = Command::new(&vim).arg("--version").output_checked_utf8()?;
     if !output.stdout.starts_with("VIM") {
@@ -139,7 +140,7 @@ pub fn upgrade_vim(ctx: &ExecutionContext) -> Result<()> {
 }
 
 pub fn upgrade_neovim(ctx: &ExecutionContext) -> Result<()> {
-    let nvim = require("nvim")?;
+    let nvim = require(binaries::NVIM)?;
     let nvimrc = nvimrc()?;
 
     print_separator("Neovim");
@@ -1,6 +1,7 @@
 use std::ffi::OsStr;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 #[cfg(windows)]
 use color_eyre::eyre;
@@ -9,7 +10,8 @@ use color_eyre::eyre::eyre;
 use color_eyre::eyre::Context;
 use color_eyre::eyre::Result;
 use rust_i18n::t;
-use serde::Deserialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use strum::Display;
 use thiserror::Error;
 #[cfg(windows)]
@@ -17,13 +19,16 @@ use tracing::{debug, warn};
 #[cfg(windows)]
 use windows::Win32::Foundation::ERROR_FILE_NOT_FOUND;
 
-use crate::command::CommandExt;
 use crate::error::UnsupportedSudo;
+use crate::events::{self, Event};
 use crate::execution_context::ExecutionContext;
 use crate::executor::Executor;
-use crate::terminal::print_separator;
+use crate::terminal::{capturing_output, print_separator};
 use crate::utils::which;
 
+/// Whether the credentials of `sudo` were cached during this run, see [`Sudo::elevate`].
+static CREDENTIALS_CACHED: AtomicBool = AtomicBool::new(false);
+
 #[derive(Clone, Debug)]
 pub struct Sudo {
     /// The path to the `sudo` binary.
@@ -144,6 +149,14 @@ const DETECT_ORDER: [SudoKind; 5] = [
 const DETECT_ORDER: [SudoKind; 2] = [SudoKind::Gsudo, SudoKind::WinSudo];
 
 impl Sudo {
+    /// The kinds of `sudo` that can be used on this machine, in the order `detect` looks for them.
+    pub fn available() -> Vec<SudoKind> {
+        DETECT_ORDER
+            .into_iter()
+            .filter(|&kind| Self::new(kind).is_ok())
+            .collect()
+    }
+
     /// Get the `sudo` binary for this platform.
     pub fn detect() -> Result<Self, SudoCreateError> {
         use SudoCreateError::*;
@@ -162,6 +175,12 @@ impl Sudo {
         Err(CannotFindBinary)
     }
 
+    /// Create Sudo from SudoKind, with the binary at `path`, which is not looked up.
+    #[cfg(test)]
+    pub(crate) fn with_path(kind: SudoKind, path: PathBuf) -> Self {
+        Self { path: Some(path), kind }
+    }
+
     /// Create Sudo from SudoKind, if found in the system
     pub fn new(kind: SudoKind) -> Result<Self, SudoCreateError> {
         // no actual binary for null sudo
@@ -264,6 +283,11 @@ impl Sudo {
         self.path.as_deref()
     }
 
+    /// The type of program being used as `sudo`.
+    pub fn kind(&self) -> SudoKind {
+        self.kind
+    }
+
     /// Elevate permissions with `sudo`.
     ///
     /// This helps prevent blocking `sudo` prompts from stopping the run in the middle of a
@@ -278,8 +302,15 @@ impl Sudo {
 
         print_separator("Sudo");
 
+        self.cache_credentials(ctx)
+    }
+
+    /// Run the dummy command of `elevate`, so that `sudo` asks for a password now if it needs one.
+    fn cache_credentials(&self, ctx: &ExecutionContext) -> Result<()> {
+        events::emit(Event::SudoRequested { sudo_kind: self.kind });
+
         // self.path is only None for null sudo, which we've handled above
-        let mut cmd = ctx.execute(self.path.as_deref().unwrap());
+        let mut cmd = ctx.command(self.path.as_deref().unwrap());
         match self.kind {
             SudoKind::Doas => {
                 // `doas` doesn't have anything like `sudo -v` to cache credentials,
@@ -336,7 +367,28 @@ impl Sudo {
             }
             SudoKind::Null => unreachable!(),
         }
-        cmd.status_checked().wrap_err("Failed to elevate permissions")
+        cmd.status_checked_interactive()
+            .wrap_err("Failed to elevate permissions")?;
+        CREDENTIALS_CACHED.store(true, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    /// Prepare to run a command with `sudo`, which may prompt for a password.
+    ///
+    /// In compact output mode the password prompt would be hidden behind the status line of the
+    /// step, so we ask for it beforehand where the credentials can be cached, once per run.
+    /// The prompt can only happen there, so it is only requested there.
+    pub(crate) fn request(&self, ctx: &ExecutionContext) -> Result<()> {
+        if matches!(self.kind, SudoKind::Sudo | SudoKind::Please) && capturing_output() {
+            if !CREDENTIALS_CACHED.load(Ordering::Relaxed) {
+                self.cache_credentials(ctx)?;
+            }
+        } else {
+            events::emit(Event::SudoRequested { sudo_kind: self.kind });
+        }
+
+        Ok(())
     }
 
     /// Execute a command with `sudo`.
@@ -351,6 +403,13 @@ impl Sudo {
         command: S,
         opts: SudoExecuteOpts,
     ) -> Result<Executor> {
+        // `use_sudo = false` in `[step.<name>]` runs the commands of the step directly
+        if ctx.step().and_then(|step| ctx.config().step_use_sudo(step)) == Some(false) {
+            return Ok(ctx.execute(command));
+        }
+
+        ctx.check_privileged(self.kind, command.as_ref())?;
+
         // null sudo is very different, do separately
         if let SudoKind::Null = self.kind {
             if opts.login_shell {
@@ -374,11 +433,15 @@ impl Sudo {
             // no sudo effectively preserves these by default
 
             // run command directly
-            return Ok(ctx.execute(command));
+            let mut cmd = ctx.limited(command);
+            ctx.apply_step_options(&mut cmd);
+            return Ok(cmd);
         }
 
+        self.request(ctx)?;
+
         // self.path is only None for null sudo, which we've handled above
-        let mut cmd = ctx.execute(self.path.as_ref().unwrap());
+        let mut cmd = ctx.limited_sudo(self.path.as_deref().unwrap(), self.kind);
 
         if opts.login_shell {
             match self.kind {
@@ -494,6 +557,7 @@ impl Sudo {
         }
 
         cmd.arg(command);
+        ctx.apply_step_options(&mut cmd);
 
         Ok(cmd)
     }
@@ -503,7 +567,7 @@ impl Sudo {
 // We always define both though, so that we don't have to put
 // #[cfg(...)] everywhere.
 
-#[derive(Clone, Copy, Debug, Display, Deserialize)]
+#[derive(Clone, Copy, Debug, Display, Deserialize, Serialize, JsonSchema)]
 #[serde(rename_all = "lowercase")]
 #[strum(serialize_all = "lowercase")]
 pub enum SudoKind {
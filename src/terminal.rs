@@ -16,10 +16,15 @@ use tracing::{debug, error};
 use which_crate::which;
 
 use crate::command::CommandExt;
+use crate::config::OutputMode;
+use crate::events::{self, Event, PromptKind};
+use crate::packages::PackageChange;
 use crate::runner::StepResult;
 
 static TERMINAL: LazyLock<Mutex<Terminal>> = LazyLock::new(|| Mutex::new(Terminal::new()));
 
+const SPINNER_FRAMES: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
 #[cfg(unix)]
 pub fn shell() -> String {
     env::var("SHELL").unwrap_or_else(|_| "sh".to_string())
@@ -34,6 +39,18 @@ pub fn run_shell() -> eyre::Result<()> {
     Command::new(shell()).env("IN_TOPGRADE", "1").status_checked()
 }
 
+/// The step being executed in compact output mode.
+struct StepStatus {
+    /// Text shown next to the spinner.
+    label: String,
+    /// Output of the commands executed so far, shown only if the step fails or asks for input.
+    output: Vec<u8>,
+    /// Index in `SPINNER_FRAMES`.
+    frame: usize,
+    /// Whether the status line is currently drawn.
+    shown: bool,
+}
+
 struct Terminal {
     width: Option<u16>,
     prefix: String,
@@ -41,6 +58,10 @@ struct Terminal {
     set_title: bool,
     display_time: bool,
     desktop_notification: bool,
+    output_mode: OutputMode,
+    step: Option<StepStatus>,
+    /// Where the captured output of a step is flushed.
+    step_output_sink: Box<dyn Write + Send>,
 }
 
 impl Terminal {
@@ -53,6 +74,98 @@ impl Terminal {
             set_title: true,
             display_time: true,
             desktop_notification: false,
+            output_mode: OutputMode::default(),
+            step: None,
+            step_output_sink: Box::new(io::stdout()),
+        }
+    }
+
+    fn set_output_mode(&mut self, output_mode: OutputMode) {
+        self.output_mode = output_mode;
+    }
+
+    fn start_step<P: AsRef<str>>(&mut self, key: P) {
+        if self.output_mode != OutputMode::Compact {
+            return;
+        }
+
+        self.step = Some(StepStatus {
+            label: key.as_ref().to_string(),
+            output: Vec::new(),
+            frame: 0,
+            shown: false,
+        });
+    }
+
+    fn finish_step<P: AsRef<str>>(&mut self, key: P, result: Option<&StepResult>) {
+        if self.step.is_none() {
+            return;
+        }
+
+        self.clear_status_line();
+        if let Some(StepResult::Failure) = result {
+            self.flush_step_output();
+        }
+        self.step = None;
+
+        if let Some(result) = result {
+            self.print_result(key, result);
+        }
+    }
+
+    fn draw_status_line(&mut self) {
+        if self.width.is_none() {
+            return;
+        }
+
+        if let Some(step) = self.step.as_mut() {
+            self.term.clear_line().ok();
+            self.term
+                .write_fmt(format_args!(
+                    "{}{} {}",
+                    self.prefix,
+                    style(SPINNER_FRAMES[step.frame % SPINNER_FRAMES.len()]).cyan().bold(),
+                    step.label
+                ))
+                .ok();
+            step.shown = true;
+        }
+    }
+
+    fn clear_status_line(&mut self) {
+        if let Some(step) = self.step.as_mut() {
+            if step.shown {
+                self.term.clear_line().ok();
+                step.shown = false;
+            }
+        }
+    }
+
+    fn tick_spinner(&mut self) {
+        if let Some(step) = self.step.as_mut() {
+            step.frame += 1;
+            self.draw_status_line();
+        }
+    }
+
+    /// Store `output` in the buffer of the current step. Returns false if there is no such buffer,
+    /// in which case the caller is responsible for displaying `output`.
+    fn capture_output(&mut self, output: &[u8]) -> bool {
+        match self.step.as_mut() {
+            Some(step) => {
+                step.output.extend_from_slice(output);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn flush_step_output(&mut self) {
+        self.clear_status_line();
+        if let Some(step) = self.step.as_mut() {
+            self.step_output_sink.write_all(&step.output).ok();
+            self.step_output_sink.flush().ok();
+            step.output.clear();
         }
     }
 
@@ -92,6 +205,12 @@ impl Terminal {
             self.notify_desktop(message.as_ref(), Some(Duration::from_secs(5)));
         }
 
+        if let Some(step) = self.step.as_mut() {
+            message.as_ref().clone_into(&mut step.label);
+            self.draw_status_line();
+            return;
+        }
+
         let now = Local::now();
         let message = if self.display_time {
             format!(
@@ -137,6 +256,7 @@ impl Terminal {
     fn print_error<P: AsRef<str>, Q: AsRef<str>>(&mut self, key: Q, message: P) {
         let key = key.as_ref();
         let message = message.as_ref();
+        self.flush_step_output();
         self.term
             .write_fmt(format_args!(
                 "{} {}",
@@ -149,6 +269,7 @@ impl Terminal {
     #[allow(dead_code)]
     fn print_warning<P: AsRef<str>>(&mut self, message: P) {
         let message = message.as_ref();
+        self.clear_status_line();
         self.term
             .write_fmt(format_args!("{}\n", style(message).yellow().bold()))
             .ok();
@@ -157,6 +278,7 @@ impl Terminal {
     #[allow(dead_code)]
     fn print_info<P: AsRef<str>>(&mut self, message: P) {
         let message = message.as_ref();
+        self.clear_status_line();
         self.term
             .write_fmt(format_args!("{}\n", style(message).blue().bold()))
             .ok();
@@ -184,8 +306,31 @@ impl Terminal {
             .ok();
     }
 
+    fn print_package_changes<P: AsRef<str>>(&mut self, key: P, changes: &[PackageChange]) {
+        if changes.is_empty() {
+            return;
+        }
+
+        self.term.write_fmt(format_args!("{}:\n", key.as_ref())).ok();
+        for change in changes {
+            self.term
+                .write_fmt(format_args!(
+                    "  {} {} → {}\n",
+                    style(&change.name).bold(),
+                    change.from,
+                    style(&change.to).green()
+                ))
+                .ok();
+        }
+    }
+
     #[allow(dead_code)]
     fn prompt_yesno(&mut self, question: &str) -> Result<bool, io::Error> {
+        self.flush_step_output();
+        events::emit(Event::PromptRequested {
+            kind: PromptKind::YesNo,
+            message: question,
+        });
         self.term
             .write_fmt(format_args!(
                 "{}",
@@ -202,6 +347,19 @@ impl Terminal {
         }
     }
 
+    fn prompt_line(&mut self, question: &str) -> Result<String, io::Error> {
+        self.flush_step_output();
+        events::emit(Event::PromptRequested {
+            kind: PromptKind::Text,
+            message: question,
+        });
+        self.term
+            .write_fmt(format_args!("{} ", style(question).yellow().bold()))
+            .ok();
+
+        self.term.read_line()
+    }
+
     #[allow(unused_variables)]
     fn should_retry(&mut self, step_name: &str) -> eyre::Result<ShouldRetry> {
         if self.width.is_none() {
@@ -221,6 +379,10 @@ impl Terminal {
             .bold();
 
         self.term.write_fmt(format_args!("\n{prompt_inner}")).ok();
+        events::emit(Event::PromptRequested {
+            kind: PromptKind::Retry,
+            message: step_name,
+        });
 
         let answer = loop {
             match self.term.read_key() {
@@ -298,6 +460,10 @@ pub fn print_result<P: AsRef<str>>(key: P, result: &StepResult) {
     TERMINAL.lock().unwrap().print_result(key, result);
 }
 
+pub fn print_package_changes<P: AsRef<str>>(key: P, changes: &[PackageChange]) {
+    TERMINAL.lock().unwrap().print_package_changes(key, changes);
+}
+
 /// Tells whether the terminal is dumb.
 pub fn is_dumb() -> bool {
     TERMINAL.lock().unwrap().width.is_none()
@@ -323,6 +489,11 @@ pub fn prompt_yesno(question: &str) -> Result<bool, io::Error> {
     TERMINAL.lock().unwrap().prompt_yesno(question)
 }
 
+/// Ask `question` and read the line of the answer.
+pub fn prompt_line(question: &str) -> Result<String, io::Error> {
+    TERMINAL.lock().unwrap().prompt_line(question)
+}
+
 pub fn notify_desktop<P: AsRef<str>>(message: P, timeout: Option<Duration>) {
     TERMINAL.lock().unwrap().notify_desktop(message, timeout);
 }
@@ -330,3 +501,120 @@ pub fn notify_desktop<P: AsRef<str>>(message: P, timeout: Option<Duration>) {
 pub fn display_time(display_time: bool) {
     TERMINAL.lock().unwrap().display_time(display_time);
 }
+
+pub fn set_output_mode(output_mode: OutputMode) {
+    TERMINAL.lock().unwrap().set_output_mode(output_mode);
+}
+
+/// Begin the status line of a step. Does nothing unless the output mode is compact.
+pub fn start_step<P: AsRef<str>>(key: P) {
+    TERMINAL.lock().unwrap().start_step(key);
+}
+
+/// Replace the status line of a step with its result, showing the captured output if it failed.
+///
+/// Nothing is printed for a `None` result, e.g. for steps whose skipping is not reported.
+pub fn finish_step<P: AsRef<str>>(key: P, result: Option<&StepResult>) {
+    TERMINAL.lock().unwrap().finish_step(key, result);
+}
+
+/// Tells whether the output of commands should be captured rather than shown.
+pub fn capturing_output() -> bool {
+    TERMINAL.lock().unwrap().step.is_some()
+}
+
+/// Store `output` until the current step fails or asks for input.
+///
+/// Returns false if the output is not being captured.
+pub fn capture_output(output: &[u8]) -> bool {
+    TERMINAL.lock().unwrap().capture_output(output)
+}
+
+/// Print the output captured for the current step so far.
+pub fn flush_step_output() {
+    TERMINAL.lock().unwrap().flush_step_output();
+}
+
+pub fn tick_spinner() {
+    TERMINAL.lock().unwrap().tick_spinner();
+}
+
+pub fn clear_status_line() {
+    TERMINAL.lock().unwrap().clear_status_line();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    /// A sink for the flushed output, which the test reads back.
+    #[derive(Clone, Default)]
+    struct Sink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn terminal(output_mode: OutputMode) -> (Terminal, Sink) {
+        let sink = Sink::default();
+        let mut terminal = Terminal::new();
+        terminal.set_output_mode(output_mode);
+        terminal.step_output_sink = Box::new(sink.clone());
+        (terminal, sink)
+    }
+
+    #[test]
+    fn test_output_not_captured_outside_compact_mode() {
+        let (mut terminal, sink) = terminal(OutputMode::default());
+        terminal.start_step("cargo");
+
+        assert!(terminal.step.is_none());
+        assert!(!terminal.capture_output(b"Updating crates\n"));
+        terminal.flush_step_output();
+        assert!(sink.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_captured_output_dropped_on_success() {
+        let (mut terminal, sink) = terminal(OutputMode::Compact);
+        terminal.start_step("cargo");
+
+        assert!(terminal.capture_output(b"Updating crates\n"));
+        terminal.finish_step("cargo", Some(&StepResult::Success));
+        assert!(terminal.step.is_none());
+        assert!(sink.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_captured_output_flushed_on_failure() {
+        let (mut terminal, sink) = terminal(OutputMode::Compact);
+        terminal.start_step("cargo");
+
+        assert!(terminal.capture_output(b"Updating crates\n"));
+        assert!(terminal.capture_output(b"error: failed\n"));
+        terminal.finish_step("cargo", Some(&StepResult::Failure));
+        assert!(terminal.step.is_none());
+        assert_eq!(*sink.0.lock().unwrap(), b"Updating crates\nerror: failed\n");
+    }
+
+    #[test]
+    fn test_captured_output_flushed_once() {
+        let (mut terminal, sink) = terminal(OutputMode::Compact);
+        terminal.start_step("cargo");
+
+        // e.g. when a command asks for input, and the step fails afterwards
+        terminal.capture_output(b"Password: ");
+        terminal.flush_step_output();
+        terminal.capture_output(b"error: failed\n");
+        terminal.finish_step("cargo", Some(&StepResult::Failure));
+        assert_eq!(*sink.0.lock().unwrap(), b"Password: error: failed\n");
+    }
+}
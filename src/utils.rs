@@ -14,6 +14,7 @@ use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{fmt, Registry};
 use tracing_subscriber::{registry, EnvFilter};
 
+use crate::cassette;
 use crate::command::CommandExt;
 use crate::config::DEFAULT_LOG_LEVEL;
 use crate::error::SkipStep;
@@ -61,8 +62,16 @@ where
     }
 }
 
+/// Find `binary_name` in the `PATH`, or in the cassette first when replaying one.
+fn find<T: AsRef<OsStr>>(binary_name: T) -> which_crate::Result<PathBuf> {
+    match cassette::program(binary_name.as_ref()) {
+        Some(path) => Ok(path),
+        None => which_crate::which(binary_name),
+    }
+}
+
 pub fn which<T: AsRef<OsStr> + Debug>(binary_name: T) -> Option<PathBuf> {
-    match which_crate::which(&binary_name) {
+    match find(&binary_name) {
         Ok(path) => {
             debug!("Detected {:?} as {:?}", &path, &binary_name);
             Some(path)
@@ -91,7 +100,7 @@ pub fn editor() -> Vec<String> {
 }
 
 pub fn require<T: AsRef<OsStr> + Debug>(binary_name: T) -> Result<PathBuf> {
-    match which_crate::which(&binary_name) {
+    match find(&binary_name) {
         Ok(path) => {
             debug!("Detected {:?} as {:?}", &path, &binary_name);
             Ok(path)
@@ -188,6 +197,10 @@ pub fn is_elevated() -> bool {
 pub mod merge_strategies {
     use merge::Merge;
 
+    use std::hash::Hash;
+
+    use indexmap::IndexMap;
+
     use crate::config::Commands;
 
     /// Prepends right to left (both Option<Vec<T>>)
@@ -236,6 +249,29 @@ pub mod merge_strategies {
             *left = right;
         }
     }
+
+    /// Merges the values with the same key, such as the overlays with the same condition, and adds
+    /// the others
+    pub fn map_merge_opt<K, V>(left: &mut Option<IndexMap<K, V>>, right: Option<IndexMap<K, V>>)
+    where
+        K: Hash + Eq,
+        V: Merge,
+    {
+        if let Some(ref mut left_inner) = left {
+            if let Some(right_inner) = right {
+                for (key, value) in right_inner {
+                    match left_inner.get_mut(&key) {
+                        Some(left_value) => left_value.merge(value),
+                        None => {
+                            left_inner.insert(key, value);
+                        }
+                    }
+                }
+            }
+        } else {
+            *left = right;
+        }
+    }
 }
 
 /// Return `Err(SkipStep)` if `python` is a Python 2 or shim.